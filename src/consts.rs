@@ -3,25 +3,41 @@
 // Shamelessly stolen from `is-video` package from sindresorhus
 // https://github.com/sindresorhus/is-video/blob/3ba58fa79b52949a0915e25f3fd2765b7a8a9809/index.js#L5
 pub const VIDEO_EXTENSIONS: &[&str] = &[
-    "3g2", "3gp", "aaf", "asf", "avchd", "avi", "drc", "flv", "m2v", "m3u8", "m4p", "m4v", "mkv",
-    "mng", "mov", "mp2", "mp4", "mpe", "mpeg", "mpg", "mpv", "mxf", "nsv", "ogg", "ogv", "qt",
-    "rm", "rmvb", "roq", "svi", "vob", "webm", "wmv", "yuv",
+    "3g2", "3gp", "aaf", "asf", "avchd", "avi", "drc", "flv", "m2ts", "m2v", "m3u8", "m4p", "m4v",
+    "mkv", "mng", "mov", "mp2", "mp4", "mpe", "mpeg", "mpg", "mpv", "mts", "mxf", "nsv", "ogg",
+    "ogv", "qt", "rm", "rmvb", "roq", "svi", "ts", "vob", "webm", "wmv", "yuv",
 ];
 
 pub const APP_ID: &[&str] = &["io.github", "roganmatrivski"];
+pub const UPLOAD_VERIFY_ATTEMPTS: usize = 3;
+/// How much --quality-retries lowers the CRF on every attempt
+pub const QUALITY_CRF_STEP: u32 = 4;
+pub const DEFAULT_MIN_VMAF: f64 = 90.0;
+pub const DEFAULT_MIN_SSIM: f64 = 0.97;
+/// First wait before retrying a rate limited yt-dlp source, doubled on every attempt
+pub const RATE_LIMIT_BACKOFF_SECS: u64 = 60;
+/// First wait before retrying a yt-dlp source after a network error, doubled on every attempt
+pub const NETWORK_BACKOFF_SECS: u64 = 5;
+/// Default of --drive-requests
+pub const DRIVE_REQUESTS: u32 = 8;
+/// First wait before retrying a rate limited Google Drive request, doubled on every attempt
+pub const DRIVE_BACKOFF_MS: u64 = 1000;
+pub const DRIVE_RATE_LIMIT_ATTEMPTS: u32 = 6;
+/// Size of each range requested by segmented downloads
+pub const SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
 pub const FFMPEG_SCALE: &str =
     r#"scale='if(lt(iw,ih),min(1080,iw),-1)':'if(lt(iw,ih),-1,min(1080,ih))'"#;
 
 pub const MAIN_BAR_FMT: &str =
-    "[{elapsed_precise}] {wide_bar:.blue} {pos:>}/{len} ({percent}%) eta {eta_precise:.blue}";
+    "{prefix}[{elapsed_precise}] {wide_bar:.blue} {pos:>}/{len} ({percent}%) eta {eta_precise:.blue}";
 pub const MAIN_BAR_FMT_MSG: &str =
-    "{msg} {wide_bar:.blue} {pos:>}/{len} ({percent}%) eta {eta_precise:.blue}";
-pub const SUB_BAR_FMT: &str = "{wide_bar:.blue} {bytes:>11.green}/{total_bytes:<11.green} {bytes_per_sec:>13.red} eta {eta:.blue}";
-pub const SUB_BAR_FMT_MSG: &str = "{msg} {wide_bar:.blue} {bytes:>11.green}/{total_bytes:<11.green} {bytes_per_sec:>13.red} eta {eta:.blue}";
+    "{prefix}{msg} {wide_bar:.blue} {pos:>}/{len} ({percent}%) eta {eta_precise:.blue}";
+pub const SUB_BAR_FMT: &str = "{prefix}{wide_bar:.blue} {bytes:>11.green}/{total_bytes:<11.green} {bytes_per_sec:>13.red} eta {eta:.blue}";
+pub const SUB_BAR_FMT_MSG: &str = "{prefix}{msg} {wide_bar:.blue} {bytes:>11.green}/{total_bytes:<11.green} {bytes_per_sec:>13.red} eta {eta:.blue}";
 pub const MAIN_BAR_CHARSET: &str = "==>-";
 pub const SUB_BAR_CHARSET: &str = "█▉▊▋▌▍▎▏  ";
 
-pub const SPINNER_FMT: &str = "{spinner} [{elapsed_precise}] {wide_msg}";
+pub const SPINNER_FMT: &str = "{prefix}{spinner} [{elapsed_precise}] {wide_msg}";
 pub const SPINNER_STRSET_DOTS12: &[&str; 56] = &[
     "⢀⠀", "⡀⠀", "⠄⠀", "⢂⠀", "⡂⠀", "⠅⠀", "⢃⠀", "⡃⠀", "⠍⠀", "⢋⠀", "⡋⠀", "⠍⠁", "⢋⠁", "⡋⠁", "⠍⠉", "⠋⠉",
     "⠋⠉", "⠉⠙", "⠉⠙", "⠉⠩", "⠈⢙", "⠈⡙", "⢈⠩", "⡀⢙", "⠄⡙", "⢂⠩", "⡂⢘", "⠅⡘", "⢃⠨", "⡃⢐", "⠍⡐", "⢋⠠",
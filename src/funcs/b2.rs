@@ -0,0 +1,166 @@
+use color_eyre::eyre::{bail, Context, Error};
+use futures_util::AsyncReadExt;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+const B2_AUTHORIZE_URL: &str = "https://api.backblazeb2.com/b2api/v2/b2_authorize_account";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct B2Authorization {
+    api_url: String,
+    authorization_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct B2File {
+    file_name: String,
+    /// `none` for large files, prefixed with `unverified:` when the uploader sent no checksum
+    content_sha1: Option<String>,
+    #[serde(default)]
+    file_info: std::collections::HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct B2FileList {
+    files: Vec<B2File>,
+}
+
+/// Credentials of a B2 remote, for what OpenDAL's stat doesn't report
+pub struct B2Account<'a> {
+    pub application_key_id: &'a str,
+    pub application_key: &'a str,
+    pub bucket_id: &'a str,
+    pub root: Option<&'a str>,
+}
+
+fn sha1_of_reader<T: std::io::Read>(mut reader: T) -> Result<String, Error> {
+    let mut hasher = Sha1::new();
+    std::io::copy(&mut reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub async fn sha1_of_path(path: &std::path::Path) -> Result<String, Error> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || sha1_of_reader(std::fs::File::open(path)?)).await?
+}
+
+/// Hashes the file as stored on the remote, by reading it back
+pub async fn sha1_of_remote(op: &opendal::Operator, path: &str) -> Result<String, Error> {
+    let mut reader = op.reader(path).await?.into_futures_async_read(..).await?;
+    let mut hasher = Sha1::new();
+    let mut buf = vec![0; 1024 * 1024];
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+impl B2Account<'_> {
+    /// Name of the file in the bucket, with the root the operator was set up with
+    fn file_name(&self, path: &str) -> String {
+        match self.root.map(|x| x.trim_matches('/')) {
+            Some(root) if !root.is_empty() => format!("{root}/{path}"),
+            _ => path.to_string(),
+        }
+    }
+
+    /// SHA1 B2 recorded for the file: `contentSha1` for files uploaded in one piece,
+    /// `large_file_sha1` for large files whose uploader provided it
+    pub async fn file_sha1(&self, path: &str) -> Result<Option<String>, Error> {
+        let client = reqwest::Client::new();
+        let auth = client
+            .get(B2_AUTHORIZE_URL)
+            .basic_auth(self.application_key_id, Some(self.application_key))
+            .send()
+            .await?
+            .error_for_status()
+            .wrap_err("B2 authorization failed")?
+            .text()
+            .await?;
+        let auth: B2Authorization = serde_json::from_str(&auth)?;
+
+        let file_name = self.file_name(path);
+        let body = serde_json::json!({
+            "bucketId": self.bucket_id,
+            "startFileName": file_name,
+            "prefix": file_name,
+            "maxFileCount": 1,
+        });
+        let list = client
+            .post(format!("{}/b2api/v2/b2_list_file_names", auth.api_url))
+            .header(reqwest::header::AUTHORIZATION, auth.authorization_token)
+            .body(body.to_string())
+            .send()
+            .await?
+            .error_for_status()
+            .wrap_err("Listing the file on B2 failed")?
+            .text()
+            .await?;
+        let list: B2FileList = serde_json::from_str(&list)?;
+
+        let Some(file) = list.files.into_iter().find(|x| x.file_name == file_name) else {
+            bail!("{file_name} not found on B2");
+        };
+
+        let sha1 = file
+            .content_sha1
+            .filter(|x| x != "none")
+            .or_else(|| file.file_info.get("large_file_sha1").cloned())
+            .map(|x| x.trim_start_matches("unverified:").to_ascii_lowercase());
+
+        Ok(sha1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_sha1() {
+        assert_eq!(
+            sha1_of_reader(&b"abc"[..]).unwrap(),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn remote_hash_matches_local() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("video.mkv");
+        std::fs::write(&path, b"video".repeat(500_000)).unwrap();
+
+        let config = crate::structs::RemoteConfig::Fs {
+            root: dir.path().to_string_lossy().into_owned(),
+        };
+        let op = crate::funcs::opendal::setup_remote(&config).unwrap();
+
+        assert_eq!(
+            sha1_of_remote(&op, "video.mkv").await.unwrap(),
+            sha1_of_path(&path).await.unwrap()
+        );
+    }
+
+    #[test]
+    fn file_names_include_root() {
+        let account = |root| B2Account {
+            application_key_id: "",
+            application_key: "",
+            bucket_id: "",
+            root,
+        };
+
+        assert_eq!(account(None).file_name("a.mkv"), "a.mkv");
+        assert_eq!(account(Some("/")).file_name("a.mkv"), "a.mkv");
+        assert_eq!(account(Some("/backup/")).file_name("a.mkv"), "backup/a.mkv");
+        assert_eq!(account(Some("x/y")).file_name("a.mkv"), "x/y/a.mkv");
+    }
+}
@@ -0,0 +1,754 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{bail, Error};
+use futures_util::{StreamExt, TryStreamExt};
+use reqwest::{header, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::{
+    consts::{MAIN_BAR_CHARSET, SEGMENT_SIZE, SUB_BAR_FMT_MSG},
+    statics::MPB,
+};
+
+use super::progressbar::get_progbar;
+
+/// Validators of the resource a partial file came from, stored next to it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ResumeInfo {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    total: Option<u64>,
+    /// Ranges finished by a segmented download, whose file is full size but has holes
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    segments: BTreeSet<(u64, u64)>,
+}
+
+fn header_str(res: &reqwest::Response, name: header::HeaderName) -> Option<String> {
+    res.headers()
+        .get(name)
+        .and_then(|x| x.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Start and total of a `Content-Range: bytes start-end/total` header
+fn content_range(res: &reqwest::Response) -> Option<(u64, Option<u64>)> {
+    let value = header_str(res, header::CONTENT_RANGE)?;
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+
+    Some((start.parse().ok()?, total.parse().ok()))
+}
+
+impl ResumeInfo {
+    fn path(file: &Path) -> PathBuf {
+        let mut path = file.as_os_str().to_owned();
+        path.push(".resume");
+        path.into()
+    }
+
+    fn load(file: &Path) -> Option<Self> {
+        let data = std::fs::read_to_string(Self::path(file)).ok()?;
+        serde_json::from_str(&data).ok()
+    }
+
+    fn save(&self, file: &Path) -> Result<(), Error> {
+        std::fs::write(Self::path(file), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    fn remove(file: &Path) {
+        let _ = std::fs::remove_file(Self::path(file));
+    }
+
+    /// Only resources that can be told apart from a changed one are worth resuming
+    fn from_response(res: &reqwest::Response, total: Option<u64>) -> Option<Self> {
+        let info = Self {
+            etag: header_str(res, header::ETAG),
+            last_modified: header_str(res, header::LAST_MODIFIED),
+            total,
+            segments: BTreeSet::new(),
+        };
+
+        (info.etag.is_some() || info.last_modified.is_some()).then_some(info)
+    }
+
+    fn same_resource(&self, other: &Self) -> bool {
+        (&self.etag, &self.last_modified, self.total)
+            == (&other.etag, &other.last_modified, other.total)
+    }
+
+    fn if_range(&self) -> Option<&str> {
+        // Weak etags aren't allowed in If-Range
+        self.etag
+            .as_deref()
+            .filter(|x| !x.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+}
+
+/// Response with the headers of the url and as little of its body as possible: a HEAD request,
+/// or a GET of the first byte for servers that don't answer HEAD
+pub async fn fetch_headers(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<reqwest::Response, Error> {
+    match client
+        .head(url)
+        .send()
+        .await
+        .and_then(|x| x.error_for_status())
+    {
+        Ok(res) => return Ok(res),
+        Err(e) => tracing::debug!("HEAD {url} failed, trying a ranged GET: {e}"),
+    }
+
+    Ok(client
+        .get(url)
+        .header(header::RANGE, "bytes=0-0")
+        .send()
+        .await?
+        .error_for_status()?)
+}
+
+/// Downloads the url into the path, resuming what an earlier attempt left behind when the server
+/// supports ranges and the resource hasn't changed since. The partial file is kept on failure.
+pub async fn download_resumable(
+    client: &reqwest::Client,
+    url: &str,
+    path: &Path,
+    progbar_msg: &str,
+) -> Result<(), Error> {
+    // Files of segmented downloads have holes, their length says nothing
+    let saved = ResumeInfo::load(path).filter(|x| x.segments.is_empty());
+    let existing = match (&saved, tokio::fs::metadata(path).await) {
+        (Some(_), Ok(meta)) => meta.len(),
+        _ => 0,
+    };
+
+    let mut req = client.get(url);
+    if let Some(saved) = saved.as_ref().filter(|_| existing > 0) {
+        if saved.total == Some(existing) {
+            tracing::debug!("{} is already complete", path.display());
+            ResumeInfo::remove(path);
+            return Ok(());
+        }
+
+        if let Some(validator) = saved.if_range() {
+            tracing::debug!("Resuming {} from byte {existing}", path.display());
+            req = req
+                .header(header::RANGE, format!("bytes={existing}-"))
+                .header(header::IF_RANGE, validator);
+        }
+    }
+
+    let res = req.send().await?;
+
+    if res.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // Whatever is on disk doesn't fit the resource anymore, start over on the next attempt
+        tokio::fs::remove_file(path).await?;
+        ResumeInfo::remove(path);
+        bail!("Server refused to resume {}", path.display());
+    }
+
+    let res = res.error_for_status()?;
+
+    let (offset, total) = if res.status() == StatusCode::PARTIAL_CONTENT {
+        let (start, total) = content_range(&res).unwrap_or((0, None));
+        let unchanged = saved.as_ref().is_some_and(|x| {
+            ResumeInfo::from_response(&res, x.total).is_some_and(|new| new.same_resource(x))
+        });
+
+        if start != existing || !unchanged {
+            tokio::fs::remove_file(path).await?;
+            ResumeInfo::remove(path);
+            bail!("Server sent a mismatched range for {}", path.display());
+        }
+
+        (start, total)
+    } else {
+        if existing > 0 {
+            tracing::info!(
+                "{} changed on the server or can't be resumed, restarting download",
+                path.display()
+            );
+        }
+
+        (0, res.content_length())
+    };
+
+    let accepts_ranges = res.status() == StatusCode::PARTIAL_CONTENT
+        || header_str(&res, header::ACCEPT_RANGES).is_some_and(|x| x == "bytes");
+
+    match ResumeInfo::from_response(&res, total).filter(|_| accepts_ranges) {
+        Some(info) => info.save(path)?,
+        None => ResumeInfo::remove(path),
+    }
+
+    let file = if offset > 0 {
+        tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .await?
+    } else {
+        tokio::fs::File::create(path).await?
+    };
+
+    let pb = MPB.add(get_progbar(
+        total.unwrap_or_default(),
+        SUB_BAR_FMT_MSG,
+        MAIN_BAR_CHARSET,
+    )?);
+    pb.set_message(progbar_msg.to_string());
+    pb.set_position(offset);
+
+    let mut wrapped_file = pb.wrap_async_write(file);
+    let mut res_body = res.bytes_stream();
+    tracing::trace!("Downloading to {}", path.to_string_lossy());
+
+    while let Some(c) = res_body.next().await {
+        let c = c?;
+
+        tokio::io::copy(&mut c.as_ref(), &mut wrapped_file).await?;
+    }
+
+    wrapped_file.flush().await?;
+    pb.finish_and_clear();
+
+    let downloaded = tokio::fs::metadata(path).await?.len();
+    if let Some(total) = total.filter(|x| *x != downloaded) {
+        bail!("Download ended early, got {downloaded} of {total} bytes");
+    }
+
+    ResumeInfo::remove(path);
+
+    Ok(())
+}
+
+async fn fetch_segment(
+    client: &reqwest::Client,
+    url: &str,
+    path: &Path,
+    validator: Option<&str>,
+    (start, end): (u64, u64),
+    pb: &indicatif::ProgressBar,
+) -> Result<(), Error> {
+    let mut req = client
+        .get(url)
+        .header(header::RANGE, format!("bytes={start}-{end}"));
+    if let Some(validator) = validator {
+        req = req.header(header::IF_RANGE, validator);
+    }
+
+    let res = req.send().await?.error_for_status()?;
+    if res.status() != StatusCode::PARTIAL_CONTENT
+        || content_range(&res).map(|(x, _)| x) != Some(start)
+    {
+        bail!("Server stopped honoring ranges, the file probably changed");
+    }
+
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+    file.seek(std::io::SeekFrom::Start(start)).await?;
+
+    let mut written = 0;
+    let mut res_body = res.bytes_stream();
+    while let Some(c) = res_body.next().await {
+        let c = c?;
+
+        file.write_all(&c).await?;
+        written += c.len() as u64;
+        pb.inc(c.len() as u64);
+    }
+    file.flush().await?;
+
+    if written != end - start + 1 {
+        bail!("Segment {start}-{end} ended early, got {written} bytes");
+    }
+
+    Ok(())
+}
+
+/// Downloads the url over several connections at once, each fetching a range of the file.
+/// Finished ranges are recorded next to the file, so a later attempt only fetches the missing
+/// ones unless the resource changed. Falls back to a single resumable stream when the server
+/// doesn't support ranges.
+pub async fn download_segmented(
+    client: &reqwest::Client,
+    url: &str,
+    path: &Path,
+    connections: usize,
+    progbar_msg: &str,
+) -> Result<(), Error> {
+    if connections <= 1 {
+        return download_resumable(client, url, path, progbar_msg).await;
+    }
+
+    let probe = client
+        .get(url)
+        .header(header::RANGE, "bytes=0-0")
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let total = match content_range(&probe) {
+        Some((0, Some(total))) if probe.status() == StatusCode::PARTIAL_CONTENT => total,
+        _ => {
+            tracing::debug!("{url} doesn't support ranges, downloading with a single connection");
+            drop(probe);
+            return download_resumable(client, url, path, progbar_msg).await;
+        }
+    };
+
+    let info = ResumeInfo::from_response(&probe, Some(total));
+    let validator = info.as_ref().and_then(|x| x.if_range().map(str::to_string));
+    drop(probe);
+
+    let size = tokio::fs::metadata(path).await.map(|x| x.len()).ok();
+    let mut info = match (info, ResumeInfo::load(path)) {
+        (Some(info), Some(saved)) if info.same_resource(&saved) && size == Some(total) => {
+            tracing::debug!(
+                "Resuming {} with {} segments done",
+                path.display(),
+                saved.segments.len()
+            );
+            Some(saved)
+        }
+        // Without validators a changed resource can't be told apart, so it's never resumed
+        (info, _) => {
+            tokio::fs::File::create(path).await?.set_len(total).await?;
+            match info {
+                Some(info) => {
+                    info.save(path)?;
+                    Some(info)
+                }
+                None => {
+                    ResumeInfo::remove(path);
+                    None
+                }
+            }
+        }
+    };
+
+    let done = info
+        .as_ref()
+        .map(|x| x.segments.clone())
+        .unwrap_or_default();
+    let missing = (0..total)
+        .step_by(SEGMENT_SIZE as usize)
+        .map(|start| (start, (start + SEGMENT_SIZE).min(total) - 1))
+        .filter(|x| !done.contains(x));
+
+    let pb = MPB.add(get_progbar(total, SUB_BAR_FMT_MSG, MAIN_BAR_CHARSET)?);
+    pb.set_message(progbar_msg.to_string());
+    pb.set_position(done.iter().map(|(start, end)| end - start + 1).sum());
+    tracing::trace!(
+        "Downloading to {} with {connections} connections",
+        path.to_string_lossy()
+    );
+
+    let (validator, pb_ref) = (validator.as_deref(), &pb);
+    let mut fetched = futures_util::stream::iter(missing)
+        .map(|range| async move {
+            fetch_segment(client, url, path, validator, range, pb_ref).await?;
+            Ok::<_, Error>(range)
+        })
+        .buffer_unordered(connections);
+
+    while let Some(range) = fetched.try_next().await? {
+        if let Some(info) = info.as_mut() {
+            info.segments.insert(range);
+            info.save(path)?;
+        }
+    }
+
+    pb.finish_and_clear();
+    ResumeInfo::remove(path);
+
+    Ok(())
+}
+
+/// Downloads a thumbnail next to the output, named after it with the image's own extension.
+/// Deleted once the returned path is dropped.
+pub async fn download_thumbnail(
+    client: &reqwest::Client,
+    url: &str,
+    output_path: &Path,
+) -> Result<tempfile::TempPath, Error> {
+    let res = client.get(url).send().await?.error_for_status()?;
+
+    let url_ext = super::filename::filename_from_url(res.url()).and_then(|x| {
+        Path::new(&x)
+            .extension()
+            .map(|x| x.to_string_lossy().to_ascii_lowercase())
+    });
+    let ext = header_str(&res, header::CONTENT_TYPE)
+        .and_then(|x| x.parse::<mime::Mime>().ok())
+        .filter(|x| x.type_() == mime::IMAGE)
+        .map(|x| match x.subtype().as_str() {
+            "jpeg" => "jpg".to_string(),
+            x => x.to_string(),
+        })
+        .or(url_ext)
+        .unwrap_or("jpg".to_string());
+
+    let path = tempfile::TempPath::from_path(output_path.with_file_name(format!(
+        "{}_thumb.{ext}",
+        output_path.file_stem().unwrap_or_default().to_string_lossy()
+    )));
+    tokio::fs::write(&path, res.bytes().await?).await?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    struct Request {
+        method: String,
+        headers: HashMap<String, String>,
+    }
+
+    struct Response {
+        status: u16,
+        headers: Vec<(&'static str, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Request {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers.get(name).map(String::as_str)
+        }
+    }
+
+    type Handler = dyn Fn(&Request) -> Response + Send + Sync;
+
+    /// Serves every request with the handler on a local port, returning the url and the
+    /// requests received so far
+    async fn serve(handler: Arc<Handler>) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/video.mp4", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let received = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (handler, received) = (handler.clone(), received.clone());
+
+                tokio::spawn(async move {
+                    let mut buf = vec![];
+                    while !buf.ends_with(b"\r\n\r\n") {
+                        let mut byte = [0];
+                        if stream.read(&mut byte).await.unwrap() == 0 {
+                            return;
+                        }
+                        buf.push(byte[0]);
+                    }
+
+                    let head = String::from_utf8(buf).unwrap();
+                    let mut lines = head.lines();
+                    let method = lines.next().unwrap().split(' ').next().unwrap();
+                    let req = Request {
+                        method: method.to_string(),
+                        headers: lines
+                            .filter_map(|x| x.split_once(": "))
+                            .map(|(k, v)| (k.to_ascii_lowercase(), v.to_string()))
+                            .collect(),
+                    };
+
+                    let res = handler(&req);
+                    let is_head = req.method == "HEAD";
+                    received.lock().unwrap().push(req);
+
+                    let mut out = format!("HTTP/1.1 {} X\r\nConnection: close\r\n", res.status);
+                    for (name, val) in &res.headers {
+                        out += &format!("{name}: {val}\r\n");
+                    }
+                    if !res.headers.iter().any(|(x, _)| *x == "Content-Length") {
+                        out += &format!("Content-Length: {}\r\n", res.body.len());
+                    }
+                    out += "\r\n";
+
+                    stream.write_all(out.as_bytes()).await.unwrap();
+                    if !is_head {
+                        stream.write_all(&res.body).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        (url, requests)
+    }
+
+    const BODY: &[u8] = b"hello world";
+
+    /// Leaves the first `len` bytes of BODY behind, as an earlier attempt would have
+    fn partial(dir: &Path, len: usize, etag: &str) -> PathBuf {
+        let path = dir.join("video.mp4");
+        std::fs::write(&path, &BODY[..len]).unwrap();
+        ResumeInfo {
+            etag: Some(etag.to_string()),
+            last_modified: None,
+            total: Some(BODY.len() as u64),
+            segments: BTreeSet::new(),
+        }
+        .save(&path)
+        .unwrap();
+
+        path
+    }
+
+    fn full(etag: &str) -> Response {
+        Response {
+            status: 200,
+            headers: vec![
+                ("ETag", etag.to_string()),
+                ("Accept-Ranges", "bytes".to_string()),
+            ],
+            body: BODY.to_vec(),
+        }
+    }
+
+    fn range(start: usize, etag: &str) -> Response {
+        Response {
+            status: 206,
+            headers: vec![
+                ("ETag", etag.to_string()),
+                (
+                    "Content-Range",
+                    format!("bytes {start}-{}/{}", BODY.len() - 1, BODY.len()),
+                ),
+            ],
+            body: BODY[start..].to_vec(),
+        }
+    }
+
+    async fn download(url: &str, path: &Path) -> Result<(), Error> {
+        download_resumable(&reqwest::Client::new(), url, path, "").await
+    }
+
+    #[tokio::test]
+    async fn resumes_with_partial_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = partial(dir.path(), 6, "\"v1\"");
+        let (url, requests) = serve(Arc::new(|req| match req.header("range") {
+            Some("bytes=6-") if req.header("if-range") == Some("\"v1\"") => range(6, "\"v1\""),
+            _ => full("\"v1\""),
+        }))
+        .await;
+
+        download(&url, &path).await.unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), BODY);
+        assert!(!ResumeInfo::path(&path).exists());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn restarts_when_server_sends_everything() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = partial(dir.path(), 6, "\"v1\"");
+        let (url, _) = serve(Arc::new(|_| full("\"v2\""))).await;
+
+        download(&url, &path).await.unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), BODY);
+        assert!(!ResumeInfo::path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn starts_over_after_range_not_satisfiable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = partial(dir.path(), 6, "\"v1\"");
+        let (url, _) = serve(Arc::new(|req| match req.header("range") {
+            Some(_) => Response {
+                status: 416,
+                headers: vec![],
+                body: vec![],
+            },
+            None => full("\"v1\""),
+        }))
+        .await;
+
+        let err = download(&url, &path).await.unwrap_err();
+        assert!(err.to_string().contains("refused to resume"), "{err}");
+        assert!(!path.exists() && !ResumeInfo::path(&path).exists());
+
+        // The next attempt downloads from scratch
+        download(&url, &path).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), BODY);
+    }
+
+    #[tokio::test]
+    async fn rejects_range_of_changed_etag() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = partial(dir.path(), 6, "\"v1\"");
+        // Ignores If-Range and sends the range of a different version
+        let (url, _) = serve(Arc::new(|_| range(6, "\"v2\""))).await;
+
+        let err = download(&url, &path).await.unwrap_err();
+        assert!(err.to_string().contains("mismatched range"), "{err}");
+        assert!(!path.exists() && !ResumeInfo::path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn rejects_mismatched_content_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = partial(dir.path(), 6, "\"v1\"");
+        let (url, _) = serve(Arc::new(|_| range(4, "\"v1\""))).await;
+
+        let err = download(&url, &path).await.unwrap_err();
+        assert!(err.to_string().contains("mismatched range"), "{err}");
+        assert!(!path.exists() && !ResumeInfo::path(&path).exists());
+    }
+
+    #[tokio::test]
+    async fn keeps_partial_file_when_cut_short() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("video.mp4");
+        let (url, _) = serve(Arc::new(|_| {
+            let mut res = full("\"v1\"");
+            res.headers.push(("Content-Length", "20".to_string()));
+            res
+        }))
+        .await;
+
+        assert!(download(&url, &path).await.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), BODY);
+        assert_eq!(ResumeInfo::load(&path).unwrap().total, Some(20));
+    }
+
+    /// Serves ranges of the body like a server with proper range support
+    fn ranges_of(body: Arc<Vec<u8>>, etag: &'static str) -> Arc<Handler> {
+        Arc::new(move |req| {
+            let (start, end) = req
+                .header("range")
+                .and_then(|x| x.strip_prefix("bytes="))
+                .and_then(|x| x.split_once('-'))
+                .unwrap();
+            let start = start.parse::<usize>().unwrap();
+            let end = end
+                .parse::<usize>()
+                .map_or(body.len() - 1, |x| x.min(body.len() - 1));
+
+            Response {
+                status: 206,
+                headers: vec![
+                    ("ETag", etag.to_string()),
+                    (
+                        "Content-Range",
+                        format!("bytes {start}-{end}/{}", body.len()),
+                    ),
+                ],
+                body: body[start..=end].to_vec(),
+            }
+        })
+    }
+
+    /// Leaves a segmented download behind with only its first segment done
+    fn first_segment_done(dir: &Path, body: &[u8], etag: &str) -> PathBuf {
+        let path = dir.join("video.mp4");
+        let mut data = vec![0; body.len()];
+        let seg = SEGMENT_SIZE as usize;
+        data[..seg].copy_from_slice(&body[..seg]);
+        std::fs::write(&path, data).unwrap();
+
+        ResumeInfo {
+            etag: Some(etag.to_string()),
+            last_modified: None,
+            total: Some(body.len() as u64),
+            segments: BTreeSet::from([(0, SEGMENT_SIZE - 1)]),
+        }
+        .save(&path)
+        .unwrap();
+
+        path
+    }
+
+    fn segmented_body() -> Arc<Vec<u8>> {
+        let len = 2 * SEGMENT_SIZE as usize + 100;
+        Arc::new((0..len).map(|x| (x % 251) as u8).collect())
+    }
+
+    fn requested_ranges(requests: &Mutex<Vec<Request>>) -> Vec<String> {
+        let mut ranges = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|x| x.header("range").map(str::to_string))
+            .collect::<Vec<_>>();
+        ranges.sort();
+        ranges
+    }
+
+    #[tokio::test]
+    async fn segmented_fetches_only_missing_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let body = segmented_body();
+        let path = first_segment_done(dir.path(), &body, "\"v1\"");
+        let (url, requests) = serve(ranges_of(body.clone(), "\"v1\"")).await;
+
+        download_segmented(&reqwest::Client::new(), &url, &path, 2, "")
+            .await
+            .unwrap();
+
+        assert!(std::fs::read(&path).unwrap() == *body);
+        assert!(!ResumeInfo::path(&path).exists());
+        let seg = SEGMENT_SIZE;
+        assert_eq!(
+            requested_ranges(&requests),
+            [
+                "bytes=0-0".to_string(),
+                format!("bytes={seg}-{}", 2 * seg - 1),
+                format!("bytes={}-{}", 2 * seg, 2 * seg + 99),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn segmented_starts_over_when_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let body = segmented_body();
+        let path = first_segment_done(
+            dir.path(),
+            &[vec![1; 10], body[10..].to_vec()].concat(),
+            "\"v1\"",
+        );
+        let (url, requests) = serve(ranges_of(body.clone(), "\"v2\"")).await;
+
+        download_segmented(&reqwest::Client::new(), &url, &path, 2, "")
+            .await
+            .unwrap();
+
+        assert!(std::fs::read(&path).unwrap() == *body);
+        assert_eq!(requested_ranges(&requests).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn headers_fall_back_to_ranged_get() {
+        let (url, requests) = serve(Arc::new(|req| match req.method.as_str() {
+            "HEAD" => Response {
+                status: 405,
+                headers: vec![],
+                body: vec![],
+            },
+            _ => range(0, "\"v1\""),
+        }))
+        .await;
+
+        let res = fetch_headers(&reqwest::Client::new(), &url).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[1].header("range"), Some("bytes=0-0"));
+    }
+}
@@ -1,22 +1,120 @@
-use color_eyre::eyre::{eyre, Error};
+use color_eyre::eyre::{eyre, ContextCompat, Error};
 use ffmpeg_sidecar::{
     command::FfmpegCommand,
     event::{FfmpegEvent, LogLevel},
 };
 
-use crate::{consts::FFMPEG_SCALE, statics::MPB};
+use crate::{
+    funcs::{
+        http::{FfmpegInput, RequestSettings},
+        quality::{QualityMetric, QualityScore},
+    },
+    parser::LineOptions,
+    statics::{ENCODE_SEMAPHORE, MPB},
+    structs::{EncodeProfile, MediaMetadata},
+};
 
 use super::{
-    ffprobe::ffprobe_path_frametotal,
-    progressbar::{get_progbar, get_spinner, update_pb_by_ffmpegprogress},
+    ffprobe::{ffprobe_frametotal, ffprobe_input, ffprobe_path, ffprobe_path_frametotal},
+    progressbar::{get_progbar, get_spinner, job_label, update_pb_by_ffmpegprogress, JOB_LABEL},
+    reencode::{should_copy, ReencodeMode},
+    subtitles::subtitle_codec,
 };
 
-pub fn ffmpeg_transcode<S: AsRef<str>>(
-    src: S,
+/// Runs a blocking ffmpeg job once one of the `--encode-jobs` slots is free
+async fn spawn_encode<F, T>(f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+    T: Send + 'static,
+{
+    let _permit = ENCODE_SEMAPHORE
+        .get_or_init(|| tokio::sync::Semaphore::new(1))
+        .acquire()
+        .await?;
+
+    let label = job_label();
+    tokio::task::spawn_blocking(move || JOB_LABEL.sync_scope(label, f)).await?
+}
+
+/// What gets encoded: one file or url, or separate video and audio streams to combine
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscodeSource {
+    pub video: String,
+    /// Audio taken from its own input, replacing any audio of the video input
+    pub audio: Option<String>,
+}
+
+impl TranscodeSource {
+    pub fn single(src: impl Into<String>) -> Self {
+        Self {
+            video: src.into(),
+            audio: None,
+        }
+    }
+}
+
+/// Returns whether the streams were encoded, rather than copied
+pub async fn ffmpeg_transcode(
+    src: &TranscodeSource,
     dst: &std::path::Path,
+    profile: &EncodeProfile,
+    opts: &LineOptions,
+    settings: &RequestSettings,
+    reencode: ReencodeMode,
     progbar_msg: &str,
-) -> Result<(), Error> {
-    let frame_total = ffprobe_path_frametotal(&src.as_ref());
+) -> Result<bool, Error> {
+    let src = src.clone();
+    let dst = dst.to_path_buf();
+    let profile = profile.clone();
+    let opts = opts.clone();
+    let settings = settings.clone();
+    let progbar_msg = progbar_msg.to_string();
+
+    spawn_encode(move || {
+        ffmpeg_transcode_blocking(
+            &src,
+            &dst,
+            &profile,
+            &opts,
+            &settings,
+            reencode,
+            &progbar_msg,
+        )
+    })
+    .await
+}
+
+fn ffmpeg_transcode_blocking(
+    src: &TranscodeSource,
+    dst: &std::path::Path,
+    profile: &EncodeProfile,
+    opts: &LineOptions,
+    settings: &RequestSettings,
+    reencode: ReencodeMode,
+    progbar_msg: &str,
+) -> Result<bool, Error> {
+    let probe_input = |x: &str| match ffprobe_input(x, &settings.ffmpeg_input(x)) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            tracing::warn!("ffprobe error: {e}");
+            None
+        }
+    };
+    // The audio input's streams stand in for the ones of the video input
+    let probe = match &src.audio {
+        Some(audio) => probe_input(&src.video)
+            .zip(probe_input(audio))
+            .map(|(mut video, audio)| {
+                video
+                    .streams
+                    .retain(|x| x.codec_type.as_deref() != Some("audio"));
+                video.streams.extend(audio.streams);
+                video
+            }),
+        None => probe_input(&src.video),
+    };
+    let frame_total = probe.as_ref().and_then(ffprobe_frametotal);
+    let copy = opts.skip_transcode || should_copy(reencode, probe.as_ref(), profile, progbar_msg);
 
     let pb = MPB.add(match frame_total {
         Some(len) => get_progbar(
@@ -32,15 +130,39 @@ pub fn ffmpeg_transcode<S: AsRef<str>>(
     pb.tick();
     pb.set_message("0 0/s s:0 b:0kbps");
 
-    let mut ffmpeg = FfmpegCommand::new()
-        .input(src)
-        .codec_video("libx265")
-        .codec_audio("copy")
-        .pix_fmt("yuva420p10le")
-        .args(["-vf", FFMPEG_SCALE])
-        .output(dst.to_string_lossy())
-        .overwrite()
-        .spawn()?;
+    let mut cmd = FfmpegCommand::new();
+
+    for input in std::iter::once(&src.video).chain(&src.audio) {
+        // Input seeking, so the encoder doesn't decode the skipped part
+        if let Some(start) = &opts.start {
+            cmd.seek(start);
+        }
+        if let Some(end) = &opts.end {
+            cmd.to(end);
+        }
+
+        settings.ffmpeg_input(input).apply(cmd.as_inner_mut());
+        cmd.input(input);
+    }
+    if src.audio.is_some() {
+        cmd.args(["-map", "0:v:0", "-map", "1:a:0"]);
+    }
+
+    if copy {
+        // Streams the profile drops stay dropped when copying
+        match profile.video_codec {
+            Some(_) => cmd.codec_video("copy"),
+            None => cmd.no_video(),
+        };
+        match profile.audio_codec {
+            Some(_) => cmd.codec_audio("copy"),
+            None => cmd.no_audio(),
+        };
+    } else {
+        profile.apply(&mut cmd);
+    }
+
+    let mut ffmpeg = cmd.output(dst.to_string_lossy()).overwrite().spawn()?;
 
     ffmpeg
         .iter()
@@ -56,11 +178,19 @@ pub fn ffmpeg_transcode<S: AsRef<str>>(
         })
         .collect::<Result<Vec<_>, Error>>()?;
 
-    Ok(())
+    pb.finish_and_clear();
+
+    Ok(!copy)
+}
+
+pub async fn ffmpeg_check(src: &std::path::Path) -> Result<(), Error> {
+    let src = src.to_path_buf();
+
+    spawn_encode(move || ffmpeg_check_blocking(&src)).await
 }
 
-pub fn ffmpeg_check(src: &std::path::Path) -> Result<(), Error> {
-    let frame_total = ffprobe_path_frametotal(src);
+fn ffmpeg_check_blocking(src: &std::path::Path) -> Result<(), Error> {
+    let frame_total = ffprobe_path_frametotal(src, &Default::default());
     let pb = MPB.add(match frame_total {
         Some(len) => get_progbar(
             len,
@@ -95,5 +225,309 @@ pub fn ffmpeg_check(src: &std::path::Path) -> Result<(), Error> {
         })
         .collect::<Result<Vec<_>, Error>>()?;
 
+    pb.finish_and_clear();
+
+    Ok(())
+}
+
+/// Whether ffmpeg was built with libvmaf
+fn has_libvmaf() -> bool {
+    static HAS_LIBVMAF: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+
+    *HAS_LIBVMAF.get_or_init(|| {
+        std::process::Command::new(ffmpeg_sidecar::paths::ffmpeg_path())
+            .args(["-hide_banner", "-filters"])
+            .output()
+            .is_ok_and(|x| String::from_utf8_lossy(&x.stdout).contains(" libvmaf "))
+    })
+}
+
+/// Value following `key` in an ffmpeg log line, e.g. `All:` in the ssim filter's summary
+fn log_value(line: &str, key: &str) -> Option<f64> {
+    let (_, rest) = line.split_once(key)?;
+    rest.split_whitespace().next()?.parse().ok()
+}
+
+/// Scores the encoded file against its source with VMAF, or SSIM when ffmpeg lacks libvmaf.
+/// The source is trimmed the same way it was for the encode.
+pub async fn ffmpeg_quality(
+    encoded: &std::path::Path,
+    source: &str,
+    opts: &LineOptions,
+    settings: &RequestSettings,
+    progbar_msg: &str,
+) -> Result<QualityScore, Error> {
+    let encoded = encoded.to_path_buf();
+    let source = source.to_string();
+    let opts = opts.clone();
+    let input = settings.ffmpeg_input(&source);
+    let progbar_msg = progbar_msg.to_string();
+
+    spawn_encode(move || ffmpeg_quality_blocking(&encoded, &source, &opts, &input, &progbar_msg))
+        .await
+}
+
+fn ffmpeg_quality_blocking(
+    encoded: &std::path::Path,
+    source: &str,
+    opts: &LineOptions,
+    input: &FfmpegInput,
+    progbar_msg: &str,
+) -> Result<QualityScore, Error> {
+    let metric = if has_libvmaf() {
+        QualityMetric::Vmaf
+    } else {
+        tracing::debug!("ffmpeg has no libvmaf, falling back to SSIM");
+        QualityMetric::Ssim
+    };
+
+    // Both sides need the same timestamps, format and size, so the output gets scaled back
+    // up to the source resolution before comparing
+    let prepare = "[0:v]setpts=PTS-STARTPTS,format=yuv420p10le[d];\
+        [1:v]setpts=PTS-STARTPTS,format=yuv420p10le[r];\
+        [d][r]scale2ref=flags=bicubic[dist][ref]";
+    let threads = std::thread::available_parallelism().map_or(1, |x| x.get());
+    let graph = match metric {
+        QualityMetric::Vmaf => format!("{prepare};[dist][ref]libvmaf=n_threads={threads}"),
+        QualityMetric::Ssim => {
+            format!("{prepare};[dist]split[d1][d2];[ref]split[r1][r2];[d1][r1]ssim;[d2][r2]psnr")
+        }
+    };
+
+    let frame_total = ffprobe_path_frametotal(encoded, &Default::default());
+    let pb = MPB.add(match frame_total {
+        Some(len) => get_progbar(
+            len,
+            crate::consts::MAIN_BAR_FMT_MSG,
+            crate::consts::SUB_BAR_CHARSET,
+        )?,
+        None => get_spinner(
+            crate::consts::SPINNER_FMT,
+            crate::consts::SPINNER_STRSET_MATERIAL,
+        )?,
+    });
+    pb.tick();
+    pb.set_message("0 0/s s:0 b:0kbps");
+
+    let mut cmd = FfmpegCommand::new();
+    cmd.input(encoded.to_string_lossy());
+
+    if let Some(start) = &opts.start {
+        cmd.seek(start);
+    }
+    if let Some(end) = &opts.end {
+        cmd.to(end);
+    }
+    input.apply(cmd.as_inner_mut());
+    cmd.input(source);
+
+    let mut ffmpeg = cmd
+        .args(["-lavfi", &graph])
+        .format("null")
+        .output("-")
+        .spawn()?;
+
+    let mut score = None;
+    ffmpeg
+        .iter()
+        .map_err(|m| eyre!(m))?
+        .map(|e| {
+            match e {
+                FfmpegEvent::Log(LogLevel::Error, e) => color_eyre::eyre::bail!(e),
+                FfmpegEvent::Log(_, line) => match metric {
+                    QualityMetric::Vmaf => {
+                        score = log_value(&line, "VMAF score: ").or(score);
+                    }
+                    QualityMetric::Ssim => {
+                        if line.contains("SSIM ") {
+                            score = log_value(&line, "All:").or(score);
+                        } else if let Some(psnr) = line
+                            .contains("PSNR ")
+                            .then(|| log_value(&line, "average:"))
+                            .flatten()
+                        {
+                            tracing::info!("{progbar_msg}: PSNR {psnr} dB");
+                        }
+                    }
+                },
+                FfmpegEvent::Progress(p) => update_pb_by_ffmpegprogress(&pb, p, progbar_msg),
+                _e => {}
+            };
+
+            color_eyre::eyre::Ok(())
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    pb.finish_and_clear();
+
+    let score = score.wrap_err_with(|| format!("ffmpeg didn't report a {metric} score"))?;
+
+    Ok(QualityScore { metric, score })
+}
+
+/// Writes tags, chapters, subtitles and cover art into the file, remuxing it without encoding
+pub async fn ffmpeg_embed_metadata(
+    path: &std::path::Path,
+    metadata: &MediaMetadata,
+    opts: &LineOptions,
+) -> Result<(), Error> {
+    let mut metadata = metadata.clone();
+    metadata.trim(opts);
+
+    if metadata.is_empty() {
+        return Ok(());
+    }
+
+    let path = path.to_path_buf();
+
+    spawn_encode(move || ffmpeg_embed_metadata_blocking(&path, &metadata)).await
+}
+
+/// Runs ffmpeg to completion, failing on any error it logs
+fn run_ffmpeg(cmd: &mut FfmpegCommand) -> Result<(), Error> {
+    cmd.spawn()?
+        .iter()
+        .map_err(|m| eyre!(m))?
+        .try_for_each(|e| match e {
+            FfmpegEvent::Log(LogLevel::Error, e) => color_eyre::eyre::bail!(e),
+            _ => Ok(()),
+        })
+}
+
+fn ffmpeg_embed_metadata_blocking(
+    path: &std::path::Path,
+    metadata: &MediaMetadata,
+) -> Result<(), Error> {
+    let ext = path
+        .extension()
+        .map(|x| x.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    let temp_path = tempfile::TempPath::from_path(path.with_file_name(format!(
+        "{}_meta.{ext}",
+        path.file_stem().unwrap_or_default().to_string_lossy()
+    )));
+
+    // Output options can only follow every input, so they're collected separately
+    let mut cmd = FfmpegCommand::new();
+    let mut out_args = ["-map", "0", "-c", "copy"].map(String::from).to_vec();
+    cmd.input(path.to_string_lossy());
+    let mut inputs = 1;
+
+    let chapters_file = tempfile::Builder::new().suffix(".txt").tempfile()?;
+    if !metadata.chapters.is_empty() {
+        std::fs::write(chapters_file.path(), metadata.ffmetadata())?;
+        cmd.input(chapters_file.path().to_string_lossy());
+        out_args.extend(["-map_chapters".to_string(), inputs.to_string()]);
+        inputs += 1;
+    }
+
+    // Subtitle streams follow every stream of the file, with the cover after them
+    let streams = ffprobe_path(path)?.streams.len();
+    let subtitle_codec = match subtitle_codec(path) {
+        Some(codec) => codec,
+        None if metadata.subtitles.is_empty() => "copy",
+        None => color_eyre::eyre::bail!("{ext} can't hold subtitle streams"),
+    };
+    for (i, subtitle) in metadata.subtitles.iter().enumerate() {
+        let idx = streams + i;
+        if subtitle.start > 0.0 {
+            cmd.args(["-ss", &subtitle.start.to_string()]);
+        }
+        if let Some(duration) = subtitle.duration {
+            cmd.args(["-t", &duration.to_string()]);
+        }
+        cmd.input(subtitle.path.to_string_lossy());
+        out_args.extend([
+            "-map".to_string(),
+            format!("{inputs}:0"),
+            format!("-c:{idx}"),
+            subtitle_codec.to_string(),
+            format!("-metadata:s:{idx}"),
+            format!("title={}", subtitle.title),
+        ]);
+        if let Some(language) = subtitle.language {
+            out_args.extend([format!("-metadata:s:{idx}"), format!("language={language}")]);
+        }
+        inputs += 1;
+    }
+
+    // Cover art only goes into containers known to hold one, as jpeg or png
+    let cover_kind = match ext.as_str() {
+        "mp4" | "m4a" | "m4v" | "mov" => Some("attached_pic"),
+        "mkv" | "mka" => Some("attachment"),
+        _ => None,
+    };
+    let mut converted = None;
+    let cover = match (&metadata.cover, cover_kind) {
+        (Some(_), None) => {
+            tracing::debug!("Not adding cover art, {ext} can't hold it");
+            None
+        }
+        (Some(src), Some(_)) => {
+            let src_ext = src
+                .extension()
+                .map(|x| x.to_string_lossy().to_ascii_lowercase());
+
+            match src_ext.as_deref() {
+                Some("jpg" | "jpeg" | "png") => Some(src.clone()),
+                _ => {
+                    let dst = tempfile::Builder::new()
+                        .suffix(".jpg")
+                        .tempfile()?
+                        .into_temp_path();
+                    run_ffmpeg(
+                        FfmpegCommand::new()
+                            .input(src.to_string_lossy())
+                            .frames(1)
+                            .output(dst.to_string_lossy())
+                            .overwrite(),
+                    )?;
+
+                    Some(converted.insert(dst).to_path_buf())
+                }
+            }
+        }
+        (None, _) => None,
+    };
+
+    if let (Some(cover), Some(kind)) = (&cover, cover_kind) {
+        let idx = streams + metadata.subtitles.len();
+
+        if kind == "attached_pic" {
+            cmd.input(cover.to_string_lossy());
+            out_args.extend([
+                "-map".to_string(),
+                format!("{inputs}:v:0"),
+                format!("-disposition:{idx}"),
+                "attached_pic".to_string(),
+            ]);
+        } else {
+            let (mimetype, filename) = match cover.extension().and_then(|x| x.to_str()) {
+                Some("png") => ("image/png", "cover.png"),
+                _ => ("image/jpeg", "cover.jpg"),
+            };
+            out_args.extend([
+                "-attach".to_string(),
+                cover.to_string_lossy().into_owned(),
+                format!("-metadata:s:{idx}"),
+                format!("mimetype={mimetype}"),
+                format!("-metadata:s:{idx}"),
+                format!("filename={filename}"),
+            ]);
+        }
+    }
+
+    for (key, value) in metadata.tags() {
+        out_args.extend(["-metadata".to_string(), format!("{key}={value}")]);
+    }
+
+    run_ffmpeg(
+        cmd.args(out_args)
+            .output(temp_path.to_string_lossy())
+            .overwrite(),
+    )?;
+
+    temp_path.persist(path)?;
+
     Ok(())
 }
@@ -1,35 +1,111 @@
 use color_eyre::eyre::Error;
 
-pub fn ffprobe_path_frametotal(path: impl AsRef<std::path::Path>) -> Option<u64> {
-    match ffprobe::ffprobe(path) {
+use crate::funcs::http::FfmpegInput;
+
+pub fn ffprobe_path_frametotal(
+    path: impl AsRef<std::path::Path>,
+    input: &FfmpegInput,
+) -> Option<u64> {
+    match ffprobe_input(path, input) {
         Err(e) => {
             tracing::warn!("ffprobe error: {e}");
             None
         }
-        Ok(i) => {
-            let mut count = None;
-            for (i, s) in i.streams.iter().enumerate() {
-                if let Some(fcount) = &s.nb_frames {
-                    let parsed_fcount = match fcount.parse::<u64>() {
-                        Ok(f) => f,
-                        Err(_) => {
-                            tracing::warn!("Failed to parse frame count from ffprobe: {fcount}");
-                            return None;
-                        }
-                    };
-
-                    count = Some(parsed_fcount);
-                    break;
-                }
+        Ok(i) => ffprobe_frametotal(&i),
+    }
+}
 
-                tracing::trace!("Stream #{i} can't find nb_frames");
-            }
+pub fn ffprobe_frametotal(probe: &ffprobe::FfProbe) -> Option<u64> {
+    let mut count = None;
+    for (i, s) in probe.streams.iter().enumerate() {
+        if let Some(fcount) = &s.nb_frames {
+            let parsed_fcount = match fcount.parse::<u64>() {
+                Ok(f) => f,
+                Err(_) => {
+                    tracing::warn!("Failed to parse frame count from ffprobe: {fcount}");
+                    return None;
+                }
+            };
 
-            count
+            count = Some(parsed_fcount);
+            break;
         }
+
+        tracing::trace!("Stream #{i} can't find nb_frames");
     }
+
+    count
+}
+
+/// Height of the first video stream, None for audio-only sources
+pub fn ffprobe_height(probe: &ffprobe::FfProbe) -> Option<i64> {
+    probe
+        .streams
+        .iter()
+        .find(|x| x.codec_type.as_deref() == Some("video"))?
+        .height
 }
 
 pub fn ffprobe_path(path: impl AsRef<std::path::Path>) -> Result<ffprobe::FfProbe, Error> {
     Ok(ffprobe::ffprobe(path)?)
 }
+
+/// Same as ffprobe_path, with options like `-headers` placed before the input.
+/// The ffprobe crate can't pass those, so this runs ffprobe itself the same way it does.
+pub fn ffprobe_input(
+    path: impl AsRef<std::path::Path>,
+    input: &FfmpegInput,
+) -> Result<ffprobe::FfProbe, Error> {
+    if input.is_empty() {
+        return ffprobe_path(path);
+    }
+
+    let mut cmd = std::process::Command::new("ffprobe");
+    cmd.args([
+        "-v",
+        "quiet",
+        "-show_format",
+        "-show_streams",
+        "-print_format",
+        "json",
+    ]);
+    input.apply(&mut cmd);
+    let out = cmd.arg(path.as_ref()).output()?;
+
+    if !out.status.success() {
+        color_eyre::eyre::bail!("ffprobe exited with {}", out.status);
+    }
+
+    Ok(serde_json::from_slice(&out.stdout)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(codec_type: &str, height: Option<i64>) -> ffprobe::Stream {
+        ffprobe::Stream {
+            codec_type: Some(codec_type.to_string()),
+            height,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn heights() {
+        let probe = |streams| ffprobe::FfProbe {
+            streams,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            ffprobe_height(&probe(vec![
+                stream("audio", None),
+                stream("video", Some(1080))
+            ])),
+            Some(1080)
+        );
+        assert_eq!(ffprobe_height(&probe(vec![stream("audio", None)])), None);
+        assert_eq!(ffprobe_height(&probe(vec![stream("video", None)])), None);
+    }
+}
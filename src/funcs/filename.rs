@@ -0,0 +1,294 @@
+use reqwest::header;
+
+#[derive(Debug, Clone, Copy, PartialEq, strum::Display)]
+enum FilenameSource {
+    #[strum(to_string = "Content-Disposition")]
+    ContentDisposition,
+    #[strum(to_string = "URL path")]
+    UrlPath,
+    #[strum(to_string = "Content-Type")]
+    ContentType,
+    #[strum(to_string = "URL hash")]
+    UrlHash,
+}
+
+/// Keeps only the last path component, so a server can't write outside the target directory
+pub fn sanitize(name: &str) -> Option<String> {
+    let name = name.replace('\\', "/");
+    let name = name.rsplit('/').next()?.trim().trim_matches('"');
+
+    match name {
+        "" | "." | ".." => None,
+        x => Some(x.to_string()),
+    }
+}
+
+/// Decodes an RFC 5987 extended value, e.g. `UTF-8'en'%E2%82%AC%20rates.mp4`
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.trim_matches('"').splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let bytes = urlencoding::decode_binary(parts.next()?.as_bytes());
+
+    if charset.eq_ignore_ascii_case("utf-8") {
+        String::from_utf8(bytes.into_owned()).ok()
+    } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+        Some(bytes.iter().map(|&x| x as char).collect())
+    } else {
+        None
+    }
+}
+
+pub fn filename_from_content_disposition(value: &str) -> Option<String> {
+    let parsed = content_disposition::parse_content_disposition(value);
+
+    // filename* wins over filename, but the parser only decodes it when filename is missing
+    let name = match parsed.params.get("filename*") {
+        Some(ext_value) => decode_ext_value(ext_value).or(parsed.filename_full()),
+        None => parsed.filename_full(),
+    };
+
+    sanitize(&name?)
+}
+
+pub fn filename_from_url(url: &reqwest::Url) -> Option<String> {
+    let segment = url.path_segments()?.next_back()?;
+    sanitize(&urlencoding::decode(segment).ok()?)
+}
+
+/// Extension of common video and audio MIME types
+pub fn ext_from_mime(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.parse::<mime::Mime>().ok()?;
+
+    let ext = match (mime.type_().as_str(), mime.subtype().as_str()) {
+        ("video", "mp4") => "mp4",
+        ("video", "webm") => "webm",
+        ("video", "x-matroska") => "mkv",
+        ("video", "quicktime") => "mov",
+        ("video", "x-msvideo") => "avi",
+        ("video", "x-flv") => "flv",
+        ("video", "mpeg") => "mpg",
+        ("video", "mp2t") => "ts",
+        ("video", "ogg") => "ogv",
+        ("video", "3gpp") => "3gp",
+        ("video", "x-ms-wmv") => "wmv",
+        ("audio", "mpeg") => "mp3",
+        ("audio", "mp4") => "m4a",
+        ("audio", "ogg") => "ogg",
+        ("audio", "webm") => "weba",
+        ("audio", "wav") | ("audio", "x-wav") => "wav",
+        ("audio", "flac") => "flac",
+        _ => return None,
+    };
+
+    Some(ext)
+}
+
+fn pick_filename(
+    url: &reqwest::Url,
+    content_disposition: Option<&str>,
+    content_type: Option<&str>,
+) -> (String, FilenameSource) {
+    let mime_ext = content_type.and_then(ext_from_mime);
+    let url_hash = format!("{:x}", md5::compute(url.as_str()))[..12].to_string();
+
+    if let Some(name) = content_disposition.and_then(filename_from_content_disposition) {
+        (name, FilenameSource::ContentDisposition)
+    } else if let Some(name) = filename_from_url(url) {
+        // Paths like /download/12345 still get a usable extension
+        let name = match (std::path::Path::new(&name).extension(), mime_ext) {
+            (None, Some(ext)) => format!("{name}.{ext}"),
+            _ => name,
+        };
+        (name, FilenameSource::UrlPath)
+    } else if let Some(ext) = mime_ext {
+        (format!("{url_hash}.{ext}"), FilenameSource::ContentType)
+    } else {
+        (url_hash, FilenameSource::UrlHash)
+    }
+}
+
+/// Picks a file name for a direct download: Content-Disposition, then the URL path after
+/// redirects, then the Content-Type, and finally a hash of the URL
+pub fn detect_filename(res: &reqwest::Response) -> String {
+    let header = |name| res.headers().get(name).and_then(|x| x.to_str().ok());
+    let (name, source) = pick_filename(
+        res.url(),
+        header(header::CONTENT_DISPOSITION),
+        header(header::CONTENT_TYPE),
+    );
+
+    tracing::info!("Using file name '{name}' from {source}");
+
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_disposition() {
+        let cases = [
+            ("attachment; filename=video.mp4", Some("video.mp4")),
+            (
+                "attachment; filename=\"my video.mp4\"",
+                Some("my video.mp4"),
+            ),
+            (
+                "attachment; filename=\"fallback.mp4\"; filename*=UTF-8''%E2%82%AC%20rates.mp4",
+                Some("€ rates.mp4"),
+            ),
+            (
+                "attachment; filename*=UTF-8''%E2%82%AC.mp4; filename=\"fallback.mp4\"",
+                Some("€.mp4"),
+            ),
+            (
+                "attachment; filename*=iso-8859-1'en'%E9t%E9.mp4",
+                Some("été.mp4"),
+            ),
+            ("attachment; filename=\"../../etc/passwd\"", Some("passwd")),
+            (
+                "attachment; filename=\"..\\\\..\\\\boot.ini\"",
+                Some("boot.ini"),
+            ),
+            ("attachment; filename=\"\"", None),
+            ("attachment; filename=..", None),
+            ("attachment; filename=.", None),
+            ("attachment", None),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(
+                filename_from_content_disposition(value).as_deref(),
+                expected,
+                "{value}"
+            );
+        }
+    }
+
+    #[test]
+    fn ext_values() {
+        let cases = [
+            ("UTF-8''a%20b.mp4", Some("a b.mp4")),
+            ("utf-8'en'%C3%A9.mp4", Some("é.mp4")),
+            ("ISO-8859-1''%E9.mp4", Some("é.mp4")),
+            ("\"UTF-8''quoted.mp4\"", Some("quoted.mp4")),
+            ("UTF-8''%FF.mp4", None),
+            ("Shift_JIS''a.mp4", None),
+            ("no-quotes.mp4", None),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(decode_ext_value(value).as_deref(), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn sanitizes() {
+        let cases = [
+            ("video.mp4", Some("video.mp4")),
+            ("  spaced.mp4 ", Some("spaced.mp4")),
+            ("\"quoted.mp4\"", Some("quoted.mp4")),
+            ("../../etc/passwd", Some("passwd")),
+            ("..\\..\\windows\\win.ini", Some("win.ini")),
+            ("dir/", None),
+            ("", None),
+            (".", None),
+            ("..", None),
+            ("a/..", None),
+        ];
+
+        for (name, expected) in cases {
+            assert_eq!(sanitize(name).as_deref(), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn url_paths() {
+        let cases = [
+            ("https://example.com/files/video.mp4?x=1", Some("video.mp4")),
+            (
+                "https://example.com/files/my%20video.mkv",
+                Some("my video.mkv"),
+            ),
+            ("https://example.com/download/12345", Some("12345")),
+            ("https://example.com/a/%2E%2E", None),
+            ("https://example.com/", None),
+        ];
+
+        for (url, expected) in cases {
+            let url = reqwest::Url::parse(url).unwrap();
+            assert_eq!(filename_from_url(&url).as_deref(), expected, "{url}");
+        }
+    }
+
+    #[test]
+    fn picks_filename() {
+        use FilenameSource::*;
+
+        let root = "https://example.com/";
+        let hash = format!("{:x}", md5::compute(root))[..12].to_string();
+        let id = "https://example.com/download/12345";
+        let cases = [
+            (
+                id,
+                Some("attachment; filename=a.mkv"),
+                None,
+                "a.mkv".to_string(),
+                ContentDisposition,
+            ),
+            (
+                id,
+                Some("inline"),
+                Some("video/webm"),
+                "12345.webm".to_string(),
+                UrlPath,
+            ),
+            (id, None, None, "12345".to_string(), UrlPath),
+            (
+                "https://example.com/a.mp4",
+                None,
+                Some("video/webm"),
+                "a.mp4".to_string(),
+                UrlPath,
+            ),
+            (
+                root,
+                None,
+                Some("video/mp4"),
+                format!("{hash}.mp4"),
+                ContentType,
+            ),
+            (root, None, Some("text/html"), hash.clone(), UrlHash),
+        ];
+
+        for (url, disposition, content_type, name, source) in cases {
+            let parsed = reqwest::Url::parse(url).unwrap();
+            assert_eq!(
+                pick_filename(&parsed, disposition, content_type),
+                (name, source),
+                "{url} {disposition:?} {content_type:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn mime_extensions() {
+        let cases = [
+            ("video/mp4", Some("mp4")),
+            ("video/x-matroska", Some("mkv")),
+            ("video/webm; codecs=\"vp9\"", Some("webm")),
+            ("VIDEO/QUICKTIME", Some("mov")),
+            ("audio/mpeg", Some("mp3")),
+            ("audio/x-wav", Some("wav")),
+            ("application/octet-stream", None),
+            ("text/html; charset=utf-8", None),
+            ("not a mime", None),
+        ];
+
+        for (content_type, expected) in cases {
+            assert_eq!(ext_from_mime(content_type), expected, "{content_type}");
+        }
+    }
+}
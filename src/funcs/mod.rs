@@ -1,5 +1,15 @@
+pub mod b2;
+pub mod download;
 pub mod ffmpeg;
 pub mod ffprobe;
+pub mod filename;
+pub mod fs;
+pub mod http;
 pub mod md5;
 pub mod opendal;
 pub mod progressbar;
+pub mod quality;
+pub mod reencode;
+pub mod retry;
+pub mod subtitles;
+pub mod ytdlp;
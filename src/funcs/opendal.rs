@@ -1,41 +1,132 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
 use async_compat::CompatExt;
-use color_eyre::eyre::{ContextCompat, Error};
-use futures_util::AsyncWriteExt;
-
-pub fn setup_opendal(service_string: &str) -> Result<opendal::Operator, Error> {
-    let [service, id, key, bucket, bucket_id, rootpath] =
-        service_string.split(';').collect::<Vec<_>>()[0..6]
-    else {
-        color_eyre::eyre::bail!("Error parsing service string")
-    };
+use color_eyre::eyre::{bail, ContextCompat, Error};
+use futures_util::{future::join_all, AsyncWriteExt};
+use opendal::{services, Operator, Scheme};
+
+use super::b2::{sha1_of_path, sha1_of_remote, B2Account};
+use crate::{consts::UPLOAD_VERIFY_ATTEMPTS, structs::RemoteConfig};
 
-    let builder = match service {
-        "B2" | "b2" => {
-            opendal::services::B2::default()
-                // set the storage bucket for OpenDAL
-                .root(rootpath)
+pub fn setup_remote(config: &RemoteConfig) -> Result<Operator, Error> {
+    let op = match config {
+        RemoteConfig::B2 {
+            application_key_id,
+            application_key,
+            bucket,
+            bucket_id,
+            root,
+        } => {
+            let mut builder = services::B2::default()
                 // set the application_key_id for OpenDAL
-                .application_key_id(id)
+                .application_key_id(application_key_id)
                 // set the application_key for OpenDAL
-                .application_key(key)
+                .application_key(application_key)
                 // set the bucket name for OpenDAL
                 .bucket(bucket)
-                .bucket_id(bucket_id)
+                .bucket_id(bucket_id);
+            if let Some(x) = root {
+                // set the storage root for OpenDAL
+                builder = builder.root(x);
+            }
+
+            Operator::new(builder)?.finish()
+        }
+        RemoteConfig::S3 {
+            bucket,
+            endpoint,
+            region,
+            access_key_id,
+            secret_access_key,
+            virtual_host_style,
+            root,
+        } => {
+            let mut builder = services::S3::default().bucket(bucket);
+            if let Some(x) = endpoint {
+                builder = builder.endpoint(x);
+            }
+            // Non-AWS stores usually don't care, but the signer needs one
+            builder = builder.region(region.as_deref().unwrap_or("us-east-1"));
+            if let Some(x) = access_key_id {
+                builder = builder.access_key_id(x);
+            }
+            if let Some(x) = secret_access_key {
+                builder = builder.secret_access_key(x);
+            }
+            if *virtual_host_style {
+                builder = builder.enable_virtual_host_style();
+            }
+            if let Some(x) = root {
+                builder = builder.root(x);
+            }
+
+            Operator::new(builder)?.finish()
+        }
+        RemoteConfig::Fs { root } => Operator::new(services::Fs::default().root(root))?.finish(),
+        RemoteConfig::Webdav {
+            endpoint,
+            username,
+            password,
+            token,
+            root,
+        } => {
+            let mut builder = services::Webdav::default().endpoint(endpoint);
+            if let Some(x) = username {
+                builder = builder.username(x);
+            }
+            if let Some(x) = password {
+                builder = builder.password(x);
+            }
+            if let Some(x) = token {
+                builder = builder.token(x);
+            }
+            if let Some(x) = root {
+                builder = builder.root(x);
+            }
+
+            Operator::new(builder)?.finish()
+        }
+        RemoteConfig::Sftp {
+            endpoint,
+            user,
+            key,
+            known_hosts_strategy,
+            root,
+        } => {
+            let mut builder = services::Sftp::default().endpoint(endpoint);
+            if let Some(x) = user {
+                builder = builder.user(x);
+            }
+            if let Some(x) = key {
+                builder = builder.key(x);
+            }
+            if let Some(x) = known_hosts_strategy {
+                builder = builder.known_hosts_strategy(x);
+            }
+            if let Some(x) = root {
+                builder = builder.root(x);
+            }
+
+            Operator::new(builder)?.finish()
         }
-        others => unimplemented!("{others} service is not implemented"),
     };
 
-    Ok(opendal::Operator::new(builder)?
-        .layer(opendal::layers::BlockingLayer::create()?)
-        .layer(
-            opendal::layers::RetryLayer::new()
-                .with_factor(2.0)
-                .with_max_times(128),
-        )
-        .finish())
+    Ok(op.layer(opendal::layers::BlockingLayer::create()?).layer(
+        opendal::layers::RetryLayer::new()
+            .with_factor(2.0)
+            .with_max_times(128),
+    ))
 }
 
-pub async fn copy_path_to_b2(path: &std::path::Path, op: &opendal::Operator) -> Result<(), Error> {
+/// Uploads the file to the operator root, returning the remote path
+pub async fn copy_path_to_b2(
+    path: &std::path::Path,
+    op: &opendal::Operator,
+    progbar_msg: &str,
+) -> Result<String, Error> {
     let file = tokio::fs::File::open(path).await?;
     let filename = path
         .file_name()
@@ -49,6 +140,7 @@ pub async fn copy_path_to_b2(path: &std::path::Path, op: &opendal::Operator) ->
         crate::consts::SUB_BAR_FMT_MSG,
         crate::consts::MAIN_BAR_CHARSET,
     )?);
+    pb.set_message(progbar_msg.to_string());
 
     let mut wrapped_file = pb.wrap_async_read(file);
 
@@ -61,25 +153,331 @@ pub async fn copy_path_to_b2(path: &std::path::Path, op: &opendal::Operator) ->
     tokio::io::copy(&mut wrapped_file, &mut writer.compat_mut()).await?;
 
     writer.close().await?;
+    pb.finish_and_clear();
+
+    Ok(filename.into_owned())
+}
+
+/// Checks the uploaded file against the local one. Size is always compared. B2 files are
+/// compared by SHA1, asked from the B2 API since OpenDAL's stat leaves it out, or hashed from
+/// the remote copy for large files that have none on record. Elsewhere MD5 is compared where
+/// the backend reports one, like S3 for uploads that weren't multipart.
+async fn verify_upload(
+    dest: &Destination,
+    path: &std::path::Path,
+    remote_path: &str,
+    size: u64,
+    md5: &str,
+) -> Result<(), Error> {
+    let op = &dest.op;
+    let meta = op.stat(remote_path).await?;
+
+    if meta.content_length() != size {
+        bail!(
+            "size mismatch, expected {size} bytes but got {}",
+            meta.content_length()
+        );
+    }
+
+    if let RemoteConfig::B2 {
+        application_key_id,
+        application_key,
+        bucket_id,
+        root,
+        ..
+    } = &dest.config
+    {
+        let account = B2Account {
+            application_key_id,
+            application_key,
+            bucket_id,
+            root: root.as_deref(),
+        };
+        let local_sha1 = sha1_of_path(path).await?;
+        let remote_sha1 = match account.file_sha1(remote_path).await? {
+            Some(x) => x,
+            None => {
+                tracing::debug!("No SHA1 recorded for {remote_path}, hashing the uploaded file");
+                sha1_of_remote(op, remote_path).await?
+            }
+        };
+
+        if remote_sha1 != local_sha1 {
+            bail!("SHA1 mismatch, expected {local_sha1} but got {remote_sha1}");
+        }
+        return Ok(());
+    }
+
+    // Only S3 etags are known to be MD5, other backends use them as opaque versions
+    let remote_md5 = meta
+        .content_md5()
+        .or_else(|| match op.info().scheme() {
+            Scheme::S3 => meta.etag(),
+            _ => None,
+        })
+        .map(|x| x.trim_matches('"').to_ascii_lowercase())
+        .filter(|x| x.len() == 32 && x.chars().all(|c| c.is_ascii_hexdigit()));
+
+    match remote_md5 {
+        Some(remote_md5) if remote_md5 != md5 => {
+            bail!("MD5 mismatch, expected {md5} but got {remote_md5}")
+        }
+        Some(_) => {}
+        None => tracing::debug!("No checksum available for {remote_path}, verified size only"),
+    }
 
     Ok(())
 }
 
-pub async fn check_path_exists(
+/// Uploads and verifies the file, uploading again when what landed remotely doesn't match
+async fn upload_verified(
     path: &std::path::Path,
-    op: &opendal::Operator,
-) -> Result<bool, Error> {
-    let filename = path
-        .file_name()
-        .wrap_err("Invalid filename")?
-        .to_string_lossy();
+    dest: &Destination,
+    size: u64,
+    md5: &str,
+) -> Result<String, Error> {
+    for attempt in 1..=UPLOAD_VERIFY_ATTEMPTS {
+        let remote_path = copy_path_to_b2(path, &dest.op, &dest.name).await?;
+
+        match verify_upload(dest, path, &remote_path, size, md5).await {
+            Ok(()) => return Ok(remote_path),
+            Err(e) => tracing::warn!(
+                "Verification of {remote_path} on {} failed (attempt {attempt}/{UPLOAD_VERIFY_ATTEMPTS}): {e}",
+                dest.name
+            ),
+        }
+    }
+
+    bail!("Upload didn't pass verification after {UPLOAD_VERIFY_ATTEMPTS} attempts")
+}
+
+// (destination, error) of every failed upload of a file
+type UploadFailures = Vec<(String, String)>;
+
+/// Uploads that failed even after being retried on their own. The job isn't run again for
+/// these, since downloading and encoding again wouldn't help.
+#[derive(Debug, Clone)]
+pub struct UploadFailed {
+    pub names: String,
+}
+
+impl std::fmt::Display for UploadFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Upload to {} failed", self.names)
+    }
+}
+
+impl std::error::Error for UploadFailed {}
+
+#[derive(Clone)]
+pub struct Destination {
+    pub name: String,
+    pub op: Operator,
+    config: RemoteConfig,
+}
+
+impl Destination {
+    pub fn new(name: String, config: &RemoteConfig) -> Result<Self, Error> {
+        Ok(Self {
+            name,
+            op: setup_remote(config)?,
+            config: config.clone(),
+        })
+    }
+}
+
+/// Every upload destination of the run, along with the files that failed to reach them
+#[derive(Clone, Default)]
+pub struct Destinations {
+    list: Vec<Destination>,
+    /// Attempts per destination, like `--retry` for whole jobs
+    retry: usize,
+    // Keyed by local path, only keeps the latest attempt
+    failures: Arc<Mutex<BTreeMap<String, UploadFailures>>>,
+}
+
+impl Destinations {
+    pub fn new(list: Vec<Destination>, retry: usize) -> Self {
+        Self {
+            list,
+            retry,
+            ..Default::default()
+        }
+    }
+
+    async fn upload_retried(
+        &self,
+        path: &std::path::Path,
+        dest: &Destination,
+        size: u64,
+        md5: &str,
+    ) -> Result<String, Error> {
+        let attempts = self.retry.max(1);
+        let mut attempt = 1;
+
+        loop {
+            match upload_verified(path, dest, size, md5).await {
+                Ok(remote_path) => return Ok(remote_path),
+                Err(e) if attempt < attempts => tracing::warn!(
+                    "Upload of {} to {} failed (attempt {attempt}/{attempts}): {e}",
+                    path.display(),
+                    dest.name
+                ),
+                Err(e) => return Err(e),
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Uploads the file to every destination, failing with [`UploadFailed`] unless all of them
+    /// succeeded and verified. Failed destinations are retried up to `retry` times on their own.
+    /// Returns the remote paths as `name:path`, or None if there's nowhere to upload to.
+    pub async fn upload(
+        &self,
+        path: &std::path::Path,
+        size: u64,
+        md5: &str,
+    ) -> Result<Option<String>, Error> {
+        if self.list.is_empty() {
+            return Ok(None);
+        }
+
+        let results = join_all(
+            self.list
+                .iter()
+                .map(|x| self.upload_retried(path, x, size, md5)),
+        )
+        .await;
+
+        let mut remote_paths = vec![];
+        let mut failed = vec![];
+        for (dest, result) in self.list.iter().zip(results) {
+            match result {
+                Ok(remote_path) => remote_paths.push(format!("{}:{remote_path}", dest.name)),
+                Err(e) => {
+                    tracing::warn!("Upload of {} to {} failed: {e}", path.display(), dest.name);
+                    failed.push((dest.name.clone(), e.to_string()));
+                }
+            }
+        }
+
+        let mut failures = self.failures.lock().unwrap();
+        let key = path.to_string_lossy().into_owned();
+
+        if failed.is_empty() {
+            failures.remove(&key);
+            return Ok(Some(remote_paths.join(", ")));
+        }
+
+        let names = failed
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        failures.insert(key, failed);
+
+        Err(UploadFailed { names }.into())
+    }
+
+    /// Logs the files that still failed to upload somewhere
+    pub fn report(&self) {
+        let failures = self.failures.lock().unwrap();
+        if failures.is_empty() {
+            return;
+        }
+
+        tracing::warn!("{} files failed to upload:", failures.len());
+        for (path, failed) in failures.iter() {
+            for (name, e) in failed {
+                tracing::warn!("  {path} -> {name}: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fs_destination(root: &std::path::Path) -> Destination {
+        let config = RemoteConfig::Fs {
+            root: root.to_string_lossy().into_owned(),
+        };
+
+        Destination::new("nas".to_string(), &config).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fs_upload_round_trip() {
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let path = local.path().join("video.mkv");
+        let data = b"not really a video".repeat(1000);
+        std::fs::write(&path, &data).unwrap();
+        let md5 = format!("{:x}", md5::compute(&data));
+
+        let dests = Destinations::new(vec![fs_destination(remote.path())], 1);
+        let remote_paths = dests.upload(&path, data.len() as u64, &md5).await.unwrap();
+
+        assert_eq!(remote_paths.as_deref(), Some("nas:video.mkv"));
+        assert_eq!(
+            std::fs::read(remote.path().join("video.mkv")).unwrap(),
+            data
+        );
+        assert!(dests.failures.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn verify_catches_size_mismatch() {
+        let remote = tempfile::tempdir().unwrap();
+        std::fs::write(remote.path().join("video.mkv"), b"truncated").unwrap();
+        let dest = fs_destination(remote.path());
+
+        let local = remote.path().join("video.mkv");
+
+        verify_upload(&dest, &local, "video.mkv", 9, "")
+            .await
+            .unwrap();
+        let err = verify_upload(&dest, &local, "video.mkv", 10, "")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "size mismatch, expected 10 bytes but got 9"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failed_uploads_are_not_retried_as_jobs() {
+        let (local, remote) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        // Fails every attempt without waiting on the backend's own retries
+        let path = local.path().join("missing.mkv");
+
+        let dests = Destinations::new(vec![fs_destination(remote.path())], 2);
+        let mut jobs = 0;
+        let result = crate::funcs::retry::with_retries(3, "", || {
+            jobs += 1;
+            let (dests, path) = (&dests, &path);
+            async move { dests.upload(path, 5, "").await.map(|_| ()) }
+        })
+        .await;
 
-    let urlencoded_filename = urlencoding::encode(&filename)
-        .replace("%27", "'")
-        .replace("%28", "(")
-        .replace("%29", ")");
+        assert!(result.unwrap_err().downcast_ref::<UploadFailed>().is_some());
+        assert_eq!(jobs, 1);
+        assert!(dests
+            .failures
+            .lock()
+            .unwrap()
+            .contains_key(&path.to_string_lossy().into_owned()));
+    }
 
-    tracing::trace!("Checking if {urlencoded_filename} exists...");
+    #[tokio::test]
+    async fn no_destinations_uploads_nothing() {
+        let dests = Destinations::default();
+        let uploaded = dests
+            .upload(std::path::Path::new("/nonexistent"), 0, "")
+            .await
+            .unwrap();
 
-    Ok(op.exists(&urlencoded_filename).await?)
+        assert_eq!(uploaded, None);
+    }
 }
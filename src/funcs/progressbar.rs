@@ -1,12 +1,25 @@
 use color_eyre::eyre::Error;
 
+tokio::task_local! {
+    /// Label of the job running on the current task, shown as prefix of its progress bars
+    pub static JOB_LABEL: String;
+}
+
+pub fn job_label() -> String {
+    JOB_LABEL
+        .try_with(|label| label.clone())
+        .unwrap_or_default()
+}
+
 pub fn get_spinner(
     spinner_fmt: &str,
     spinner_strset: &[&str],
 ) -> Result<indicatif::ProgressBar, Error> {
-    Ok(indicatif::ProgressBar::new_spinner().with_style(
-        indicatif::ProgressStyle::with_template(spinner_fmt)?.tick_strings(spinner_strset),
-    ))
+    Ok(indicatif::ProgressBar::new_spinner()
+        .with_style(
+            indicatif::ProgressStyle::with_template(spinner_fmt)?.tick_strings(spinner_strset),
+        )
+        .with_prefix(job_label()))
 }
 
 pub fn get_progbar(
@@ -15,7 +28,8 @@ pub fn get_progbar(
     bar_char: &str,
 ) -> Result<indicatif::ProgressBar, Error> {
     Ok(indicatif::ProgressBar::new(len)
-        .with_style(indicatif::ProgressStyle::with_template(bar_fmt)?.progress_chars(bar_char)))
+        .with_style(indicatif::ProgressStyle::with_template(bar_fmt)?.progress_chars(bar_char))
+        .with_prefix(job_label()))
 }
 
 pub fn update_pb_by_ffmpegprogress(
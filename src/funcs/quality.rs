@@ -0,0 +1,115 @@
+use color_eyre::eyre::Error;
+
+use crate::{
+    consts::QUALITY_CRF_STEP,
+    funcs::{
+        ffmpeg::{ffmpeg_quality, ffmpeg_transcode, TranscodeSource},
+        http::RequestSettings,
+    },
+    init::DownloadOpts,
+    parser::LineOptions,
+    structs::EncodeProfile,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum QualityMetric {
+    /// Needs an ffmpeg built with libvmaf, scored 0-100
+    Vmaf,
+    /// Fallback when libvmaf is missing, scored 0-1
+    Ssim,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityScore {
+    pub metric: QualityMetric,
+    pub score: f64,
+}
+
+/// Output scored below the profile's threshold
+#[derive(Debug, Clone, Copy)]
+pub struct LowQuality {
+    pub score: QualityScore,
+    pub min: f64,
+}
+
+impl std::fmt::Display for LowQuality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} score {:.3} is below {}",
+            self.score.metric, self.score.score, self.min
+        )
+    }
+}
+
+impl std::error::Error for LowQuality {}
+
+/// Encodes the source, then scores the output against its video if `--quality-check` is set.
+/// Outputs below the profile's threshold are encoded again at a lower CRF while
+/// `--quality-retries` allows it, otherwise they fail with [`LowQuality`].
+pub async fn transcode_checked(
+    args: &DownloadOpts,
+    src: &TranscodeSource,
+    dst: &std::path::Path,
+    profile: &EncodeProfile,
+    opts: &LineOptions,
+    settings: &RequestSettings,
+    progbar_msg: &str,
+) -> Result<Option<QualityScore>, Error> {
+    let mut profile = profile.clone();
+    let mut attempt = 0;
+
+    loop {
+        let encoded = ffmpeg_transcode(
+            src,
+            dst,
+            &profile,
+            opts,
+            settings,
+            args.reencode,
+            progbar_msg,
+        )
+        .await?;
+
+        if !args.quality_check {
+            return Ok(None);
+        }
+        if !encoded || profile.video_codec.is_none() {
+            tracing::debug!("Skipping quality check of {progbar_msg}, there's no encoded video");
+            return Ok(None);
+        }
+
+        let score = ffmpeg_quality(
+            dst,
+            &src.video,
+            opts,
+            settings,
+            &format!("Checking {progbar_msg}"),
+        )
+        .await?;
+        let min = profile.min_quality(score.metric);
+
+        if score.score >= min {
+            tracing::info!(
+                "{progbar_msg} passed the quality check, {} {:.3}",
+                score.metric,
+                score.score
+            );
+            return Ok(Some(score));
+        }
+
+        let low = LowQuality { score, min };
+        match profile.crf {
+            Some(crf) if attempt < args.quality_retries && crf > QUALITY_CRF_STEP => {
+                tracing::warn!(
+                    "{progbar_msg}: {low}, encoding again at CRF {}",
+                    crf - QUALITY_CRF_STEP
+                );
+                profile.crf = Some(crf - QUALITY_CRF_STEP);
+                attempt += 1;
+            }
+            _ => return Err(low.into()),
+        }
+    }
+}
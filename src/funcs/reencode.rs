@@ -0,0 +1,360 @@
+use crate::structs::EncodeProfile;
+
+/// When to encode with the profile instead of copying the source streams
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ReencodeMode {
+    /// Always encode with the profile
+    Always,
+    /// Copy the streams when the source already matches the profile
+    Auto,
+    /// Always copy the streams, only remuxing into the profile's container
+    Never,
+}
+
+/// Codec produced by an ffmpeg encoder, as ffprobe names it
+fn encoder_codec(encoder: &str) -> &str {
+    match encoder {
+        "libx265" | "hevc_nvenc" | "hevc_qsv" | "hevc_vaapi" | "hevc_videotoolbox" | "hevc_amf" => {
+            "hevc"
+        }
+        "libx264" | "h264_nvenc" | "h264_qsv" | "h264_vaapi" | "h264_videotoolbox" | "h264_amf" => {
+            "h264"
+        }
+        "libsvtav1" | "libaom-av1" | "librav1e" | "av1_nvenc" | "av1_qsv" | "av1_vaapi" => "av1",
+        "libvpx-vp9" | "vp9_qsv" | "vp9_vaapi" => "vp9",
+        "libvpx" => "vp8",
+        "libopus" => "opus",
+        "libvorbis" => "vorbis",
+        "libmp3lame" => "mp3",
+        "libfdk_aac" => "aac",
+        x => x,
+    }
+}
+
+/// Chroma subsampling and bit depth of planar YUV formats like `yuv420p10le`.
+/// Alpha is ignored since none of the sources carry any.
+fn yuv_format(pix_fmt: &str) -> Option<(&str, u32)> {
+    let rest = ["yuva", "yuvj", "yuv"]
+        .iter()
+        .find_map(|x| pix_fmt.strip_prefix(x))?;
+    let (subsampling, depth) = rest.split_once('p')?;
+    let depth = match depth.trim_end_matches("le").trim_end_matches("be") {
+        "" => 8,
+        x => x.parse().ok()?,
+    };
+
+    Some((subsampling, depth))
+}
+
+/// Whether a source in `source` can stand in for an encode to `target`. Bumping the bit depth of
+/// an already lossy source doesn't gain anything, so lower depths are fine.
+fn pix_fmt_compatible(source: &str, target: &str) -> bool {
+    match (yuv_format(source), yuv_format(target)) {
+        (Some((source_sub, source_depth)), Some((target_sub, target_depth))) => {
+            source_sub == target_sub && source_depth <= target_depth
+        }
+        _ => source == target,
+    }
+}
+
+/// Bits per second of an ffmpeg bitrate like `4M` or `2500k`
+fn parse_bitrate(bitrate: &str) -> Option<u64> {
+    let bitrate = bitrate.trim();
+    let (num, mult) = match bitrate.char_indices().last()? {
+        (i, 'k' | 'K') => (&bitrate[..i], 1e3),
+        (i, 'M') => (&bitrate[..i], 1e6),
+        (i, 'G') => (&bitrate[..i], 1e9),
+        _ => (bitrate, 1.0),
+    };
+
+    num.parse::<f64>()
+        .ok()
+        .filter(|x| *x >= 0.0)
+        .map(|x| (x * mult) as u64)
+}
+
+/// Reason the probed source doesn't match the profile, or None if it can be copied as-is
+fn mismatch(probe: &ffprobe::FfProbe, profile: &EncodeProfile) -> Option<String> {
+    let stream = |ty: &str| {
+        probe
+            .streams
+            .iter()
+            .find(|x| x.codec_type.as_deref() == Some(ty))
+    };
+
+    let Some(encoder) = &profile.video_codec else {
+        return Some("profile drops the video stream".to_string());
+    };
+    let Some(video) = stream("video") else {
+        return Some("source has no video stream".to_string());
+    };
+
+    let codec = video.codec_name.as_deref().unwrap_or_default();
+    if codec != encoder_codec(encoder) {
+        return Some(format!("codec {codec} isn't {}", encoder_codec(encoder)));
+    }
+
+    match (profile.max_resolution, video.width.zip(video.height)) {
+        (Some(max), Some((w, h))) if w.min(h) > max as i64 => {
+            return Some(format!("{w}x{h} is above {max}p"))
+        }
+        (Some(_), None) => return Some("source resolution is unknown".to_string()),
+        // The scale filter can't be checked against the source without knowing its limit
+        (None, _) if profile.scale.is_some() => {
+            return Some("profile scales without a max_resolution".to_string())
+        }
+        _ => {}
+    }
+
+    if let Some(target) = &profile.pix_fmt {
+        let pix_fmt = video.pix_fmt.as_deref().unwrap_or_default();
+        if !pix_fmt_compatible(pix_fmt, target) {
+            return Some(format!("pixel format {pix_fmt} doesn't fit {target}"));
+        }
+    }
+
+    // Containers like mkv only report the bitrate of the whole file
+    if let Some(target) = &profile.video_bitrate {
+        let source = video
+            .bit_rate
+            .as_deref()
+            .or(probe.format.bit_rate.as_deref())
+            .and_then(parse_bitrate);
+        match (source, parse_bitrate(target)) {
+            (Some(source), Some(max)) if source > max => {
+                return Some(format!("bitrate {source} is above {target}"))
+            }
+            (None, _) => return Some("source bitrate is unknown".to_string()),
+            (_, None) => return Some(format!("bitrate {target} can't be parsed")),
+            _ => {}
+        }
+    }
+
+    match (profile.audio_codec.as_deref(), stream("audio")) {
+        (None, Some(_)) => return Some("profile drops the audio stream".to_string()),
+        (Some(encoder), Some(audio)) if encoder != "copy" => {
+            let codec = audio.codec_name.as_deref().unwrap_or_default();
+            if codec != encoder_codec(encoder) {
+                return Some(format!(
+                    "audio codec {codec} isn't {}",
+                    encoder_codec(encoder)
+                ));
+            }
+        }
+        _ => {}
+    }
+
+    None
+}
+
+/// Decides whether to copy the source streams instead of encoding them, and logs why
+pub fn should_copy(
+    mode: ReencodeMode,
+    probe: Option<&ffprobe::FfProbe>,
+    profile: &EncodeProfile,
+    name: &str,
+) -> bool {
+    let reason = match (mode, probe) {
+        (ReencodeMode::Always, _) => Some("--reencode=always".to_string()),
+        (ReencodeMode::Never, _) => None,
+        (ReencodeMode::Auto, None) => Some("source couldn't be probed".to_string()),
+        (ReencodeMode::Auto, Some(probe)) => mismatch(probe, profile),
+    };
+
+    match reason {
+        Some(reason) => {
+            tracing::info!("Encoding {name}: {reason}");
+            false
+        }
+        None if mode == ReencodeMode::Never => {
+            tracing::info!("Copying streams of {name}: --reencode=never");
+            true
+        }
+        None => {
+            tracing::info!("Copying streams of {name}: source already matches the profile");
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(codec_type: &str, codec: &str) -> ffprobe::Stream {
+        ffprobe::Stream {
+            codec_type: Some(codec_type.to_string()),
+            codec_name: Some(codec.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// 1920x1080 yuv420p H.264 at 3 Mbit/s with AAC audio
+    fn probe() -> ffprobe::FfProbe {
+        ffprobe::FfProbe {
+            streams: vec![
+                ffprobe::Stream {
+                    width: Some(1920),
+                    height: Some(1080),
+                    pix_fmt: Some("yuv420p".to_string()),
+                    bit_rate: Some("3000000".to_string()),
+                    ..stream("video", "h264")
+                },
+                stream("audio", "aac"),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn pix_fmts() {
+        let cases = [
+            ("yuv420p", "yuv420p", true),
+            ("yuv420p", "yuv420p10le", true),
+            ("yuvj420p", "yuv420p", true),
+            ("yuv420p", "yuva420p10le", true),
+            ("yuv420p10le", "yuv420p", false),
+            ("yuv444p", "yuv420p", false),
+            ("yuv422p10le", "yuv420p10le", false),
+            ("nv12", "nv12", true),
+            ("nv12", "yuv420p", false),
+            ("", "yuv420p", false),
+        ];
+
+        for (source, target, expected) in cases {
+            assert_eq!(
+                pix_fmt_compatible(source, target),
+                expected,
+                "{source} -> {target}"
+            );
+        }
+    }
+
+    #[test]
+    fn bitrates() {
+        let cases = [
+            ("4M", Some(4_000_000)),
+            ("2500k", Some(2_500_000)),
+            ("2.5M", Some(2_500_000)),
+            ("192K", Some(192_000)),
+            ("3000000", Some(3_000_000)),
+            ("fast", None),
+            ("", None),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(parse_bitrate(value), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn mismatches() {
+        let h264 = EncodeProfile::h264();
+        let with = |f: fn(&mut EncodeProfile)| {
+            let mut profile = h264.clone();
+            f(&mut profile);
+            profile
+        };
+        let mut probe_4k = probe();
+        probe_4k.streams[0].width = Some(3840);
+        probe_4k.streams[0].height = Some(2160);
+        let mut probe_10bit = probe();
+        probe_10bit.streams[0].pix_fmt = Some("yuv420p10le".to_string());
+        let mut probe_mkv = probe();
+        probe_mkv.streams[0].bit_rate = None;
+        probe_mkv.format.bit_rate = Some("2000000".to_string());
+        let mut probe_no_rate = probe();
+        probe_no_rate.streams[0].bit_rate = None;
+        let audio_only = ffprobe::FfProbe {
+            streams: vec![stream("audio", "aac")],
+            ..Default::default()
+        };
+
+        let cases = [
+            (probe(), h264.clone(), None),
+            (
+                probe(),
+                EncodeProfile::hevc_1080p(),
+                Some("codec h264 isn't hevc"),
+            ),
+            (probe_4k, h264.clone(), Some("3840x2160 is above 1080p")),
+            (
+                probe_10bit,
+                h264.clone(),
+                Some("pixel format yuv420p10le doesn't fit yuv420p"),
+            ),
+            (
+                probe(),
+                with(|x| x.video_bitrate = Some("2M".to_string())),
+                Some("bitrate 3000000 is above 2M"),
+            ),
+            (
+                probe(),
+                with(|x| x.video_bitrate = Some("4M".to_string())),
+                None,
+            ),
+            (
+                probe_mkv,
+                with(|x| x.video_bitrate = Some("2500k".to_string())),
+                None,
+            ),
+            (
+                probe_no_rate,
+                with(|x| x.video_bitrate = Some("4M".to_string())),
+                Some("source bitrate is unknown"),
+            ),
+            (
+                probe(),
+                with(|x| x.audio_codec = Some("libopus".to_string())),
+                Some("audio codec aac isn't opus"),
+            ),
+            (
+                probe(),
+                with(|x| x.audio_codec = None),
+                Some("profile drops the audio stream"),
+            ),
+            (
+                probe(),
+                with(|x| {
+                    x.max_resolution = None;
+                }),
+                Some("profile scales without a max_resolution"),
+            ),
+            (
+                probe(),
+                EncodeProfile::audio_only(),
+                Some("profile drops the video stream"),
+            ),
+            (audio_only, h264.clone(), Some("source has no video stream")),
+        ];
+
+        for (i, (probe, profile, expected)) in cases.into_iter().enumerate() {
+            assert_eq!(mismatch(&probe, &profile).as_deref(), expected, "case {i}");
+        }
+    }
+
+    #[test]
+    fn copies_only_when_allowed() {
+        let h264 = EncodeProfile::h264();
+        let hevc = EncodeProfile::hevc_1080p();
+        let probe = probe();
+
+        let cases = [
+            (ReencodeMode::Auto, Some(&probe), &h264, true),
+            (ReencodeMode::Auto, Some(&probe), &hevc, false),
+            (ReencodeMode::Auto, None, &h264, false),
+            (ReencodeMode::Always, Some(&probe), &h264, false),
+            (ReencodeMode::Never, Some(&probe), &hevc, true),
+            (ReencodeMode::Never, None, &hevc, true),
+        ];
+
+        for (mode, probe, profile, expected) in cases {
+            assert_eq!(
+                should_copy(mode, probe, profile, "video"),
+                expected,
+                "{mode:?} {:?}",
+                profile.video_codec
+            );
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use color_eyre::eyre::Error;
+
+use super::{opendal::UploadFailed, ytdlp::YtDlpError};
+
+/// Runs the job until it succeeds or runs out of attempts. yt-dlp failures are retried as their
+/// kind calls for, failed uploads not at all since they were retried already, and anything else
+/// up to `retry` times in a row.
+pub async fn with_retries<T, F, Fut>(retry: usize, what: &str, mut job: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, Error>>,
+{
+    let mut attempt = 0;
+
+    loop {
+        let e = match job().await {
+            Ok(x) => return Ok(x),
+            Err(e) => e,
+        };
+
+        let kind = e.downcast_ref::<YtDlpError>().map(|x| x.kind);
+        tracing::warn!("Attempt #{attempt}{what} failed. Reason: {e}");
+        if e.downcast_ref::<UploadFailed>().is_some() {
+            return Err(e);
+        }
+
+        attempt += 1;
+        if attempt >= kind.map_or(retry, |x| x.attempts(retry)) {
+            return Err(e);
+        }
+
+        if let Some(backoff) = kind.map(|x| x.backoff(attempt - 1)) {
+            if !backoff.is_zero() {
+                tracing::info!("Waiting {}s before retrying{what}", backoff.as_secs());
+                tokio::time::sleep(backoff).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::funcs::ytdlp::YtDlpErrorKind;
+
+    fn ytdlp_error(kind: YtDlpErrorKind) -> Error {
+        YtDlpError {
+            kind,
+            message: kind.to_string(),
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn retries_by_kind() {
+        let attempts = AtomicUsize::new(0);
+        let result = with_retries(3, "", || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(ytdlp_error(YtDlpErrorKind::Other)),
+                n => Ok(n),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 1);
+
+        let attempts = AtomicUsize::new(0);
+        let result = with_retries(3, "", || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(ytdlp_error(YtDlpErrorKind::Unavailable))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
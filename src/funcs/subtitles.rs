@@ -0,0 +1,184 @@
+use std::{collections::BTreeMap, path::Path};
+
+use color_eyre::eyre::{ContextCompat, Error};
+use youtube_dl::Subtitle;
+
+use crate::structs::SubtitleStream;
+
+/// Formats ffmpeg can mux as subtitle streams, most preferred first
+const SUBTITLE_EXTS: [&str; 3] = ["srt", "vtt", "ass"];
+
+/// A subtitle track downloaded next to the output, deleted once dropped
+#[derive(Debug)]
+pub struct SubtitleTrack {
+    /// Language key as yt-dlp gives it, e.g. `en-US`
+    pub lang: String,
+    pub automatic: bool,
+    pub path: tempfile::TempPath,
+}
+
+impl SubtitleTrack {
+    /// ISO 639 code for the stream's language tag, if the key names a known language
+    pub fn iso639(&self) -> Option<&'static str> {
+        let code = self.lang.split(['-', '_']).next()?;
+        let lang = match code.len() {
+            2 => isolang::Language::from_639_1(&code.to_ascii_lowercase()),
+            3 => isolang::Language::from_639_3(&code.to_ascii_lowercase()),
+            _ => None,
+        }?;
+
+        Some(lang.to_639_3())
+    }
+
+    /// The track as a stream to mux, titled with its key so regional variants stay apart
+    pub fn stream(&self) -> SubtitleStream {
+        SubtitleStream {
+            path: self.path.to_path_buf(),
+            language: self.iso639(),
+            title: match self.automatic {
+                true => format!("{} (auto)", self.lang),
+                false => self.lang.clone(),
+            },
+            start: 0.0,
+            duration: None,
+        }
+    }
+}
+
+/// Whether a yt-dlp language key is what was asked for, e.g. `en` matches `en` and `en-US`
+fn lang_matches(wanted: &str, key: &str) -> bool {
+    key.eq_ignore_ascii_case(wanted)
+        || key
+            .get(..wanted.len() + 1)
+            .is_some_and(|x| x.eq_ignore_ascii_case(&format!("{wanted}-")))
+}
+
+/// The most preferred format of a track
+fn pick_format(formats: &[Subtitle]) -> Option<&Subtitle> {
+    SUBTITLE_EXTS.iter().find_map(|ext| {
+        formats
+            .iter()
+            .find(|x| x.ext.as_deref() == Some(ext) && (x.url.is_some() || x.data.is_some()))
+    })
+}
+
+/// Picks the tracks for the wanted languages, as `(lang, automatic, format)`. `all` takes every
+/// manual track. Automatic captions are only used for languages without a manual track, and
+/// only the closest one per language, as YouTube offers a machine translation for almost any.
+pub fn select_subtitles(
+    video: &youtube_dl::SingleVideo,
+    langs: &[String],
+    auto_subs: bool,
+) -> Vec<(String, bool, Subtitle)> {
+    let manual = video
+        .subtitles
+        .iter()
+        .flatten()
+        .filter_map(|(k, v)| Some((k.as_str(), pick_format(v.as_deref()?)?)))
+        .collect::<BTreeMap<_, _>>();
+    let automatic = video
+        .automatic_captions
+        .iter()
+        .flatten()
+        .filter_map(|(k, v)| Some((k.as_str(), pick_format(v)?)))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut selected: Vec<(&str, bool, &Subtitle)> = vec![];
+    for wanted in langs {
+        let wanted = wanted.trim();
+        let found = manual
+            .iter()
+            .filter(|(k, _)| wanted == "all" || lang_matches(wanted, k))
+            .map(|(k, v)| (*k, false, *v))
+            .collect::<Vec<_>>();
+
+        if found.is_empty() && auto_subs && wanted != "all" {
+            let closest = automatic
+                .get_key_value(wanted)
+                .or_else(|| automatic.iter().find(|(k, _)| lang_matches(wanted, k)));
+            match closest {
+                Some((k, v)) => selected.push((k, true, v)),
+                None => tracing::warn!("No subtitles or automatic captions for {wanted}"),
+            }
+        } else if found.is_empty() {
+            tracing::warn!("No subtitles for {wanted}");
+        }
+
+        for track in found {
+            if !selected.iter().any(|(k, auto, _)| *k == track.0 && !auto) {
+                selected.push(track);
+            }
+        }
+    }
+
+    selected
+        .into_iter()
+        .map(|(k, auto, v)| (k.to_string(), auto, v.clone()))
+        .collect()
+}
+
+/// Downloads the selected subtitle tracks next to the output as `{stem}.{lang}.{ext}`.
+/// Tracks failing to download are skipped with a warning.
+pub async fn download_subtitles(
+    client: &reqwest::Client,
+    selected: Vec<(String, bool, Subtitle)>,
+    output_path: &Path,
+) -> Vec<SubtitleTrack> {
+    let stem = output_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let mut tracks = vec![];
+
+    for (lang, automatic, subtitle) in selected {
+        let path = tempfile::TempPath::from_path(output_path.with_file_name(format!(
+            "{stem}.{lang}.{}",
+            subtitle.ext.as_deref().unwrap_or_default()
+        )));
+
+        let res = async {
+            let data = match &subtitle.data {
+                Some(data) => data.clone().into_bytes(),
+                None => {
+                    let url = subtitle.url.as_ref().wrap_err("Subtitle has no url")?;
+                    let res = client.get(url).send().await?.error_for_status()?;
+                    res.bytes().await?.to_vec()
+                }
+            };
+            tokio::fs::write(&path, data).await?;
+
+            color_eyre::eyre::Ok(())
+        }
+        .await;
+
+        match res {
+            Ok(()) => tracks.push(SubtitleTrack {
+                lang,
+                automatic,
+                path,
+            }),
+            Err(e) => tracing::warn!("Failed to download {lang} subtitles: {e}"),
+        }
+    }
+
+    tracks
+}
+
+/// Encoder for subtitle streams in the output's container, None if it can't hold any
+pub fn subtitle_codec(path: &Path) -> Option<&'static str> {
+    match path
+        .extension()
+        .map(|x| x.to_string_lossy().to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("mkv" | "mka") => Some("copy"),
+        Some("mp4" | "m4v" | "mov") => Some("mov_text"),
+        Some("webm") => Some("webvtt"),
+        _ => None,
+    }
+}
+
+/// Keeps the tracks as files next to the output, returning their paths
+pub fn persist_sidecars(tracks: Vec<SubtitleTrack>) -> Result<Vec<std::path::PathBuf>, Error> {
+    tracks.into_iter().map(|x| Ok(x.path.keep()?)).collect()
+}
@@ -0,0 +1,384 @@
+use color_eyre::eyre::{ContextCompat, Error};
+use youtube_dl::Format;
+
+use crate::consts::{NETWORK_BACKOFF_SECS, RATE_LIMIT_BACKOFF_SECS};
+
+/// Formats yt-dlp picked with the format selector
+#[derive(Debug, Clone)]
+pub struct SelectedFormats {
+    /// e.g. `137+140`
+    pub format_id: String,
+    pub video: Format,
+    /// Separate audio format, when the selector combines two
+    pub audio: Option<Format>,
+}
+
+fn has_codec(codec: &Option<String>) -> bool {
+    codec.as_deref().is_some_and(|x| x != "none")
+}
+
+impl SelectedFormats {
+    /// Reads the selection from yt-dlp's info. Combined formats are listed in
+    /// `requested_formats`, a single one is described by the info itself.
+    pub fn from_info(
+        info: &serde_json::Value,
+        video: &youtube_dl::SingleVideo,
+    ) -> Result<Self, Error> {
+        let requested = info
+            .get("requested_formats")
+            .map(|x| serde_json::from_value::<Vec<Format>>(x.clone()))
+            .transpose()?
+            .unwrap_or_default();
+
+        let (selected_video, audio) = match requested.as_slice() {
+            [] => (
+                Format {
+                    format_id: video.format_id.clone(),
+                    url: video.url.clone(),
+                    ext: video.ext.clone(),
+                    resolution: video.resolution.clone(),
+                    vcodec: video.vcodec.clone(),
+                    acodec: video.acodec.clone(),
+                    ..Default::default()
+                },
+                None,
+            ),
+            [single] => (single.clone(), None),
+            [first, .., last] => match (has_codec(&first.vcodec), has_codec(&last.vcodec)) {
+                (false, true) => (last.clone(), Some(first.clone())),
+                _ => (first.clone(), Some(last.clone())),
+            },
+        };
+
+        selected_video
+            .url
+            .as_ref()
+            .wrap_err("yt-dlp selected a format without url")?;
+        if let Some(audio) = &audio {
+            audio
+                .url
+                .as_ref()
+                .wrap_err("yt-dlp selected an audio format without url")?;
+        }
+
+        let format_id = video
+            .format_id
+            .clone()
+            .or_else(|| selected_video.format_id.clone())
+            .unwrap_or_default();
+
+        Ok(Self {
+            format_id,
+            video: selected_video,
+            audio,
+        })
+    }
+
+    pub fn video_url(&self) -> &str {
+        self.video.url.as_deref().unwrap_or_default()
+    }
+
+    pub fn audio_url(&self) -> Option<&str> {
+        self.audio.as_ref().and_then(|x| x.url.as_deref())
+    }
+}
+
+/// Entry of a playlist, channel or tab as listed with `--flat-playlist`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaylistItem {
+    pub id: String,
+    pub url: String,
+    pub title: Option<String>,
+    /// `YYYYMMDD`, if the listing includes it
+    pub upload_date: Option<String>,
+    /// Position in the playlist as yt-dlp reports it, if it does
+    pub playlist_index: Option<usize>,
+}
+
+pub fn is_playlist(info: &serde_json::Value) -> bool {
+    info["_type"] == "playlist"
+}
+
+/// Videos of a flat playlist, in order. Entries handled by the playlist's own extractor are
+/// tabs or nested playlists, like the Videos and Shorts tabs of a channel. Their urls are
+/// returned separately, to be listed in turn.
+pub fn playlist_entries(info: &serde_json::Value) -> (Vec<PlaylistItem>, Vec<String>) {
+    let extractor = info["extractor_key"].as_str();
+    let mut items = vec![];
+    let mut nested = vec![];
+    let mut nested_lists = false;
+
+    for entry in info["entries"].as_array().into_iter().flatten() {
+        if is_playlist(entry) {
+            let (entry_items, entry_nested) = playlist_entries(entry);
+            items.extend(entry_items);
+            nested.extend(entry_nested);
+            nested_lists = true;
+            continue;
+        }
+
+        let Some(url) = entry["webpage_url"].as_str().or(entry["url"].as_str()) else {
+            continue;
+        };
+        if extractor.is_some() && entry["ie_key"].as_str() == extractor {
+            nested.push(url.to_string());
+            continue;
+        }
+
+        items.push(PlaylistItem {
+            id: entry["id"].as_str().unwrap_or(url).to_string(),
+            url: url.to_string(),
+            title: entry["title"].as_str().map(str::to_string),
+            upload_date: entry["upload_date"].as_str().map(str::to_string),
+            playlist_index: entry["playlist_index"].as_u64().map(|x| x as usize),
+        });
+    }
+
+    // Every nested list counts from 1 again
+    if nested_lists || !nested.is_empty() {
+        clear_indices(&mut items);
+    }
+
+    (items, nested)
+}
+
+/// Drops the playlist indices yt-dlp reported, for items collected from several lists
+pub fn clear_indices(items: &mut [PlaylistItem]) {
+    for item in items {
+        item.playlist_index = None;
+    }
+}
+
+/// What kind of problem made yt-dlp fail, deciding how it's retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, strum::Display)]
+pub enum YtDlpErrorKind {
+    #[strum(serialize = "unavailable or private")]
+    Unavailable,
+    #[strum(serialize = "geo-blocked")]
+    GeoBlocked,
+    #[strum(serialize = "needs cookies or login")]
+    LoginRequired,
+    #[strum(serialize = "rate limited")]
+    RateLimited,
+    #[strum(serialize = "network error")]
+    Network,
+    #[strum(serialize = "other")]
+    Other,
+}
+
+impl YtDlpErrorKind {
+    /// Sorts yt-dlp's error message into a kind, by the phrases its extractors use
+    pub fn classify(message: &str) -> Self {
+        let message = message.to_ascii_lowercase();
+        let has = |phrases: &[&str]| phrases.iter().any(|x| message.contains(x));
+
+        if has(&[
+            "available in your country",
+            "geo restrict",
+            "geo-restrict",
+            "blocked it in your country",
+        ]) {
+            Self::GeoBlocked
+        } else if has(&[
+            "sign in to confirm",
+            "login required",
+            "use --cookies",
+            "--cookies-from-browser",
+            "members-only",
+            "join this channel",
+            "age-restricted",
+            "requires authentication",
+            "http error 401",
+        ]) {
+            Self::LoginRequired
+        } else if has(&[
+            "private video",
+            "video unavailable",
+            "video is private",
+            "has been removed",
+            "no longer available",
+            "account has been terminated",
+            "does not exist",
+            "http error 404",
+            "http error 410",
+        ]) {
+            Self::Unavailable
+        } else if has(&[
+            "http error 429",
+            "too many requests",
+            "rate-limit",
+            "rate limit",
+        ]) {
+            Self::RateLimited
+        } else if has(&[
+            "timed out",
+            "connection reset",
+            "connection refused",
+            "connection aborted",
+            "remote end closed",
+            "temporary failure in name resolution",
+            "name or service not known",
+            "network is unreachable",
+            "incompleteread",
+            "unable to download webpage",
+            "http error 500",
+            "http error 502",
+            "http error 503",
+            "http error 504",
+        ]) {
+            Self::Network
+        } else {
+            Self::Other
+        }
+    }
+
+    /// Attempts made in total, out of the --retry amount. Retrying doesn't fix a video that's
+    /// gone, blocked or behind a login.
+    pub fn attempts(&self, retry: usize) -> usize {
+        match self {
+            Self::Unavailable | Self::GeoBlocked | Self::LoginRequired => 1,
+            Self::RateLimited | Self::Network | Self::Other => retry,
+        }
+    }
+
+    /// Wait before the attempt following the failed one
+    pub fn backoff(&self, attempt: usize) -> std::time::Duration {
+        let base = match self {
+            Self::RateLimited => RATE_LIMIT_BACKOFF_SECS,
+            Self::Network => NETWORK_BACKOFF_SECS,
+            _ => 0,
+        };
+
+        std::time::Duration::from_secs(base << attempt.min(4))
+    }
+}
+
+/// yt-dlp failing to extract a url
+#[derive(Debug, Clone)]
+pub struct YtDlpError {
+    pub kind: YtDlpErrorKind,
+    pub message: String,
+}
+
+impl std::fmt::Display for YtDlpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "yt-dlp failed ({}): {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for YtDlpError {}
+
+impl From<youtube_dl::Error> for YtDlpError {
+    fn from(e: youtube_dl::Error) -> Self {
+        let message = match &e {
+            // The last error line says what went wrong, warnings before it rarely matter
+            youtube_dl::Error::ExitCode { stderr, .. } => stderr
+                .lines()
+                .rev()
+                .find(|x| x.starts_with("ERROR:"))
+                .or(stderr.lines().last())
+                .unwrap_or_default()
+                .trim_start_matches("ERROR:")
+                .trim()
+                .to_string(),
+            e => e.to_string(),
+        };
+        let kind = match &e {
+            youtube_dl::Error::ProcessTimeout => YtDlpErrorKind::Network,
+            _ => YtDlpErrorKind::classify(&message),
+        };
+
+        Self { kind, message }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_errors() {
+        use YtDlpErrorKind::*;
+
+        let cases = [
+            (
+                "[youtube] dQw4w9WgXcQ: Video unavailable. The uploader has not made this video available in your country",
+                GeoBlocked,
+            ),
+            (
+                "[BiliBili] BV1xx411c7mD: This video may be deleted or geo-restricted. You might want to try a VPN or a proxy server (with --proxy)",
+                GeoBlocked,
+            ),
+            (
+                "[youtube] dQw4w9WgXcQ: Sign in to confirm your age. This video may be inappropriate for some users. Use --cookies-from-browser or --cookies for the authentication.",
+                LoginRequired,
+            ),
+            (
+                "[youtube] dQw4w9WgXcQ: Sign in to confirm you’re not a bot. Use --cookies-from-browser or --cookies for the authentication.",
+                LoginRequired,
+            ),
+            (
+                "[youtube] dQw4w9WgXcQ: Join this channel to get access to members-only content like this video, and other exclusive perks.",
+                LoginRequired,
+            ),
+            (
+                "[patreon] 12345: Unable to download JSON metadata: HTTP Error 401: Unauthorized",
+                LoginRequired,
+            ),
+            (
+                "[youtube] dQw4w9WgXcQ: Private video. Sign in if you've been granted access to this video",
+                Unavailable,
+            ),
+            (
+                "[youtube] dQw4w9WgXcQ: Video unavailable. This video has been removed by the uploader",
+                Unavailable,
+            ),
+            (
+                "[youtube] dQw4w9WgXcQ: Video unavailable. This video is no longer available because the YouTube account associated with this video has been terminated.",
+                Unavailable,
+            ),
+            (
+                "[generic] video: Unable to download webpage: HTTP Error 404: Not Found (caused by <HTTPError 404: Not Found>)",
+                Unavailable,
+            ),
+            (
+                "[youtube] dQw4w9WgXcQ: Unable to download webpage: HTTP Error 429: Too Many Requests (caused by <HTTPError 429: Too Many Requests>)",
+                RateLimited,
+            ),
+            (
+                "[generic] Unable to download webpage: <urlopen error [Errno -2] Name or service not known> (caused by TransportError('<urlopen error [Errno -2] Name or service not known>'))",
+                Network,
+            ),
+            ("[download] Got error: The read operation timed out", Network),
+            (
+                "[vimeo] 76979871: Unable to download JSON metadata: HTTP Error 503: Service Unavailable",
+                Network,
+            ),
+            ("Unsupported URL: https://example.com/", Other),
+            (
+                "[youtube] dQw4w9WgXcQ: Requested format is not available. Use --list-formats for a list of available formats",
+                Other,
+            ),
+        ];
+
+        for (message, kind) in cases {
+            assert_eq!(YtDlpErrorKind::classify(message), kind, "{message}");
+        }
+    }
+
+    #[test]
+    fn takes_last_error_line() {
+        let e = youtube_dl::Error::ExitCode {
+            code: 1,
+            stderr: "WARNING: [youtube] Falling back to generic n function search\n\
+                ERROR: [youtube] dQw4w9WgXcQ: Private video. Sign in if you've been granted access to this video\n"
+                .to_string(),
+        };
+        let e = YtDlpError::from(e);
+
+        assert_eq!(e.kind, YtDlpErrorKind::Unavailable);
+        assert!(e
+            .message
+            .starts_with("[youtube] dQw4w9WgXcQ: Private video"));
+    }
+}
@@ -4,6 +4,7 @@ use clap::{Parser, Subcommand};
 use clap_stdin::MaybeStdin;
 use color_eyre::eyre::{bail, Context, Error};
 
+pub mod cookies;
 pub mod db;
 mod progressbar_logwriter;
 
@@ -27,7 +28,13 @@ pub struct AppArgs {
 
 #[derive(Subcommand, Clone)]
 pub enum Subcommands {
-    Download(DownloadOpts),
+    Download {
+        #[command(flatten)]
+        opts: Box<DownloadOpts>,
+
+        #[command(flatten)]
+        input: PlaylistInput,
+    },
     Authenticate {
         #[command(subcommand)]
         service: AuthorizeCommands,
@@ -36,13 +43,35 @@ pub enum Subcommands {
         #[command(subcommand)]
         command: db::DbCommands,
     },
+    Cookies {
+        #[command(subcommand)]
+        command: cookies::CookieCommands,
+    },
 }
 
 #[derive(Subcommand, Clone)]
 pub enum AuthorizeCommands {
     GoogleDrive {
-        client_id: String,
-        client_secret: String,
+        /// OAuth client id, not needed with --service-account
+        #[arg(required_unless_present = "service_account")]
+        client_id: Option<String>,
+
+        /// OAuth client secret, not needed with --service-account
+        #[arg(required_unless_present = "service_account")]
+        client_secret: Option<String>,
+
+        /// Sign in with a browser on any device and paste the URL it got redirected to,
+        /// instead of receiving the redirect on this machine. Works on headless machines.
+        #[arg(long, action, conflicts_with = "service_account", verbatim_doc_comment)]
+        headless: bool,
+
+        /// JSON key of a service account to authenticate as, instead of signing in
+        #[arg(long, conflicts_with_all = ["client_id", "client_secret"])]
+        service_account: Option<PathBuf>,
+
+        /// Email of the user the service account acts as, with domain-wide delegation
+        #[arg(long, requires = "service_account", conflicts_with_all = ["client_id", "client_secret"])]
+        subject: Option<String>,
     },
     Dropbox {
         client_id: String,
@@ -51,8 +80,9 @@ pub enum AuthorizeCommands {
 
 #[derive(Debug, Clone, clap::Args)]
 pub struct DownloadOpts {
-    /// Use (or create) cookie file on custom path. Defaults to projects data folder. Varies by OS.
-    #[arg(long)]
+    /// Use cookie file on custom path for yt-dlp sources.
+    /// Defaults to the jar imported with `cookies import` for the domain, then cookie.txt in projects data folder.
+    #[arg(long, verbatim_doc_comment)]
     pub cookies: Option<std::path::PathBuf>,
 
     /// yt-dlp path. Will use the environment PATH if not provided.
@@ -64,30 +94,186 @@ pub struct DownloadOpts {
     #[arg(long, short = 'd', verbatim_doc_comment)]
     pub target_dir: Option<std::path::PathBuf>,
 
+    /// Name of a remote in the remote config to upload finished files to.
+    /// Can be repeated or comma separated to upload to several remotes.
+    /// Supported types: b2, s3, fs, webdav, sftp
+    /// Will delete downloaded video once every upload succeeded, unless specified with --skip-video-delete
+    #[arg(long, verbatim_doc_comment, value_delimiter = ',')]
+    pub upload: Vec<String>,
+
+    /// Use custom remote config file. Defaults to remotes.json in projects data folder.
+    #[arg(long)]
+    pub remote_config: Option<PathBuf>,
+
     /// Format: B2;Key ID;App Key;Bucket;BucketID;Root path
-    /// Upload downloaded path to OpenDAL supported service instead
-    /// Will delete downloaded video unless specified with --skip-video-delete
+    /// Same as --upload with a B2 remote, kept for compatibility
     #[arg(long, verbatim_doc_comment)]
     pub b2args: Option<String>,
 
+    /// Extra HTTP header sent to every source, e.g. "Authorization: Bearer token".
+    /// Can be repeated. Per-host headers go in the header config, per-entry ones in header="..."
+    #[arg(short = 'H', long = "header", value_name = "HEADER", value_parser = crate::parser::parse_header, verbatim_doc_comment)]
+    pub headers: Vec<(String, String)>,
+
+    /// Proxy used for every HTTP request, e.g. http://proxy:3128 or socks5://127.0.0.1:1080
+    #[arg(long)]
+    pub proxy: Option<String>,
+
+    /// Use custom header config file. Defaults to headers.json in projects data folder.
+    /// Maps host patterns like "cdn.example.com" or "*.example.com" to headers.
+    #[arg(long, verbatim_doc_comment)]
+    pub header_config: Option<PathBuf>,
+
     /// Skip video deletion on upload stage
     #[arg(long, action)]
     pub skip_video_delete: bool,
 
+    /// Process entries again even if the database says they're already done
+    #[arg(long, action)]
+    pub force: bool,
+
     /// Removes index number from file name
     #[arg(long, action)]
     pub no_index_filename: bool,
 
+    /// Encoding profile used for transcoding.
+    /// Built-in profiles: hevc-1080p, av1, h264, audio-only
+    #[arg(long, default_value = "hevc-1080p", verbatim_doc_comment)]
+    pub profile: String,
+
+    /// Use custom encoding profile config file. Defaults to profiles.json in projects data folder.
+    /// Profiles defined there override built-in profiles with the same name.
+    #[arg(long, verbatim_doc_comment)]
+    pub profile_config: Option<PathBuf>,
+
+    /// When to encode with the profile. auto copies the streams if the source already has the
+    /// profile's codecs, resolution and pixel format, never always copies them.
+    #[arg(long, value_enum, default_value_t = crate::funcs::reencode::ReencodeMode::Auto, verbatim_doc_comment)]
+    pub reencode: crate::funcs::reencode::ReencodeMode,
+
+    /// Score every encoded file against its source with VMAF, or SSIM if ffmpeg lacks libvmaf.
+    /// Files below the profile's min_vmaf/min_ssim fail, unless --quality-retries is set.
+    #[arg(long, action, verbatim_doc_comment)]
+    pub quality_check: bool,
+
+    /// Encode files failing the quality check again, up to this many times,
+    /// each time at a CRF lowered by 4. Needs a profile with a crf.
+    #[arg(
+        long,
+        default_value_t = 0,
+        requires = "quality_check",
+        verbatim_doc_comment
+    )]
+    pub quality_retries: u32,
+
+    /// yt-dlp format selector, e.g. bv*+ba/b or b[height<=720].
+    /// Separate video and audio formats are combined by ffmpeg.
+    #[arg(short = 'f', long, default_value = "bv*+ba/b", verbatim_doc_comment)]
+    pub format: String,
+
+    /// Prefer yt-dlp formats up to this height, e.g. 1080
+    #[arg(long)]
+    pub max_height: Option<u32>,
+
+    /// Prefer yt-dlp formats with this video codec, e.g. h264, vp9 or av01
+    #[arg(long)]
+    pub prefer_vcodec: Option<String>,
+
+    /// yt-dlp format sort order, e.g. fps,size. Applied after --max-height and --prefer-vcodec.
+    #[arg(long, verbatim_doc_comment)]
+    pub format_sort: Option<String>,
+
+    /// Entries of yt-dlp playlists and channels to process, e.g. 1-10,15.
+    /// Same syntax as yt-dlp's --playlist-items.
+    #[arg(long, verbatim_doc_comment)]
+    pub playlist_items: Option<String>,
+
+    /// Only process playlist entries uploaded on or after this date (YYYY-MM-DD)
+    #[arg(long)]
+    pub date_after: Option<chrono::NaiveDate>,
+
+    /// Only process playlist entries uploaded on or before this date (YYYY-MM-DD)
+    #[arg(long)]
+    pub date_before: Option<chrono::NaiveDate>,
+
+    /// Process at most this many entries of each playlist per run,
+    /// leaving out the ones processed or filtered out before.
+    #[arg(long, verbatim_doc_comment)]
+    pub playlist_limit: Option<usize>,
+
+    /// Subtitle languages to fetch for yt-dlp entries, e.g. en,de or all.
+    /// Muxed into mkv, mp4 and webm outputs, saved as files next to other containers.
+    #[arg(long, value_delimiter = ',', verbatim_doc_comment)]
+    pub subs: Vec<String>,
+
+    /// Use automatic captions for languages in --subs without subtitles
+    #[arg(long, action, requires = "subs")]
+    pub auto_subs: bool,
+
+    /// Only take files of Google Drive folders whose path inside the folder matches the glob,
+    /// e.g. "Season 1/**" or "*.mkv". Globs without a slash match the file name.
+    /// Can be repeated or comma separated.
+    #[arg(long, value_delimiter = ',', verbatim_doc_comment)]
+    pub drive_include: Vec<String>,
+
+    /// Leave out files and folders of Google Drive folders whose path matches the glob.
+    /// Same syntax as --drive-include, and wins over it.
+    #[arg(long, value_delimiter = ',', verbatim_doc_comment)]
+    pub drive_exclude: Vec<String>,
+
+    /// MIME types of files taken from Google Drive folders, e.g. video/mp4 or audio/*.
+    /// video/* also takes files with a video extension, whatever their type.
+    /// * takes every file.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "video/*",
+        verbatim_doc_comment
+    )]
+    pub drive_mime: Vec<String>,
+
+    /// Leave out files of Google Drive folders smaller than this, e.g. 50M
+    #[arg(long, value_parser = crate::parser::parse_size)]
+    pub drive_min_size: Option<u64>,
+
+    /// Leave out files of Google Drive folders bigger than this, e.g. 4G
+    #[arg(long, value_parser = crate::parser::parse_size)]
+    pub drive_max_size: Option<u64>,
+
+    /// Amount of files of a Google Drive folder processed concurrently
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub drive_jobs: u32,
+
+    /// Amount of Google Drive API requests made concurrently while walking folders
+    #[arg(long, default_value_t = crate::consts::DRIVE_REQUESTS, value_parser = clap::value_parser!(u32).range(1..))]
+    pub drive_requests: u32,
+
+    /// Amount of playlist entries processed concurrently
+    #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub jobs: u32,
+
+    /// Amount of ffmpeg processes running concurrently
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub encode_jobs: u32,
+
     /// Retry amount
     #[arg(short, long, default_value_t = 5)]
     pub retry: usize,
 
+    /// Amount of parallel connections used to download a direct link, each fetching a part of the file.
+    /// Implies --download-first for direct links. Falls back to one connection if the server doesn't support it.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..), verbatim_doc_comment)]
+    pub connections: u32,
+
     /// Download the file first instead of passing url to ffmpeg
     /// Some services will ignore this option due to how their service work.
     /// e.g. Google Drive
     #[arg(long, action)]
     pub download_first: bool,
+}
 
+#[derive(Debug, Clone, clap::Args)]
+pub struct PlaylistInput {
     /// Path to the input file
     #[arg(
         short,
@@ -108,14 +294,122 @@ pub struct DownloadOpts {
 }
 
 impl DownloadOpts {
-    pub fn get_cookie_path(&self) -> PathBuf {
-        if let Some(c) = self.cookies.clone() {
+    /// Cookie file for the url, None if there's none yet and yt-dlp should go without.
+    /// A --cookies file that doesn't exist is an error, only the default jars are optional.
+    pub fn get_cookie_path(&self, url: &str) -> Result<Option<PathBuf>, Error> {
+        if let Some(path) = &self.cookies {
+            if !path.is_file() {
+                bail!("Cookie file {} not found", path.display());
+            }
+            return Ok(Some(path.clone()));
+        }
+
+        let path = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|x| x.host_str().and_then(cookies::cookie_jar_for_host))
+            .unwrap_or_else(|| crate::statics::PROJECT_DIR_PATH.join("cookie.txt"));
+
+        if !path.exists() {
+            tracing::debug!("No cookie file at {}, going without", path.display());
+            return Ok(None);
+        }
+
+        Ok(Some(path))
+    }
+
+    pub fn get_remote_config_path(&self) -> PathBuf {
+        if let Some(c) = self.remote_config.clone() {
+            c
+        } else {
+            crate::statics::PROJECT_DIR_PATH.join("remotes.json")
+        }
+    }
+
+    pub fn get_header_config_path(&self) -> PathBuf {
+        if let Some(c) = self.header_config.clone() {
             c
         } else {
-            crate::statics::PROJECT_DIR_PATH.join("cookie.txt")
+            crate::statics::PROJECT_DIR_PATH.join("headers.json")
         }
     }
 
+    /// Headers and proxy for requests to the url. Per-entry headers win over per-host ones,
+    /// which win over --header.
+    pub fn get_request_settings(
+        &self,
+        url: &str,
+        opts: &crate::parser::LineOptions,
+    ) -> crate::funcs::http::RequestSettings {
+        let mut settings = crate::funcs::http::RequestSettings {
+            headers: vec![],
+            proxy: self.proxy.clone(),
+        };
+
+        settings.merge_headers(self.headers.clone());
+        if let (Some(rules), Some(host)) = (
+            crate::statics::HEADER_RULES.get(),
+            reqwest::Url::parse(url)
+                .ok()
+                .and_then(|x| x.host_str().map(str::to_string)),
+        ) {
+            settings.merge_headers(rules.for_host(&host));
+        }
+        settings.merge_headers(opts.headers.clone());
+
+        settings
+    }
+
+    /// Name of the encoding profile used for the entry
+    pub fn get_profile_name<'a>(&'a self, opts: &'a crate::parser::LineOptions) -> &'a str {
+        opts.profile.as_deref().unwrap_or(&self.profile)
+    }
+
+    /// yt-dlp format selector used for the entry
+    pub fn get_format<'a>(&'a self, opts: &'a crate::parser::LineOptions) -> &'a str {
+        opts.format.as_deref().unwrap_or(&self.format)
+    }
+
+    /// yt-dlp `-S` value made of the height and codec preferences, then --format-sort
+    pub fn get_format_sort(&self) -> Option<String> {
+        let fields = [
+            self.max_height.map(|x| format!("res:{x}")),
+            self.prefer_vcodec.as_ref().map(|x| format!("vcodec:{x}")),
+            self.format_sort.clone(),
+        ];
+        let sort = fields.into_iter().flatten().collect::<Vec<_>>();
+
+        (!sort.is_empty()).then(|| sort.join(","))
+    }
+
+    pub fn get_profile_config_path(&self) -> PathBuf {
+        if let Some(c) = self.profile_config.clone() {
+            c
+        } else {
+            crate::statics::PROJECT_DIR_PATH.join("profiles.json")
+        }
+    }
+
+    /// Target directory joined with the entry's subdirectory, created if missing
+    pub fn get_output_dir(&self, opts: &crate::parser::LineOptions) -> Result<PathBuf, Error> {
+        let base = match self.target_dir {
+            Some(ref x) => x.clone(),
+            None => std::env::current_dir()?,
+        };
+
+        let dir = match &opts.dir {
+            Some(subdir) => base.join(subdir),
+            None => return Ok(base),
+        };
+
+        if !dir.exists() {
+            std::fs::create_dir_all(&dir).wrap_err("Failed to create entry subdirectory")?;
+        }
+
+        Ok(dir)
+    }
+}
+
+impl PlaylistInput {
     pub fn contents(self) -> Result<String, Error> {
         if let Some(p) = self.input_file {
             std::fs::read_to_string(p).wrap_err("Failed to read file")
@@ -188,3 +482,33 @@ pub fn initialize() -> Result<AppArgs, Error> {
 
     Ok(args)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn download_opts(args: &[&str]) -> DownloadOpts {
+        let args = ["yt-dlp-to-ffmpeg", "download"]
+            .iter()
+            .chain(args)
+            .chain(&["-"]);
+        match AppArgs::try_parse_from(args).unwrap().command {
+            Subcommands::Download { opts, .. } => *opts,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn explicit_cookie_file_must_exist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cookies.txt");
+        let opts = download_opts(&["--cookies", &path.to_string_lossy()]);
+        assert!(opts.get_cookie_path("https://example.com/").is_err());
+
+        std::fs::write(&path, "# Netscape HTTP Cookie File\n").unwrap();
+        assert_eq!(
+            opts.get_cookie_path("https://example.com/").unwrap(),
+            Some(path)
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use std::path::PathBuf;
+
+use clap::Subcommand;
+use color_eyre::eyre::Error;
+
+use crate::{statics::PROJECT_DIR_PATH, structs::CookieJar};
+
+#[derive(Subcommand, Clone)]
+pub enum CookieCommands {
+    /// Import cookies for yt-dlp into a jar per domain in the projects data folder
+    Import {
+        /// Firefox or Chromium profile folder, its cookie database, or a Netscape cookie file
+        source: PathBuf,
+
+        /// Domain to import cookies for, e.g. youtube.com. Subdomains are included.
+        /// Can be repeated or comma separated.
+        #[arg(
+            long = "domain",
+            required = true,
+            value_delimiter = ',',
+            verbatim_doc_comment
+        )]
+        domains: Vec<String>,
+    },
+}
+
+/// Folder holding the imported jars, named `{domain}.txt`
+pub fn cookie_jar_dir() -> PathBuf {
+    PROJECT_DIR_PATH.join("cookies")
+}
+
+/// Imported jar of the host, or of the closest parent domain with one
+pub fn cookie_jar_for_host(host: &str) -> Option<PathBuf> {
+    let host = host.to_ascii_lowercase();
+    let mut domain = host.as_str();
+
+    loop {
+        let path = cookie_jar_dir().join(format!("{domain}.txt"));
+        if path.is_file() {
+            return Some(path);
+        }
+        domain = domain.split_once('.')?.1;
+    }
+}
+
+pub async fn handle_cookie_commands(cmd: &CookieCommands) -> Result<(), Error> {
+    match cmd {
+        CookieCommands::Import { source, domains } => {
+            let (kind, jar) = CookieJar::load(source).await?;
+            println!("Read {} {kind} cookies", jar.0.len());
+
+            std::fs::create_dir_all(cookie_jar_dir())?;
+            for domain in domains {
+                let domain = domain.trim().trim_start_matches('.').to_ascii_lowercase();
+                let filtered = jar.for_domain(&domain);
+                if filtered.0.is_empty() {
+                    tracing::warn!("No unexpired cookies found for {domain}");
+                    continue;
+                }
+
+                let path = cookie_jar_dir().join(format!("{domain}.txt"));
+                crate::funcs::fs::write_private(&path, filtered.to_netscape().as_bytes())?;
+                println!(
+                    "Wrote {} cookies for {domain} to {}",
+                    filtered.0.len(),
+                    path.display()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
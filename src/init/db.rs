@@ -1,43 +1,96 @@
-use clap::{crate_name, Subcommand};
-use color_eyre::eyre::Error;
+use clap::Subcommand;
+use color_eyre::eyre::{bail, Error};
 
 use libsql::Builder;
 
 use crate::statics::PROJECT_DIR_PATH;
 use std::fs;
 
+pub mod jobs;
+mod query;
+pub mod sync;
+
 #[derive(Subcommand, Clone)]
 pub enum DbCommands {
     Init {
         #[clap(flatten)]
         remote_args: Option<RemoteDbCredentials>,
+
+        /// Keep the local database standalone and only sync with sync-remote/sync-local
+        #[arg(long, requires = "url")]
+        no_replica: bool,
     },
+    /// Push local job history to the remote database
     SyncRemote,
-    // SyncLocal,
+    /// Pull job history from the remote database into the local one
+    SyncLocal,
+    /// Show the database mode and when it last synced
+    Status,
+    /// List recorded jobs
+    List {
+        /// Job status, e.g. running, done, failed
+        #[arg(long)]
+        status: Option<jobs::JobStatus>,
+
+        /// Only jobs created on or after this date (YYYY-MM-DD)
+        #[arg(long)]
+        since: Option<chrono::NaiveDate>,
+
+        /// Source type, e.g. yt-dlp, direct, gdrive, dropbox
+        #[arg(long)]
+        source: Option<crate::parser::DlTypes>,
+
+        /// Print as JSON instead of a table
+        #[arg(long, action)]
+        json: bool,
+    },
+    /// Show every recorded field of a job
+    Show {
+        id: i64,
+
+        /// Print as JSON instead of a table
+        #[arg(long, action)]
+        json: bool,
+    },
+    /// Run failed jobs again through the download pipeline
+    RetryFailed {
+        #[command(flatten)]
+        opts: Box<super::DownloadOpts>,
+    },
+    /// Delete a job by id, or every job of a url
+    Forget { target: String },
 }
 
 #[derive(Debug, Clone, clap::Args, serde::Deserialize, serde::Serialize)]
 pub struct RemoteDbCredentials {
     token: String,
     url: String,
+
+    #[arg(skip = true)]
+    #[serde(default = "default_replica")]
+    replica: bool,
+}
+
+fn default_replica() -> bool {
+    true
 }
 
 impl RemoteDbCredentials {
-    pub fn new<T: ToString>(token: T, url: T) -> Self {
-        Self {
-            token: token.to_string(),
-            url: url.to_string(),
-        }
+    fn cred_path() -> std::path::PathBuf {
+        PROJECT_DIR_PATH.join("db_creds.json")
+    }
+
+    pub fn exists() -> bool {
+        Self::cred_path().exists()
     }
 
     pub fn load_default() -> Result<Self, Error> {
-        let cred_path = PROJECT_DIR_PATH.join("db_creds.json");
-        let cred_data = fs::read_to_string(&cred_path)?;
+        let cred_data = fs::read_to_string(Self::cred_path())?;
         Ok(serde_json::from_str(&cred_data)?)
     }
 
     pub fn save(&self) -> Result<(), Error> {
-        let cred_path = PROJECT_DIR_PATH.join("db_creds.json");
+        let cred_path = Self::cred_path();
         let filestr = serde_json::to_string(self)?;
         fs::write(&cred_path, &filestr)?;
         Ok(())
@@ -45,19 +98,27 @@ impl RemoteDbCredentials {
 }
 
 pub async fn handle_db_init(cmd: &Option<RemoteDbCredentials>) -> Result<(), Error> {
-    let db_path = PROJECT_DIR_PATH.join("database.db");
+    let db_path = get_db_path();
     let db = match cmd {
-        Some(RemoteDbCredentials { token, url }) => {
+        Some(RemoteDbCredentials {
+            token,
+            url,
+            replica: true,
+        }) => {
             Builder::new_remote_replica(&db_path, url.to_string(), token.to_string())
                 .build()
                 .await?
         }
-        None => Builder::new_local(&db_path).build().await?,
+        _ => Builder::new_local(&db_path).build().await?,
     };
 
     let conn = db.connect()?;
     conn.query("select 1; select 1;", ()).await.unwrap();
-    db.sync().await?;
+    jobs::migrate(&conn).await?;
+
+    if cmd.as_ref().is_some_and(|x| x.replica) {
+        db.sync().await?;
+    }
 
     if let Some(creds) = cmd {
         creds.save()?;
@@ -66,12 +127,147 @@ pub async fn handle_db_init(cmd: &Option<RemoteDbCredentials>) -> Result<(), Err
     Ok(())
 }
 
+fn get_db_path() -> std::path::PathBuf {
+    PROJECT_DIR_PATH.join("database.db")
+}
+
+fn load_replica_creds() -> Result<Option<RemoteDbCredentials>, Error> {
+    if !RemoteDbCredentials::exists() {
+        return Ok(None);
+    }
+
+    let creds = RemoteDbCredentials::load_default()?;
+    Ok(creds.replica.then_some(creds))
+}
+
+/// Opens the database, as a replica of the remote one if it was set up that way on init
+pub async fn open_db() -> Result<libsql::Database, Error> {
+    let Some(RemoteDbCredentials { token, url, .. }) = load_replica_creds()? else {
+        return Ok(Builder::new_local(get_db_path()).build().await?);
+    };
+
+    let db = Builder::new_remote_replica(get_db_path(), url, token)
+        .build()
+        .await?;
+    let replicated = db.sync().await?;
+    sync::SyncState::record_replica_sync(replicated.frames_synced() as u64)?;
+
+    Ok(db)
+}
+
+fn format_sync_entry(entry: &Option<sync::SyncEntry>, unit: &str) -> String {
+    match entry {
+        Some(x) => format!("{} ({} {unit})", x.at.to_rfc3339(), x.count),
+        None => "never".to_string(),
+    }
+}
+
+pub async fn handle_db_status() -> Result<(), Error> {
+    let creds = if RemoteDbCredentials::exists() {
+        Some(RemoteDbCredentials::load_default()?)
+    } else {
+        None
+    };
+    let state = sync::SyncState::load()?;
+
+    println!("Database: {}", get_db_path().display());
+    match &creds {
+        Some(x) if x.replica => println!("Mode: remote replica"),
+        _ => println!("Mode: local"),
+    }
+    println!(
+        "Remote: {}",
+        creds.as_ref().map_or("not configured", |x| x.url.as_str())
+    );
+
+    if get_db_path().exists() {
+        // Only looking, so neither migrate nor take a write lock on a replica in use
+        let db = Builder::new_local(get_db_path())
+            .flags(libsql::OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .build()
+            .await?;
+        let conn = db.connect()?;
+        // Databases created before job history was recorded have no jobs table
+        let has_jobs = conn
+            .query("SELECT 1 FROM pragma_table_info('jobs')", ())
+            .await?
+            .next()
+            .await?
+            .is_some();
+
+        let mut counts = vec![];
+        if has_jobs {
+            let mut rows = conn
+                .query("SELECT status, COUNT(*) FROM jobs GROUP BY status", ())
+                .await?;
+            while let Some(row) = rows.next().await? {
+                counts.push(format!("{} {}", row.get::<i64>(1)?, row.get::<String>(0)?));
+            }
+        }
+        println!(
+            "Jobs: {}",
+            if counts.is_empty() {
+                "none".to_string()
+            } else {
+                counts.join(", ")
+            }
+        );
+    } else {
+        println!("Jobs: database not initialized");
+    }
+
+    if creds.as_ref().is_some_and(|x| x.replica) {
+        println!(
+            "Last replica sync: {}",
+            format_sync_entry(&state.last_replica_sync, "frames")
+        );
+    }
+    println!("Last push: {}", format_sync_entry(&state.last_push, "rows"));
+    println!("Last pull: {}", format_sync_entry(&state.last_pull, "rows"));
+
+    Ok(())
+}
+
 pub async fn handle_db_commands(cmd: &DbCommands) -> Result<(), Error> {
     match cmd {
-        DbCommands::Init { remote_args } => {
-            handle_db_init(remote_args).await?;
+        DbCommands::Init {
+            remote_args,
+            no_replica,
+        } => {
+            let remote_args = remote_args.clone().map(|x| RemoteDbCredentials {
+                replica: !no_replica,
+                ..x
+            });
+            handle_db_init(&remote_args).await?;
+        }
+        DbCommands::SyncRemote => {
+            let rows = sync::sync(sync::SyncDirection::Push).await?;
+            println!("Pushed {rows} rows to the remote database");
+        }
+        DbCommands::SyncLocal => {
+            let rows = sync::sync(sync::SyncDirection::Pull).await?;
+            println!("Pulled {rows} rows from the remote database");
+        }
+        DbCommands::Status => handle_db_status().await?,
+        DbCommands::List {
+            status,
+            since,
+            source,
+            json,
+        } => {
+            let filter = jobs::JobFilter {
+                status: *status,
+                since: *since,
+                source: source.clone(),
+            };
+            query::handle_list(&filter, *json).await?;
+        }
+        DbCommands::Show { id, json } => query::handle_show(*id, *json).await?,
+        DbCommands::Forget { target } => query::handle_forget(target).await?,
+        // Needs the whole download pipeline, so main runs it
+        DbCommands::RetryFailed { .. } => {
+            bail!("retry-failed runs through the download pipeline, not the database commands")
         }
-        DbCommands::SyncRemote => todo!(),
     }
     Ok(())
 }
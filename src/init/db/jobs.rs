@@ -0,0 +1,581 @@
+use std::{collections::HashMap, sync::Arc};
+
+use color_eyre::eyre::{ContextCompat, Error};
+use libsql::{params, Connection, Database};
+use serde::{Deserialize, Serialize};
+use strum::{Display, EnumString};
+
+use crate::{
+    funcs::{
+        quality::{LowQuality, QualityScore},
+        ytdlp::PlaylistItem,
+    },
+    parser::{DlTypes, LineOptions, PlaylistEntry},
+};
+
+const JOBS_SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS jobs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    uid TEXT NOT NULL,
+    source_type TEXT NOT NULL,
+    source_id TEXT NOT NULL,
+    source_url TEXT,
+    entry TEXT,
+    entry_index INTEGER,
+    title TEXT,
+    output_path TEXT,
+    remote_path TEXT,
+    size INTEGER,
+    md5 TEXT,
+    quality_metric TEXT,
+    quality_score REAL,
+    profile TEXT,
+    status TEXT NOT NULL,
+    error TEXT,
+    created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+    updated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+);
+CREATE INDEX IF NOT EXISTS jobs_source_idx ON jobs (source_type, source_id);
+CREATE UNIQUE INDEX IF NOT EXISTS jobs_uid_idx ON jobs (uid);
+CREATE TABLE IF NOT EXISTS playlist_items (
+    playlist_url TEXT NOT NULL,
+    video_id TEXT NOT NULL,
+    url TEXT NOT NULL,
+    playlist_index INTEGER,
+    upload_date TEXT,
+    first_seen TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+    PRIMARY KEY (playlist_url, video_id)
+);
+"#;
+
+// Columns missing from databases created by older versions, with the statements adding them
+const JOBS_UPGRADES: &[(&str, &str)] = &[
+    (
+        "uid",
+        r#"
+ALTER TABLE jobs ADD COLUMN uid TEXT;
+UPDATE jobs SET uid = lower(hex(randomblob(16))) WHERE uid IS NULL;
+"#,
+    ),
+    ("source_url", "ALTER TABLE jobs ADD COLUMN source_url TEXT;"),
+    ("entry", "ALTER TABLE jobs ADD COLUMN entry TEXT;"),
+    (
+        "entry_index",
+        "ALTER TABLE jobs ADD COLUMN entry_index INTEGER;",
+    ),
+    (
+        "quality_score",
+        r#"
+ALTER TABLE jobs ADD COLUMN quality_metric TEXT;
+ALTER TABLE jobs ADD COLUMN quality_score REAL;
+"#,
+    ),
+];
+
+pub const JOB_COLUMNS: &str = "id, uid, source_type, source_id, source_url, entry, entry_index, \
+    title, output_path, remote_path, size, md5, quality_metric, quality_score, profile, status, error, \
+    created_at, updated_at";
+
+#[derive(Debug, Clone, Copy, PartialEq, Display, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum JobStatus {
+    Running,
+    Done,
+    Failed,
+}
+
+/// Row of the `jobs` table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRecord {
+    pub id: i64,
+    pub uid: String,
+    pub source_type: String,
+    pub source_id: String,
+    /// Url of the playlist entry the job came from
+    pub source_url: Option<String>,
+    /// Playlist entry the job came from, in playlist syntax
+    pub entry: Option<String>,
+    /// Index the output file was named with
+    pub entry_index: Option<i64>,
+    pub title: Option<String>,
+    pub output_path: Option<String>,
+    pub remote_path: Option<String>,
+    pub size: Option<i64>,
+    pub md5: Option<String>,
+    /// Metric of the quality check, e.g. `vmaf`
+    pub quality_metric: Option<String>,
+    pub quality_score: Option<f64>,
+    pub profile: Option<String>,
+    pub status: String,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// Conditions for listing jobs, unset fields match everything
+#[derive(Debug, Clone, Default)]
+pub struct JobFilter {
+    pub status: Option<JobStatus>,
+    pub since: Option<chrono::NaiveDate>,
+    pub source: Option<DlTypes>,
+}
+
+/// Everything known about a job before it starts processing
+pub struct NewJob<'a> {
+    pub source_type: &'a DlTypes,
+    pub source_id: &'a str,
+    pub source_url: &'a str,
+    /// Index the output file is named with
+    pub index: Option<usize>,
+    pub title: &'a str,
+    pub output_path: &'a std::path::Path,
+    pub profile: &'a str,
+    pub opts: &'a LineOptions,
+}
+
+/// What's remembered about a video of a playlist
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnownPlaylistItem {
+    /// Position the output is named with, kept from when the video was first listed
+    pub index: usize,
+    pub upload_date: Option<chrono::NaiveDate>,
+}
+
+/// What a successful job produced
+#[derive(Debug, Clone)]
+pub struct JobOutput {
+    pub remote_path: Option<String>,
+    pub size: u64,
+    pub md5: String,
+    pub quality: Option<QualityScore>,
+}
+
+impl JobOutput {
+    /// Collects size and MD5 of the file. Call before the file gets deleted on upload.
+    pub async fn from_path(path: &std::path::Path) -> Result<Self, Error> {
+        let size = tokio::fs::metadata(path).await?.len();
+        let path = path.to_path_buf();
+        let md5 = tokio::task::spawn_blocking(move || crate::funcs::md5::get_md5_from_path(&path))
+            .await??;
+
+        Ok(Self {
+            remote_path: None,
+            size,
+            md5,
+            quality: None,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct JobHistory {
+    // Kept alive for as long as the connection is used
+    _db: Arc<Database>,
+    conn: Connection,
+}
+
+impl JobHistory {
+    pub async fn open() -> Result<Self, Error> {
+        let db = super::open_db().await?;
+        let conn = db.connect()?;
+
+        migrate(&conn).await?;
+
+        // Jobs of a run that crashed or got killed never finished
+        let stale = conn
+            .execute(
+                "UPDATE jobs SET status = ?1, error = ?2,
+                updated_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now') WHERE status = ?3",
+                params![
+                    JobStatus::Failed.to_string(),
+                    "Interrupted before finishing",
+                    JobStatus::Running.to_string()
+                ],
+            )
+            .await?;
+        if stale > 0 {
+            tracing::warn!("Marked {stale} jobs left running by an earlier run as failed");
+        }
+
+        Ok(Self {
+            _db: Arc::new(db),
+            conn,
+        })
+    }
+
+    /// Whether the source already went through the whole pipeline successfully
+    pub async fn is_done(&self, source_type: &DlTypes, source_id: &str) -> Result<bool, Error> {
+        let mut rows = self
+            .conn
+            .query(
+                "SELECT 1 FROM jobs WHERE source_type = ?1 AND source_id = ?2 AND status = ?3 LIMIT 1",
+                params![
+                    source_type.to_string(),
+                    source_id,
+                    JobStatus::Done.to_string()
+                ],
+            )
+            .await?;
+
+        Ok(rows.next().await?.is_some())
+    }
+
+    /// Records the job as running, returning its id
+    pub async fn start(&self, job: NewJob<'_>) -> Result<i64, Error> {
+        let entry = PlaylistEntry {
+            ty: job.source_type.clone(),
+            opts: job.opts.clone(),
+            url: job.source_url.to_string(),
+        };
+
+        // The connection is shared between jobs, so last_insert_rowid() can't be trusted
+        let mut rows = self
+            .conn
+            .query(
+                "INSERT INTO jobs (uid, source_type, source_id, source_url, entry, entry_index, title,
+                    output_path, profile, status)
+                VALUES (lower(hex(randomblob(16))), ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9) RETURNING id",
+                params![
+                    job.source_type.to_string(),
+                    job.source_id,
+                    job.source_url,
+                    entry.to_string(),
+                    job.index.map(|x| x as i64),
+                    job.title,
+                    job.output_path.to_string_lossy().into_owned(),
+                    job.profile,
+                    JobStatus::Running.to_string()
+                ],
+            )
+            .await?;
+
+        let row = rows.next().await?.wrap_err("Failed to insert job record")?;
+
+        Ok(row.get::<i64>(0)?)
+    }
+
+    pub async fn finish(&self, id: i64, result: &Result<JobOutput, Error>) -> Result<(), Error> {
+        match result {
+            Ok(output) => {
+                self.conn
+                    .execute(
+                        "UPDATE jobs SET status = ?2, remote_path = ?3, size = ?4, md5 = ?5,
+                        quality_metric = ?6, quality_score = ?7, error = NULL,
+                        updated_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now') WHERE id = ?1",
+                        params![
+                            id,
+                            JobStatus::Done.to_string(),
+                            output.remote_path.clone(),
+                            output.size as i64,
+                            output.md5.clone(),
+                            output.quality.map(|x| x.metric.to_string()),
+                            output.quality.map(|x| x.score)
+                        ],
+                    )
+                    .await?
+            }
+            Err(e) => {
+                // Outputs rejected by the quality check still get their score recorded
+                let quality = e.downcast_ref::<LowQuality>().map(|x| x.score);
+
+                self.conn
+                    .execute(
+                        "UPDATE jobs SET status = ?2, error = ?3, quality_metric = ?4, quality_score = ?5,
+                        updated_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now') WHERE id = ?1",
+                        params![
+                            id,
+                            JobStatus::Failed.to_string(),
+                            e.to_string(),
+                            quality.map(|x| x.metric.to_string()),
+                            quality.map(|x| x.score)
+                        ],
+                    )
+                    .await?
+            }
+        };
+
+        Ok(())
+    }
+
+    pub async fn list(&self, filter: &JobFilter) -> Result<Vec<JobRecord>, Error> {
+        let mut rows = self
+            .conn
+            .query(
+                &format!(
+                    "SELECT {JOB_COLUMNS} FROM jobs
+                    WHERE (?1 IS NULL OR status = ?1)
+                    AND (?2 IS NULL OR created_at >= ?2)
+                    AND (?3 IS NULL OR source_type = ?3)
+                    ORDER BY id"
+                ),
+                params![
+                    filter.status.map(|x| x.to_string()),
+                    filter.since.map(|x| x.format("%Y-%m-%d").to_string()),
+                    filter.source.as_ref().map(|x| x.to_string())
+                ],
+            )
+            .await?;
+
+        let mut records = vec![];
+        while let Some(row) = rows.next().await? {
+            records.push(libsql::de::from_row::<JobRecord>(&row)?);
+        }
+
+        Ok(records)
+    }
+
+    pub async fn get(&self, id: i64) -> Result<Option<JobRecord>, Error> {
+        let mut rows = self
+            .conn
+            .query(
+                &format!("SELECT {JOB_COLUMNS} FROM jobs WHERE id = ?1"),
+                params![id],
+            )
+            .await?;
+
+        match rows.next().await? {
+            Some(row) => Ok(Some(libsql::de::from_row::<JobRecord>(&row)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Deletes a job by id, or every job of a source id or playlist url, returning the amount deleted
+    pub async fn forget(&self, target: &str) -> Result<u64, Error> {
+        let deleted = match target.parse::<i64>() {
+            Ok(id) => {
+                self.conn
+                    .execute("DELETE FROM jobs WHERE id = ?1", params![id])
+                    .await?
+            }
+            Err(_) => {
+                self.conn
+                    .execute(
+                        "DELETE FROM jobs WHERE source_id = ?1 OR source_url = ?1",
+                        params![target],
+                    )
+                    .await?
+            }
+        };
+
+        Ok(deleted)
+    }
+
+    /// Records the videos a playlist expanded into, keeping what's known about ones seen before,
+    /// and returns what's known about every video of the playlist by id. New videos get the index
+    /// yt-dlp reports, or are numbered after the ones already recorded. Indices never change once
+    /// stored, as yt-dlp's shift whenever the playlist does.
+    pub async fn record_playlist(
+        &self,
+        playlist_url: &str,
+        items: &[PlaylistItem],
+    ) -> Result<HashMap<String, KnownPlaylistItem>, Error> {
+        for item in items {
+            self.conn
+                .execute(
+                    "INSERT INTO playlist_items (playlist_url, video_id, url, playlist_index, upload_date)
+                    VALUES (?1, ?2, ?3, COALESCE(?4, (SELECT COALESCE(MAX(playlist_index), 0) + 1
+                        FROM playlist_items WHERE playlist_url = ?1)), ?5)
+                    ON CONFLICT (playlist_url, video_id) DO UPDATE SET
+                        upload_date = COALESCE(?5, upload_date)",
+                    params![
+                        playlist_url,
+                        item.id.clone(),
+                        item.url.clone(),
+                        item.playlist_index.map(|x| x as i64),
+                        item.upload_date.clone()
+                    ],
+                )
+                .await?;
+        }
+
+        let mut rows = self
+            .conn
+            .query(
+                "SELECT video_id, playlist_index, upload_date FROM playlist_items
+                WHERE playlist_url = ?1",
+                params![playlist_url],
+            )
+            .await?;
+
+        let mut known = HashMap::new();
+        while let Some(row) = rows.next().await? {
+            let upload_date = row
+                .get::<Option<String>>(2)?
+                .and_then(|x| chrono::NaiveDate::parse_from_str(&x, "%Y%m%d").ok());
+            known.insert(
+                row.get::<String>(0)?,
+                KnownPlaylistItem {
+                    index: row.get::<i64>(1)? as usize,
+                    upload_date,
+                },
+            );
+        }
+
+        Ok(known)
+    }
+
+    /// Remembers the upload date of a playlist video the listing had none for, so later runs
+    /// can apply date filters without fetching it again
+    pub async fn record_upload_date(
+        &self,
+        playlist_url: &str,
+        video_id: &str,
+        date: chrono::NaiveDate,
+    ) -> Result<(), Error> {
+        self.conn
+            .execute(
+                "UPDATE playlist_items SET upload_date = ?3 WHERE playlist_url = ?1 AND video_id = ?2",
+                params![
+                    playlist_url,
+                    video_id,
+                    date.format("%Y%m%d").to_string()
+                ],
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Playlist entries of failed jobs whose source hasn't been processed successfully since,
+    /// with the index their output was named with
+    pub async fn failed_entries(&self) -> Result<Vec<(Option<usize>, String)>, Error> {
+        // SQLite takes the bare entry_index from the row matching MAX(id), the latest attempt
+        let mut rows = self
+            .conn
+            .query(
+                "SELECT entry, COUNT(*), entry_index, MAX(id) FROM jobs
+                WHERE status = ?1 AND NOT EXISTS (
+                    SELECT 1 FROM jobs AS done
+                    WHERE done.source_type = jobs.source_type
+                    AND done.source_id = jobs.source_id
+                    AND done.status = ?2
+                )
+                GROUP BY entry ORDER BY MIN(id)",
+                params![JobStatus::Failed.to_string(), JobStatus::Done.to_string()],
+            )
+            .await?;
+
+        let mut entries = vec![];
+        while let Some(row) = rows.next().await? {
+            match row.get::<Option<String>>(0)? {
+                Some(entry) => {
+                    let index = row.get::<Option<i64>>(2)?.map(|x| x as usize);
+                    entries.push((index, entry))
+                }
+                None => tracing::warn!(
+                    "Skipping {} failed jobs recorded before playlist entries were stored",
+                    row.get::<i64>(1)?
+                ),
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+pub async fn migrate(conn: &Connection) -> Result<(), Error> {
+    let mut rows = conn
+        .query("SELECT name FROM pragma_table_info('jobs')", ())
+        .await?;
+
+    let mut columns = vec![];
+    while let Some(row) = rows.next().await? {
+        columns.push(row.get::<String>(0)?);
+    }
+
+    // Fresh databases get every column from the schema below
+    if !columns.is_empty() {
+        for (column, upgrade) in JOBS_UPGRADES {
+            if !columns.iter().any(|x| x == column) {
+                tracing::info!("Upgrading jobs table, adding {column}...");
+                conn.execute_batch(upgrade).await?;
+            }
+        }
+    }
+
+    conn.execute_batch(JOBS_SCHEMA).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn memory_history() -> JobHistory {
+        let db = libsql::Builder::new_local(":memory:")
+            .build()
+            .await
+            .unwrap();
+        let conn = db.connect().unwrap();
+        migrate(&conn).await.unwrap();
+
+        JobHistory {
+            _db: Arc::new(db),
+            conn,
+        }
+    }
+
+    fn item(id: &str, playlist_index: Option<usize>, upload_date: Option<&str>) -> PlaylistItem {
+        PlaylistItem {
+            id: id.to_string(),
+            url: format!("https://example.com/{id}"),
+            title: None,
+            upload_date: upload_date.map(str::to_string),
+            playlist_index,
+        }
+    }
+
+    #[tokio::test]
+    async fn playlist_indices_stay_put() {
+        let history = memory_history().await;
+        let url = "https://example.com/playlist";
+
+        let known = history
+            .record_playlist(
+                url,
+                &[item("a", None, None), item("b", None, Some("20240102"))],
+            )
+            .await
+            .unwrap();
+        assert_eq!(known["a"].index, 1);
+        assert_eq!(known["b"].index, 2);
+        assert_eq!(
+            known["b"].upload_date,
+            chrono::NaiveDate::from_ymd_opt(2024, 1, 2)
+        );
+
+        // A new video listed first doesn't shift the ones already named
+        let date = chrono::NaiveDate::from_ymd_opt(2023, 5, 6).unwrap();
+        history.record_upload_date(url, "a", date).await.unwrap();
+        let known = history
+            .record_playlist(
+                url,
+                &[
+                    item("c", None, None),
+                    item("a", None, None),
+                    item("b", None, None),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(known["a"].index, 1);
+        assert_eq!(known["b"].index, 2);
+        assert_eq!(known["c"].index, 3);
+        assert_eq!(known["a"].upload_date, Some(date));
+
+        // yt-dlp's index names new videos, but doesn't renumber stored ones
+        let known = history
+            .record_playlist(url, &[item("d", Some(7), None), item("c", Some(1), None)])
+            .await
+            .unwrap();
+        assert_eq!(known["d"].index, 7);
+        assert_eq!(known["c"].index, 3);
+
+        let known = history
+            .record_playlist(url, &[item("d", Some(2), None)])
+            .await
+            .unwrap();
+        assert_eq!(known["d"].index, 7);
+    }
+}
@@ -0,0 +1,134 @@
+use color_eyre::eyre::{ContextCompat, Error};
+
+use super::jobs::{JobFilter, JobHistory, JobRecord};
+
+const TITLE_MAX_LEN: usize = 60;
+
+fn truncate(s: &str, max: usize) -> String {
+    if s.chars().count() <= max {
+        return s.to_string();
+    }
+
+    format!("{}…", s.chars().take(max - 1).collect::<String>())
+}
+
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths = headers
+        .iter()
+        .map(|x| x.chars().count())
+        .collect::<Vec<_>>();
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+
+    let print_row = |cells: Vec<&str>| {
+        let line = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!("{cell:<w$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    };
+
+    print_row(headers.to_vec());
+    for row in rows {
+        print_row(row.iter().map(String::as_str).collect());
+    }
+}
+
+pub async fn handle_list(filter: &JobFilter, json: bool) -> Result<(), Error> {
+    let jobs = JobHistory::open().await?.list(filter).await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&jobs)?);
+        return Ok(());
+    }
+
+    if jobs.is_empty() {
+        println!("No jobs found");
+        return Ok(());
+    }
+
+    let rows = jobs
+        .iter()
+        .map(|x| {
+            vec![
+                x.id.to_string(),
+                x.status.clone(),
+                x.source_type.clone(),
+                truncate(x.title.as_deref().unwrap_or(&x.source_id), TITLE_MAX_LEN),
+                x.updated_at.clone(),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    print_table(&["ID", "STATUS", "SOURCE", "TITLE", "UPDATED"], &rows);
+
+    Ok(())
+}
+
+pub async fn handle_show(id: i64, json: bool) -> Result<(), Error> {
+    let job: JobRecord = JobHistory::open()
+        .await?
+        .get(id)
+        .await?
+        .wrap_err_with(|| format!("No job with id {id}"))?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&job)?);
+        return Ok(());
+    }
+
+    let opt = |x: &Option<String>| x.clone().unwrap_or_default();
+    let rows = [
+        ("ID", job.id.to_string()),
+        ("UID", job.uid),
+        ("STATUS", job.status),
+        ("SOURCE", job.source_type),
+        ("SOURCE ID", job.source_id),
+        ("URL", opt(&job.source_url)),
+        ("ENTRY", opt(&job.entry)),
+        (
+            "INDEX",
+            job.entry_index.map(|x| x.to_string()).unwrap_or_default(),
+        ),
+        ("TITLE", opt(&job.title)),
+        ("OUTPUT", opt(&job.output_path)),
+        ("REMOTE", opt(&job.remote_path)),
+        ("SIZE", job.size.map(|x| x.to_string()).unwrap_or_default()),
+        ("MD5", opt(&job.md5)),
+        (
+            "QUALITY",
+            match (&job.quality_metric, job.quality_score) {
+                (Some(metric), Some(score)) => format!("{metric} {score:.3}"),
+                _ => String::new(),
+            },
+        ),
+        ("PROFILE", opt(&job.profile)),
+        ("ERROR", opt(&job.error)),
+        ("CREATED", job.created_at),
+        ("UPDATED", job.updated_at),
+    ]
+    .into_iter()
+    .map(|(k, v)| vec![k.to_string(), v])
+    .collect::<Vec<_>>();
+
+    print_table(&["FIELD", "VALUE"], &rows);
+
+    Ok(())
+}
+
+pub async fn handle_forget(target: &str) -> Result<(), Error> {
+    let deleted = JobHistory::open().await?.forget(target).await?;
+
+    if deleted == 0 {
+        tracing::warn!("No jobs matched {target}");
+    } else {
+        tracing::info!("Forgot {deleted} jobs");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,246 @@
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::{bail, Error};
+use libsql::{params, Builder, Connection, Database};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    jobs::{migrate, JobRecord, JOB_COLUMNS},
+    RemoteDbCredentials,
+};
+use crate::statics::PROJECT_DIR_PATH;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncDirection {
+    Push,
+    Pull,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncEntry {
+    pub at: DateTime<Utc>,
+    /// Rows for row syncs, WAL frames for replica syncs
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncState {
+    pub last_push: Option<SyncEntry>,
+    pub last_pull: Option<SyncEntry>,
+    pub last_replica_sync: Option<SyncEntry>,
+}
+
+impl SyncState {
+    fn path() -> std::path::PathBuf {
+        PROJECT_DIR_PATH.join("db_sync_state.json")
+    }
+
+    pub fn load() -> Result<Self, Error> {
+        let path = Self::path();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        std::fs::write(Self::path(), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    fn update(f: impl FnOnce(&mut Self, SyncEntry), count: u64) -> Result<(), Error> {
+        let mut state = Self::load()?;
+        f(
+            &mut state,
+            SyncEntry {
+                at: Utc::now(),
+                count,
+            },
+        );
+        state.save()
+    }
+
+    pub fn record_replica_sync(frames: u64) -> Result<(), Error> {
+        Self::update(|s, e| s.last_replica_sync = Some(e), frames)
+    }
+
+    fn record(direction: SyncDirection, rows: u64) -> Result<(), Error> {
+        match direction {
+            SyncDirection::Push => Self::update(|s, e| s.last_push = Some(e), rows),
+            SyncDirection::Pull => Self::update(|s, e| s.last_pull = Some(e), rows),
+        }
+    }
+}
+
+async fn open_remote(creds: &RemoteDbCredentials) -> Result<(Database, Connection), Error> {
+    let db = Builder::new_remote(creds.url.clone(), creds.token.clone())
+        .build()
+        .await?;
+    let conn = db.connect()?;
+    migrate(&conn).await?;
+
+    Ok((db, conn))
+}
+
+async fn fetch_rows(conn: &Connection) -> Result<Vec<JobRecord>, Error> {
+    let mut rows = conn
+        .query(&format!("SELECT {JOB_COLUMNS} FROM jobs"), ())
+        .await?;
+
+    let mut records = vec![];
+    while let Some(row) = rows.next().await? {
+        records.push(libsql::de::from_row::<JobRecord>(&row)?);
+    }
+
+    Ok(records)
+}
+
+/// Inserts rows missing from the database and updates the ones that are older,
+/// returning the amount of rows changed
+async fn upsert_rows(conn: &Connection, records: Vec<JobRecord>) -> Result<u64, Error> {
+    let tx = conn.transaction().await?;
+    let mut changed = 0;
+
+    for r in records {
+        changed += tx
+            .execute(
+                "INSERT INTO jobs (uid, source_type, source_id, source_url, entry, entry_index,
+                    title, output_path, remote_path, size, md5, quality_metric, quality_score,
+                    profile, status, error, created_at, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+                    ?18)
+                ON CONFLICT(uid) DO UPDATE SET
+                    source_url = excluded.source_url,
+                    entry = excluded.entry,
+                    entry_index = excluded.entry_index,
+                    title = excluded.title,
+                    output_path = excluded.output_path,
+                    remote_path = excluded.remote_path,
+                    size = excluded.size,
+                    md5 = excluded.md5,
+                    quality_metric = excluded.quality_metric,
+                    quality_score = excluded.quality_score,
+                    profile = excluded.profile,
+                    status = excluded.status,
+                    error = excluded.error,
+                    updated_at = excluded.updated_at
+                WHERE excluded.updated_at > jobs.updated_at",
+                params![
+                    r.uid,
+                    r.source_type,
+                    r.source_id,
+                    r.source_url,
+                    r.entry,
+                    r.entry_index,
+                    r.title,
+                    r.output_path,
+                    r.remote_path,
+                    r.size,
+                    r.md5,
+                    r.quality_metric,
+                    r.quality_score,
+                    r.profile,
+                    r.status,
+                    r.error,
+                    r.created_at,
+                    r.updated_at
+                ],
+            )
+            .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(changed)
+}
+
+pub async fn sync(direction: SyncDirection) -> Result<u64, Error> {
+    if !RemoteDbCredentials::exists() {
+        bail!("No remote database configured. Run `database init` with a token and url first");
+    }
+    let creds = RemoteDbCredentials::load_default()?;
+
+    let local_db = super::open_db().await?;
+    let local = local_db.connect()?;
+    migrate(&local).await?;
+
+    let (_remote_db, remote) = open_remote(&creds).await?;
+
+    let (src, dst) = match direction {
+        SyncDirection::Push => (&local, &remote),
+        SyncDirection::Pull => (&remote, &local),
+    };
+
+    let changed = upsert_rows(dst, fetch_rows(src).await?).await?;
+
+    SyncState::record(direction, changed)?;
+
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn local_db() -> Connection {
+        let db = Builder::new_local(":memory:").build().await.unwrap();
+        let conn = db.connect().unwrap();
+        migrate(&conn).await.unwrap();
+        conn
+    }
+
+    async fn insert(conn: &Connection, uid: &str, status: &str, updated_at: &str) {
+        conn.execute(
+            "INSERT INTO jobs (uid, source_type, source_id, status, created_at, updated_at)
+            VALUES (?1, 'direct', ?1, ?2, '2026-01-01T00:00:00Z', ?3)",
+            params![uid, status, updated_at],
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn statuses(conn: &Connection) -> Vec<(String, String)> {
+        fetch_rows(conn)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| (x.uid, x.status))
+            .collect()
+    }
+
+    async fn copy(src: &Connection, dst: &Connection) -> u64 {
+        upsert_rows(dst, fetch_rows(src).await.unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn inserts_missing_rows_once() {
+        let (src, dst) = (local_db().await, local_db().await);
+        insert(&src, "a", "done", "2026-01-01T00:00:00Z").await;
+        insert(&src, "b", "failed", "2026-01-01T00:00:00Z").await;
+
+        assert_eq!(copy(&src, &dst).await, 2);
+        assert_eq!(copy(&src, &dst).await, 0);
+        assert_eq!(statuses(&dst).await, statuses(&src).await);
+    }
+
+    #[tokio::test]
+    async fn newer_rows_win() {
+        let (src, dst) = (local_db().await, local_db().await);
+        insert(&src, "a", "done", "2026-01-02T00:00:00Z").await;
+        insert(&src, "b", "failed", "2026-01-01T00:00:00Z").await;
+        insert(&dst, "a", "failed", "2026-01-01T00:00:00Z").await;
+        insert(&dst, "b", "done", "2026-01-02T00:00:00Z").await;
+
+        assert_eq!(copy(&src, &dst).await, 1);
+        assert_eq!(
+            statuses(&dst).await,
+            [("a".into(), "done".into()), ("b".into(), "done".into())]
+        );
+
+        // Pulling back brings the other side up to date too
+        assert_eq!(copy(&dst, &src).await, 1);
+        assert_eq!(statuses(&src).await, statuses(&dst).await);
+    }
+}
@@ -1,7 +1,6 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
 use color_eyre::Report;
-use indicatif::ProgressIterator;
 
 mod consts;
 mod funcs;
@@ -16,15 +15,35 @@ mod parser;
 #[tokio::main]
 #[tracing::instrument]
 async fn main() -> Result<(), Report> {
-    let args = Rc::new(init::initialize()?);
+    let args = init::initialize()?;
 
-    let args = match &args.command {
+    let (args, vids) = match args.command {
         init::Subcommands::Authenticate { service } => {
             match &service {
                 init::AuthorizeCommands::GoogleDrive {
                     client_id,
                     client_secret,
-                } => services::google_drive::auth::authenticate(&client_id, &client_secret).await?,
+                    headless,
+                    service_account,
+                    subject,
+                } => {
+                    use services::google_drive::auth::{GdriveAuthFlow, GdriveCredentials};
+
+                    let flow = match (service_account, headless) {
+                        (Some(_), _) => GdriveAuthFlow::ServiceAccount,
+                        (None, true) => GdriveAuthFlow::Headless,
+                        (None, false) => GdriveAuthFlow::Installed,
+                    };
+                    let creds = GdriveCredentials {
+                        flow,
+                        id: client_id.clone().unwrap_or_default(),
+                        secret: client_secret.clone().unwrap_or_default(),
+                        subject: subject.clone(),
+                    };
+
+                    services::google_drive::auth::authenticate(creds, service_account.as_deref())
+                        .await?
+                }
                 init::AuthorizeCommands::Dropbox { client_id } => {
                     services::dropbox::auth::authenticate(client_id).await?;
                 }
@@ -32,13 +51,55 @@ async fn main() -> Result<(), Report> {
 
             return Ok(());
         }
+        init::Subcommands::Database {
+            command: init::db::DbCommands::RetryFailed { opts },
+        } => {
+            let entries = init::db::jobs::JobHistory::open()
+                .await?
+                .failed_entries()
+                .await?;
+
+            if entries.is_empty() {
+                tracing::info!("No failed jobs to retry");
+                return Ok(());
+            }
+
+            // Outputs keep the index they were named with the first time
+            let vids = entries
+                .iter()
+                .enumerate()
+                .map(|(line_idx, (i, entry))| Ok((*i, parser::parse_line((line_idx, entry))?)))
+                .collect::<Result<Vec<_>, Report>>()?;
+
+            (Arc::new(*opts), vids)
+        }
+        init::Subcommands::Cookies { command } => {
+            return init::cookies::handle_cookie_commands(&command).await;
+        }
         init::Subcommands::Database { command } => {
-            return init::db::handle_db_commands(command).await;
+            return init::db::handle_db_commands(&command).await;
 
             // return Ok(());
         }
-        init::Subcommands::Download(download_opts) => download_opts,
-        _ => unimplemented!(),
+        init::Subcommands::Download { opts, input } => {
+            let vids = input
+                .contents()?
+                .lines()
+                .enumerate()
+                .filter(parser::line_filter)
+                .map(parser::parse_line)
+                .collect::<Result<Vec<_>, Report>>()?;
+
+            // A single entry isn't numbered
+            let numbered = vids.len() > 1;
+            let vids = vids
+                .into_iter()
+                .enumerate()
+                .map(|(i, entry)| (numbered.then_some(i), entry))
+                .collect::<Vec<_>>();
+
+            (Arc::new(*opts), vids)
+        }
     };
 
     if let Some(path) = &args.target_dir {
@@ -52,19 +113,42 @@ async fn main() -> Result<(), Report> {
         }
     }
 
-    let op: Option<opendal::Operator> = if let Some(ref key) = args.b2args {
-        Some(funcs::opendal::setup_opendal(key)?)
-    } else {
-        None
-    };
+    let mut dests = vec![];
+    if !args.upload.is_empty() {
+        let remotes = structs::RemoteConfigs::load(&args.get_remote_config_path())?;
+        for name in &args.upload {
+            dests.push(funcs::opendal::Destination::new(
+                name.clone(),
+                remotes.get(name)?,
+            )?);
+        }
+    }
+    if let Some(ref key) = args.b2args {
+        dests.push(funcs::opendal::Destination::new(
+            "b2args".to_string(),
+            &structs::RemoteConfig::from_b2args(key)?,
+        )?);
+    }
+    let dests = funcs::opendal::Destinations::new(dests, args.retry);
+
+    let header_rules = structs::HeaderRules::load(&args.get_header_config_path())?;
+    statics::HEADER_RULES.get_or_init(|| header_rules);
 
-    let playlist_str = args.clone().contents()?;
+    statics::ENCODE_SEMAPHORE
+        .get_or_init(|| tokio::sync::Semaphore::new(args.encode_jobs as usize));
+    statics::DRIVE_REQUEST_SEMAPHORE
+        .get_or_init(|| tokio::sync::Semaphore::new(args.drive_requests as usize));
 
-    let vids = playlist_str
-        .lines()
-        .filter(parser::line_filter)
-        .map(parser::parse_line)
-        .collect::<Result<Vec<_>, Report>>()?;
+    let history = init::db::jobs::JobHistory::open().await?;
+
+    let profiles = Arc::new(structs::EncodeProfiles::load(
+        &args.get_profile_config_path(),
+    )?);
+
+    // Resolve every profile up front, so a typo fails before anything is downloaded
+    for (_, parser::PlaylistEntry { opts, .. }) in &vids {
+        profiles.get(args.get_profile_name(opts))?;
+    }
 
     let total_pb = if vids.len() > 1 {
         statics::MPB.add(funcs::progressbar::get_progbar(
@@ -76,43 +160,87 @@ async fn main() -> Result<(), Report> {
         indicatif::ProgressBar::hidden()
     };
 
-    for (i, (ty, x)) in vids.iter().progress_with(total_pb).enumerate() {
-        let i: Option<usize> = if vids.len() > 1 { Some(i) } else { None };
+    let vids_len = vids.len();
+    let job_permits = Arc::new(tokio::sync::Semaphore::new(args.jobs as usize));
+    let mut jobs = tokio::task::JoinSet::new();
 
-        for retry_num in 0..args.retry {
-            let run_result = match ty {
-                parser::DlTypes::YtDlp => main_funcs::handle_ytdlp(args, i, x, op.clone()).await,
-                parser::DlTypes::DirectLink => {
-                    main_funcs::handle_directdl::handle_direct(args, i, x, op.clone()).await
-                }
-                parser::DlTypes::GoogleDrive => {
-                    services::google_drive::handle_google_drive(args, i, x, op.clone()).await
-                }
-                parser::DlTypes::Dropbox => {
-                    services::dropbox::handler::handle_dropbox(args, i, x, op.clone()).await
-                }
-            };
+    for (pos, (i, entry)) in vids.into_iter().enumerate() {
+        let permit = job_permits.clone().acquire_owned().await?;
 
-            let is_inner_retry = {
-                match ty {
-                    parser::DlTypes::GoogleDrive => true,
-                    parser::DlTypes::Dropbox => true,
-                    _ => false,
-                }
-            };
+        let label = match vids_len > 1 && args.jobs > 1 {
+            true => format!("[{}/{vids_len}] ", pos + 1),
+            false => String::new(),
+        };
 
-            if run_result.is_ok() {
-                break;
-            }
+        let args = args.clone();
+        let profiles = profiles.clone();
+        let dests = dests.clone();
+        let history = history.clone();
+        let total_pb = total_pb.clone();
 
-            let line_pos_str = i.map_or("".to_string(), |x| format!(" at line {}", x + 1));
+        jobs.spawn(funcs::progressbar::JOB_LABEL.scope(label, async move {
+            let _permit = permit;
+            let profile = profiles.get(args.get_profile_name(&entry.opts))?;
 
-            tracing::warn!(
-                "Attempt #{retry_num}{line_pos_str} failed. Reason: {}",
-                run_result.unwrap_err()
-            );
-        }
+            process_entry(&args, i, &entry, profile, &history, &dests).await;
+            total_pb.inc(1);
+
+            Ok::<(), Report>(())
+        }));
+    }
+
+    while let Some(res) = jobs.join_next().await {
+        res??;
+    }
+
+    total_pb.finish();
+    dests.report();
+
+    let failed = statics::RUN_SUMMARY.report();
+    if failed > 0 {
+        color_eyre::eyre::bail!("{failed} sources failed");
     }
 
     Ok(())
 }
+
+async fn process_entry(
+    args: &init::DownloadOpts,
+    i: Option<usize>,
+    entry: &parser::PlaylistEntry,
+    profile: &structs::EncodeProfile,
+    history: &init::db::jobs::JobHistory,
+    dests: &funcs::opendal::Destinations,
+) {
+    let parser::PlaylistEntry { ty, opts, url: x } = entry;
+    let line_pos_str = i.map_or("".to_string(), |x| format!(" at line {}", x + 1));
+
+    let result = funcs::retry::with_retries(args.retry, &line_pos_str, || async {
+        match ty {
+            parser::DlTypes::YtDlp => {
+                main_funcs::handle_ytdlp(args, i, x, opts, profile, history, dests).await
+            }
+            parser::DlTypes::DirectLink => {
+                main_funcs::handle_directdl::handle_direct(
+                    args, i, x, opts, profile, history, dests,
+                )
+                .await
+            }
+            parser::DlTypes::GoogleDrive => {
+                services::google_drive::handle_google_drive(
+                    args, i, x, opts, profile, history, dests,
+                )
+                .await
+            }
+            parser::DlTypes::Dropbox => {
+                services::dropbox::handler::handle_dropbox(
+                    args, i, x, opts, profile, history, dests,
+                )
+                .await
+            }
+        }
+    })
+    .await;
+
+    statics::RUN_SUMMARY.record(x, &result);
+}
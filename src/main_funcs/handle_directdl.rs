@@ -1,36 +1,52 @@
 use color_eyre::eyre::ContextCompat;
-use futures_util::StreamExt;
 
 use crate::{
     funcs::{
-        ffmpeg::ffmpeg_transcode,
-        ffprobe::ffprobe_path,
-        opendal::{check_path_exists, copy_path_to_b2},
+        download::{download_segmented, fetch_headers},
+        ffmpeg::{ffmpeg_embed_metadata, TranscodeSource},
+        ffprobe::{ffprobe_height, ffprobe_input},
+        filename::detect_filename,
+        opendal::Destinations,
         progressbar::create_indefinite_spinner,
+        quality::transcode_checked,
     },
-    init::DownloadOpts,
+    init::{
+        db::jobs::{JobHistory, JobOutput, NewJob},
+        DownloadOpts,
+    },
+    parser::{DlTypes, LineOptions},
     statics::MPB,
+    structs::{EncodeProfile, MediaMetadata},
 };
 
 pub async fn handle_direct(
     args: &DownloadOpts,
     i: Option<usize>,
     url: &str,
-    op: Option<opendal::Operator>,
+    opts: &LineOptions,
+    profile: &EncodeProfile,
+    history: &JobHistory,
+    dests: &Destinations,
 ) -> Result<(), color_eyre::eyre::Error> {
-    let response = reqwest::get(url).await?;
-    let content_disposition_str = response
-        .headers()
-        .get(reqwest::header::CONTENT_DISPOSITION)
-        .and_then(|cd| cd.to_str().ok())
-        .unwrap();
+    if !args.force && history.is_done(&DlTypes::DirectLink, url).await? {
+        tracing::info!("Skipping {url}, already processed");
+        return Ok(());
+    }
 
-    let content_disposition =
-        &content_disposition::parse_content_disposition(content_disposition_str)
-            .filename_full()
-            .unwrap();
+    let settings = args.get_request_settings(url, opts);
+    let client = settings.client()?;
+
+    let response = fetch_headers(&client, url).await?;
+    let filename = detect_filename(&response);
+    let modified = response
+        .headers()
+        .get(reqwest::header::LAST_MODIFIED)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| chrono::DateTime::parse_from_rfc2822(x).ok())
+        .map(|x| x.to_utc());
+    drop(response);
 
-    let filepath = std::path::Path::new(content_disposition);
+    let filepath = std::path::Path::new(&filename);
 
     let title = filepath
         .file_stem()
@@ -38,13 +54,17 @@ pub async fn handle_direct(
         .wrap_err("File stem somehow ends with '..'")?;
 
     let id = title;
-    let _thumbnail = ""; // TODO: Find out how to implement thumbnail in ffmpeg
     let ext = filepath
         .extension()
         .and_then(|x| x.to_str())
         .unwrap_or("mp4");
+    let stem = match &opts.name {
+        Some(name) => name.clone(),
+        None => format!("{title}_[{id}]"),
+    };
     let out_name = format!(
-        "{idxstr}{title}_[{id}].{ext}",
+        "{idxstr}{stem}.{ext}",
+        ext = profile.output_ext(ext),
         idxstr = if !args.no_index_filename {
             match i {
                 Some(index) => format!("{:05}_", index),
@@ -64,81 +84,88 @@ pub async fn handle_direct(
         },
     );
 
-    let output_path = match args.target_dir {
-        Some(ref x) => x.join(out_name),
-        None => std::env::current_dir()?.join(out_name),
-    };
-
-    if let Some(op) = &op {
-        if check_path_exists(&output_path, op).await? {
-            tracing::warn!("File already exists on remote");
-            return Ok(());
-        }
-    };
-
-    let pb = create_indefinite_spinner(MPB.clone(), format!("Fetching {id}"))?;
-
-    let source = if args.download_first {
-        let temp_encode_path = output_path.with_file_name(format!(
-            "{}_temp{}",
-            output_path.file_stem().unwrap().to_string_lossy(),
-            output_path
-                .extension()
-                .map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()))
-        ));
-
-        let file = tokio::fs::File::create(&temp_encode_path).await?;
-
-        let path = tempfile::TempPath::from_path(&temp_encode_path);
-
-        let pb = MPB.add(crate::funcs::progressbar::get_progbar(
-            response.content_length().unwrap(), // 'Unwrap' should be fine. If body is None, it'll throw early.
-            crate::consts::SUB_BAR_FMT_MSG,
-            crate::consts::MAIN_BAR_CHARSET,
-        )?);
-
-        let mut res_body = response.bytes_stream();
-
-        let mut wrapped_file = pb.wrap_async_write(file);
-        tracing::trace!("Downloading to {}", temp_encode_path.to_string_lossy());
+    let output_path = args.get_output_dir(opts)?.join(out_name);
+
+    let job_id = history
+        .start(NewJob {
+            source_type: &DlTypes::DirectLink,
+            source_id: url,
+            source_url: url,
+            index: i,
+            title,
+            output_path: &output_path,
+            profile: args.get_profile_name(opts),
+            opts,
+        })
+        .await?;
+
+    let result = async {
+        let pb = create_indefinite_spinner(MPB.clone(), format!("Fetching {id}"))?;
+
+        // Credentials can't be handed to ffmpeg safely, so those sources are downloaded first
+        let source = if args.download_first || args.connections > 1 || settings.has_credentials() {
+            let temp_encode_path = output_path.with_file_name(format!(
+                "{}_temp{}",
+                output_path.file_stem().unwrap().to_string_lossy(),
+                output_path
+                    .extension()
+                    .map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()))
+            ));
+
+            // Kept on failure, so the next attempt can resume it unless it was segmented
+            download_segmented(
+                &client,
+                url,
+                &temp_encode_path,
+                args.connections as usize,
+                &format!("Downloading {title}"),
+            )
+            .await?;
+
+            tempfile::TempPath::from_path(&temp_encode_path)
+        } else {
+            tempfile::TempPath::from_path(url)
+        };
 
-        while let Some(c) = res_body.next().await {
-            let c = c?;
-
-            tokio::io::copy(&mut c.as_ref(), &mut wrapped_file).await?;
-        }
+        let res = ffprobe_input(&source, &settings.ffmpeg_input(&source.to_string_lossy()))?;
 
         pb.finish_and_clear();
 
-        path
-    } else {
-        tempfile::TempPath::from_path(id)
-    };
-
-    let res = ffprobe_path(&source)?;
-
-    pb.finish_and_clear();
-
-    let video_stream = res
-        .streams
-        .iter()
-        .find(|x| x.codec_type == Some("video".to_string()))
-        .unwrap();
-
-    let res = video_stream.height.unwrap();
+        let label = match ffprobe_height(&res) {
+            Some(res) => format!("{title} ({res})"),
+            None => title.to_string(),
+        };
+
+        let quality = transcode_checked(
+            args,
+            &TranscodeSource::single(source.to_string_lossy()),
+            &output_path,
+            profile,
+            opts,
+            &settings,
+            &label,
+        )
+        .await?;
+
+        let metadata = MediaMetadata::from_file(&filename, modified);
+        if let Err(e) = ffmpeg_embed_metadata(&output_path, &metadata, opts).await {
+            tracing::warn!("Failed to embed metadata into {title}: {e}");
+        }
 
-    ffmpeg_transcode(
-        &source.to_string_lossy(),
-        &output_path,
-        format!("{title} ({res})").as_str(),
-    )?;
+        let mut output = JobOutput::from_path(&output_path).await?;
+        output.quality = quality;
 
-    if let Some(op) = &op {
-        copy_path_to_b2(&output_path, op).await?;
-        if !args.skip_video_delete {
+        // Only counts as done once every destination has the file
+        output.remote_path = dests.upload(&output_path, output.size, &output.md5).await?;
+        if output.remote_path.is_some() && !args.skip_video_delete {
             std::fs::remove_file(&output_path)?;
         }
-    };
 
-    Ok(())
+        Ok(output)
+    }
+    .await;
+
+    history.finish(job_id, &result).await?;
+
+    result.map(|_| ())
 }
@@ -1,51 +1,316 @@
-use color_eyre::eyre::{ContextCompat, Error};
-use futures_util::StreamExt;
+use std::collections::HashSet;
+
+use color_eyre::eyre::{Context, ContextCompat, Error};
+use indicatif::ProgressIterator;
+use serde::Deserialize;
 
 use crate::{
+    consts,
     funcs::{
-        ffmpeg::ffmpeg_transcode,
-        opendal::{check_path_exists, copy_path_to_b2},
-        progressbar::create_indefinite_spinner,
+        download::{download_resumable, download_thumbnail},
+        ffmpeg::{ffmpeg_embed_metadata, TranscodeSource},
+        opendal::Destinations,
+        progressbar::{create_indefinite_spinner, get_progbar},
+        quality::transcode_checked,
+        retry::with_retries,
+        subtitles::{download_subtitles, persist_sidecars, select_subtitles, subtitle_codec},
+        ytdlp::{clear_indices, is_playlist, playlist_entries, SelectedFormats, YtDlpError},
+    },
+    init::{
+        db::jobs::{JobHistory, JobOutput, NewJob},
+        DownloadOpts,
     },
-    init::DownloadOpts,
-    statics::MPB,
+    parser::{DlTypes, LineOptions},
+    statics::{MPB, RUN_SUMMARY},
+    structs::{EncodeProfile, MediaMetadata},
 };
 
+/// yt-dlp invocation for the url, with cookies, request settings and format selection applied
+struct YtDlpCommand {
+    ytdl: youtube_dl::YoutubeDl,
+    /// Config file holding the headers and proxy, deleted once dropped
+    _config: Option<tempfile::NamedTempFile>,
+}
+
+impl YtDlpCommand {
+    async fn run(&self) -> Result<serde_json::Value, youtube_dl::Error> {
+        self.ytdl.run_raw_async().await
+    }
+}
+
+fn ytdlp_command(
+    args: &DownloadOpts,
+    url: &str,
+    opts: &LineOptions,
+) -> Result<YtDlpCommand, Error> {
+    let mut ytdl = youtube_dl::YoutubeDl::new(url);
+    ytdl.youtube_dl_path(args.yt_dlp.clone().unwrap_or("yt-dlp".into()));
+    if let Some(cookies) = args.get_cookie_path(url)? {
+        ytdl.cookies(cookies.canonicalize()?.to_string_lossy());
+    }
+    let config = args
+        .get_request_settings(url, opts)
+        .apply_ytdlp(&mut ytdl)?;
+    ytdl.format(args.get_format(opts));
+    if let Some(sort) = args.get_format_sort() {
+        ytdl.extra_arg("-S").extra_arg(sort);
+    }
+
+    Ok(YtDlpCommand {
+        ytdl,
+        _config: config,
+    })
+}
+
+/// Same, but only listing the entries of playlists, channels and tabs
+fn ytdlp_list_command(
+    args: &DownloadOpts,
+    url: &str,
+    opts: &LineOptions,
+) -> Result<YtDlpCommand, Error> {
+    let mut cmd = ytdlp_command(args, url, opts)?;
+    cmd.ytdl.flat_playlist(true);
+    if let Some(items) = &args.playlist_items {
+        cmd.ytdl.extra_arg("--playlist-items").extra_arg(items);
+    }
+
+    Ok(cmd)
+}
+
+/// Why a playlist entry uploaded on the date is left out by --date-after and --date-before
+fn date_skip_reason(args: &DownloadOpts, date: Option<chrono::NaiveDate>) -> Option<String> {
+    let date = date?;
+    match (args.date_after, args.date_before) {
+        (Some(after), _) if date < after => Some(format!("uploaded {date}, before {after}")),
+        (_, Some(before)) if date > before => Some(format!("uploaded {date}, after {before}")),
+        _ => None,
+    }
+}
+
 pub async fn handle_ytdlp(
     args: &DownloadOpts,
     i: Option<usize>,
     x: &str,
-    op: Option<opendal::Operator>,
+    opts: &LineOptions,
+    profile: &EncodeProfile,
+    history: &JobHistory,
+    dests: &Destinations,
 ) -> Result<(), Error> {
+    if !args.force && history.is_done(&DlTypes::YtDlp, x).await? {
+        tracing::info!("Skipping {x}, already processed");
+        return Ok(());
+    }
+
     let pb = create_indefinite_spinner(MPB.clone(), format!("Fetching {x}"))?;
 
-    let res = match youtube_dl::YoutubeDl::new(x)
-        .youtube_dl_path(args.yt_dlp.clone().unwrap_or("yt-dlp".into()))
-        .cookies(args.get_cookie_path().canonicalize()?.to_string_lossy())
+    // Flat listing still resolves single videos fully
+    let info = ytdlp_list_command(args, x, opts)?
         .run()
-    {
-        Ok(x) => x,
-        Err(e) => {
-            tracing::error!("# Error: {e}");
-            return Ok(());
+        .await
+        .map_err(YtDlpError::from)?;
+
+    pb.finish_and_clear();
+
+    if is_playlist(&info) {
+        return handle_playlist(args, x, &info, opts, profile, history, dests).await;
+    }
+
+    let video = FetchedVideo {
+        index: i,
+        url: x,
+        info,
+    };
+    handle_video(args, video, opts, profile, history, dests).await
+}
+
+/// Lists the playlist, channel or tab and processes every video in it as an entry of its own,
+/// named after its position in the playlist. Videos processed on earlier runs, or whose upload
+/// date is known to be filtered out, aren't fetched again.
+async fn handle_playlist(
+    args: &DownloadOpts,
+    playlist_url: &str,
+    info: &serde_json::Value,
+    opts: &LineOptions,
+    profile: &EncodeProfile,
+    history: &JobHistory,
+    dests: &Destinations,
+) -> Result<(), Error> {
+    let playlist_title = info["title"].as_str().unwrap_or(playlist_url);
+
+    let (mut items, mut nested) = playlist_entries(info);
+    let mut listed = HashSet::from([playlist_url.to_string()]);
+    while !nested.is_empty() {
+        let url = nested.remove(0);
+        if !listed.insert(url.clone()) {
+            continue;
+        }
+
+        let what = format!(" of listing {url}");
+        let tab = with_retries(args.retry, &what, || async {
+            let pb = create_indefinite_spinner(MPB.clone(), format!("Listing {url}"))?;
+            let tab = ytdlp_list_command(args, &url, opts)?
+                .run()
+                .await
+                .map_err(YtDlpError::from)?;
+            pb.finish_and_clear();
+
+            Ok(tab)
+        })
+        .await?;
+
+        let (mut tab_items, tab_nested) = playlist_entries(&tab);
+        clear_indices(&mut tab_items);
+        items.extend(tab_items);
+        nested.extend(tab_nested);
+    }
+
+    // Channel tabs can list the same video more than once
+    let mut seen = HashSet::new();
+    items.retain(|x| seen.insert(x.id.clone()));
+
+    let known = history.record_playlist(playlist_url, &items).await?;
+    RUN_SUMMARY.expand(playlist_url);
+
+    let mut pending = vec![];
+    for item in &items {
+        let Some(known) = known.get(&item.id) else {
+            continue;
+        };
+        if !args.force && history.is_done(&DlTypes::YtDlp, &item.url).await? {
+            tracing::debug!("Skipping {}, already processed", item.url);
+            continue;
+        }
+        // Dates are remembered rather than the outcome, so changed filters apply to every entry
+        if let Some(reason) = date_skip_reason(args, known.upload_date) {
+            tracing::info!("Skipping {}, {reason}", item.url);
+            continue;
+        }
+
+        pending.push((known.index, item));
+    }
+    if let Some(limit) = args.playlist_limit {
+        pending.truncate(limit);
+    }
+
+    tracing::info!(
+        "{playlist_title}: processing {} of {} entries",
+        pending.len(),
+        items.len()
+    );
+
+    // Every entry would end up with the same name
+    let opts = match &opts.name {
+        Some(_) => {
+            tracing::warn!("Ignoring name option for playlist {playlist_url}");
+            LineOptions {
+                name: None,
+                ..opts.clone()
+            }
         }
+        None => opts.clone(),
     };
 
-    pb.finish_and_clear();
+    let total_pb = if pending.len() > 1 {
+        MPB.add(get_progbar(
+            pending.len() as u64,
+            consts::MAIN_BAR_FMT,
+            consts::MAIN_BAR_CHARSET,
+        )?)
+    } else {
+        indicatif::ProgressBar::hidden()
+    };
+    total_pb.set_message(playlist_title.to_string());
+
+    // Entries are retried on their own, a failing one doesn't bring the playlist down
+    for (index, item) in pending.into_iter().progress_with(total_pb) {
+        let what = format!(" of {}", item.url);
+        let result = with_retries(args.retry, &what, || async {
+            let pb = create_indefinite_spinner(MPB.clone(), format!("Fetching {}", item.url))?;
+            let info = ytdlp_command(args, &item.url, &opts)?
+                .run()
+                .await
+                .map_err(YtDlpError::from)?;
+            pb.finish_and_clear();
+
+            // Flat listings mostly lack upload dates, so they're checked again here
+            let date = info["upload_date"]
+                .as_str()
+                .and_then(|x| chrono::NaiveDate::parse_from_str(x, "%Y%m%d").ok());
+            if let Some(date) = date {
+                history
+                    .record_upload_date(playlist_url, &item.id, date)
+                    .await?;
+            }
+            if let Some(reason) = date_skip_reason(args, date) {
+                tracing::info!("Skipping {}, {reason}", item.url);
+                return Ok(());
+            }
+
+            let video = FetchedVideo {
+                index: Some(index),
+                url: &item.url,
+                info,
+            };
+            handle_video(args, video, &opts, profile, history, dests).await
+        })
+        .await;
+
+        RUN_SUMMARY.record(&item.url, &result);
+    }
+
+    Ok(())
+}
+
+/// A video resolved by yt-dlp
+struct FetchedVideo<'a> {
+    /// Index in the file name: the line of the input, or the position in the playlist
+    index: Option<usize>,
+    url: &'a str,
+    info: serde_json::Value,
+}
+
+async fn handle_video(
+    args: &DownloadOpts,
+    video: FetchedVideo<'_>,
+    opts: &LineOptions,
+    profile: &EncodeProfile,
+    history: &JobHistory,
+    dests: &Destinations,
+) -> Result<(), Error> {
+    let FetchedVideo {
+        index: i,
+        url: x,
+        info,
+    } = video;
 
-    let video = res.into_single_video().wrap_err("Failed to get video")?;
-    let formats = video.formats.wrap_err("Failed to get formats")?;
-    let bestformat = formats.last().wrap_err("Failed to get bestformat")?;
+    let video = youtube_dl::SingleVideo::deserialize(&info).wrap_err("Failed to get video")?;
+    let formats = SelectedFormats::from_info(&info, &video).wrap_err("Failed to get formats")?;
+    let metadata = MediaMetadata::from_ytdlp(&video);
+    let selected_subs = select_subtitles(&video, &args.subs, args.auto_subs);
 
-    let res = bestformat.resolution.clone().unwrap_or("".to_string());
+    let res = video
+        .resolution
+        .clone()
+        .or(formats.video.resolution.clone())
+        .unwrap_or_default();
 
     let id = video.id;
     let title = video.title.wrap_err("Failed to get title")?;
-    let _thumbnail = video.thumbnail.wrap_err("Failed to get thumbnail")?; // TODO: Find out how to implement thumbnail in _thumbnailmpeg
-    let url = bestformat.url.clone().wrap_err("Failed to get url")?;
-    let ext = bestformat.ext.clone().wrap_err("Failed to get ext")?;
+    let thumbnail = video.thumbnail;
+    let url = formats.video_url().to_string();
+    // Combined formats get the extension yt-dlp would merge them into
+    let ext = video
+        .ext
+        .or(formats.video.ext.clone())
+        .wrap_err("Failed to get ext")?;
+    tracing::info!("Selected format {} for {title}", formats.format_id);
+    let stem = match &opts.name {
+        Some(name) => name.clone(),
+        None => format!("{title}_[{id}]"),
+    };
     let out_name = format!(
-        "{idxstr}{title}_[{id}].{ext}",
+        "{idxstr}{stem}.{ext}",
+        ext = profile.output_ext(&ext),
         idxstr = if !args.no_index_filename {
             match i {
                 Some(index) => format!("{:05}_", index),
@@ -64,69 +329,165 @@ pub async fn handle_ytdlp(
         },
     );
 
-    let output_path = match args.target_dir {
-        Some(ref x) => x.join(out_name),
-        None => std::env::current_dir()?.join(out_name),
-    };
+    let output_path = args.get_output_dir(opts)?.join(out_name);
 
-    if let Some(op) = &op {
-        if check_path_exists(&output_path, op).await? {
-            tracing::warn!("File already exists on remote");
-            return Ok(());
-        }
-    };
+    let job_id = history
+        .start(NewJob {
+            source_type: &DlTypes::YtDlp,
+            source_id: x,
+            source_url: x,
+            index: i,
+            title: &title,
+            output_path: &output_path,
+            profile: args.get_profile_name(opts),
+            opts,
+        })
+        .await?;
 
-    let source = if args.download_first {
-        let temp_encode_path = output_path.with_file_name(format!(
-            "{}_temp{}",
-            output_path.file_stem().unwrap().to_string_lossy(),
-            output_path
-                .extension()
-                .map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()))
-        ));
+    // The media url can be on another host than the page, with rules of its own
+    let settings = args.get_request_settings(&url, opts);
 
-        let file = tokio::fs::File::create(&temp_encode_path).await?;
+    let result = async {
+        // Credentials can't be handed to ffmpeg safely, so those sources are downloaded first
+        let (source, _temp_paths) = if args.download_first || settings.has_credentials() {
+            let temp_encode_path = output_path.with_file_name(format!(
+                "{}_temp{}",
+                output_path.file_stem().unwrap().to_string_lossy(),
+                output_path
+                    .extension()
+                    .map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()))
+            ));
 
-        let path = tempfile::TempPath::from_path(&temp_encode_path);
+            // Kept on failure, so the next attempt can resume it
+            download_resumable(
+                &settings.client()?,
+                &url,
+                &temp_encode_path,
+                &format!("Downloading {title}"),
+            )
+            .await?;
+            let mut temp_paths = vec![tempfile::TempPath::from_path(&temp_encode_path)];
 
-        let response = reqwest::get(url).await?;
+            let audio = match (formats.audio_url(), &formats.audio) {
+                (Some(audio_url), Some(audio)) => {
+                    let temp_audio_path = output_path.with_file_name(format!(
+                        "{}_temp_audio.{}",
+                        output_path.file_stem().unwrap().to_string_lossy(),
+                        audio.ext.as_deref().unwrap_or("m4a")
+                    ));
 
-        let pb = MPB.add(crate::funcs::progressbar::get_progbar(
-            response.content_length().unwrap(), // 'Unwrap' should be fine. If body is None, it'll throw early.
-            crate::consts::SUB_BAR_FMT_MSG,
-            crate::consts::MAIN_BAR_CHARSET,
-        )?);
+                    download_resumable(
+                        &args.get_request_settings(audio_url, opts).client()?,
+                        audio_url,
+                        &temp_audio_path,
+                        &format!("Downloading {title} audio"),
+                    )
+                    .await?;
+                    temp_paths.push(tempfile::TempPath::from_path(&temp_audio_path));
 
-        let mut res_body = response.bytes_stream();
+                    Some(temp_audio_path.to_string_lossy().into_owned())
+                }
+                _ => None,
+            };
 
-        let mut wrapped_file = pb.wrap_async_write(file);
-        tracing::trace!("Downloading to {}", temp_encode_path.to_string_lossy());
+            let source = TranscodeSource {
+                video: temp_encode_path.to_string_lossy().into_owned(),
+                audio,
+            };
+            (source, temp_paths)
+        } else {
+            let source = TranscodeSource {
+                video: url.clone(),
+                audio: formats.audio_url().map(str::to_string),
+            };
+            (source, vec![])
+        };
+
+        let quality = transcode_checked(
+            args,
+            &source,
+            &output_path,
+            profile,
+            opts,
+            &settings,
+            format!("{title} ({res})").as_str(),
+        )
+        .await?;
 
-        while let Some(c) = res_body.next().await {
-            let c = c?;
+        let cover = match &thumbnail {
+            Some(x) => download_thumbnail(
+                &args.get_request_settings(x, opts).client()?,
+                x,
+                &output_path,
+            )
+            .await
+            .inspect_err(|e| tracing::warn!("Failed to download thumbnail of {title}: {e}"))
+            .ok(),
+            None => None,
+        };
+        let subtitles = download_subtitles(&settings.client()?, selected_subs, &output_path).await;
+        let (subtitles, mut sidecars) = match subtitle_codec(&output_path) {
+            Some(_) => (subtitles, vec![]),
+            None => (vec![], subtitles),
+        };
 
-            tokio::io::copy(&mut c.as_ref(), &mut wrapped_file).await?;
+        let mut metadata = MediaMetadata {
+            subtitles: subtitles.iter().map(|x| x.stream()).collect(),
+            cover: cover.as_ref().map(|x| x.to_path_buf()),
+            ..metadata.clone()
+        };
+        if let Err(e) = ffmpeg_embed_metadata(&output_path, &metadata, opts).await {
+            match subtitles.is_empty() {
+                true => tracing::warn!("Failed to embed metadata into {title}: {e}"),
+                // Selected subtitles mustn't get lost, so they're kept next to the video instead
+                false => {
+                    tracing::warn!(
+                        "Failed to embed subtitles into {title}, keeping them as files: {e}"
+                    );
+                    metadata.subtitles.clear();
+                    sidecars = subtitles;
+                    if let Err(e) = ffmpeg_embed_metadata(&output_path, &metadata, opts).await {
+                        tracing::warn!("Failed to embed metadata into {title}: {e}");
+                    }
+                }
+            }
         }
 
-        pb.finish_and_clear();
+        let mut output = JobOutput::from_path(&output_path).await?;
+        output.quality = quality;
 
-        path
-    } else {
-        tempfile::TempPath::from_path(url)
-    };
-
-    ffmpeg_transcode(
-        &source.to_string_lossy(),
-        &output_path,
-        format!("{title} ({res})").as_str(),
-    )?;
+        if !sidecars.is_empty() && (opts.start.is_some() || opts.end.is_some()) {
+            tracing::warn!("Subtitle files of {title} aren't trimmed with start and end");
+        }
+        // Subtitle files are extras, one failing to upload is kept locally instead of failing
+        // the job, and nothing is deleted before the video is uploaded
+        let mut uploaded_sidecars = vec![];
+        for sidecar in persist_sidecars(sidecars)? {
+            let sidecar_output = JobOutput::from_path(&sidecar).await?;
+            match dests
+                .upload(&sidecar, sidecar_output.size, &sidecar_output.md5)
+                .await
+            {
+                Ok(Some(_)) => uploaded_sidecars.push(sidecar),
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Failed to upload {}, keeping it: {e}", sidecar.display()),
+            }
+        }
 
-    if let Some(op) = &op {
-        copy_path_to_b2(&output_path, op).await?;
-        if !args.skip_video_delete {
+        // Only counts as done once every destination has the file
+        output.remote_path = dests.upload(&output_path, output.size, &output.md5).await?;
+        if output.remote_path.is_some() && !args.skip_video_delete {
             std::fs::remove_file(&output_path)?;
+            for sidecar in uploaded_sidecars {
+                std::fs::remove_file(&sidecar)?;
+            }
         }
-    };
 
-    Ok(())
+        Ok(output)
+    }
+    .await;
+
+    history.finish(job_id, &result).await?;
+
+    result.map(|_| ())
 }
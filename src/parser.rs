@@ -1,7 +1,12 @@
+use std::path::{Component, PathBuf};
 use std::str::FromStr;
 
-use nom::character::complete::multispace0;
-use nom::combinator::opt;
+use color_eyre::eyre::{bail, eyre, Report};
+use nom::branch::alt;
+use nom::bytes::complete::{escaped_transform, tag, take_while1};
+use nom::character::complete::{multispace0, space0, space1};
+use nom::combinator::{map, opt, rest, value};
+use nom::multi::many0;
 use nom::{
     bytes::complete::is_not,
     character::complete::char,
@@ -11,50 +16,450 @@ use nom::{
 use strum::EnumString;
 
 // List of websites that accepts a URL string
-#[derive(Debug, PartialEq, EnumString)]
+#[derive(Debug, Clone, PartialEq, EnumString, strum::Display)]
 pub enum DlTypes {
-    #[strum(ascii_case_insensitive, serialize = "yt-dlp", serialize = "yt-dl")]
+    #[strum(ascii_case_insensitive, to_string = "yt-dlp", serialize = "yt-dl")]
     YtDlp,
-    #[strum(ascii_case_insensitive, serialize = "direct", serialize = "dl")]
+    #[strum(ascii_case_insensitive, to_string = "direct", serialize = "dl")]
     DirectLink,
     #[strum(
         ascii_case_insensitive,
-        serialize = "google-drive",
-        serialize = "gdrive"
+        to_string = "gdrive",
+        serialize = "google-drive"
     )]
     GoogleDrive,
-    #[strum(ascii_case_insensitive, serialize = "dropbox")]
+    #[strum(ascii_case_insensitive, to_string = "dropbox")]
     Dropbox,
 }
 
-fn nom_parse_line(line: &str) -> IResult<&str, (&str, &str)> {
+/// Per-entry overrides written inside the type brackets,
+/// e.g. `#[yt-dlp profile=av1 name="Lecture 3" start=00:01:00]: url`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineOptions {
+    /// Output file name, without extension
+    pub name: Option<String>,
+    /// Encoding profile name
+    pub profile: Option<String>,
+    /// Subdirectory inside the target directory
+    pub dir: Option<PathBuf>,
+    /// Trim start, in ffmpeg time duration syntax
+    pub start: Option<String>,
+    /// Trim end, in ffmpeg time duration syntax
+    pub end: Option<String>,
+    /// Copy streams as-is instead of encoding with the profile
+    pub skip_transcode: bool,
+    /// Extra HTTP headers, overriding global and per-host ones with the same name
+    pub headers: Vec<(String, String)>,
+    /// yt-dlp format selector, overriding --format
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaylistEntry {
+    pub ty: DlTypes,
+    pub opts: LineOptions,
+    pub url: String,
+}
+
+impl std::fmt::Display for PlaylistEntry {
+    /// Writes the entry back in playlist syntax
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let quote = |v: &str| format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\""));
+        let opts = &self.opts;
+
+        write!(f, "#[{}", self.ty)?;
+        if let Some(x) = &opts.name {
+            write!(f, " name={}", quote(x))?;
+        }
+        if let Some(x) = &opts.profile {
+            write!(f, " profile={}", quote(x))?;
+        }
+        if let Some(x) = &opts.dir {
+            write!(f, " dir={}", quote(&x.to_string_lossy()))?;
+        }
+        if let Some(x) = &opts.start {
+            write!(f, " start={x}")?;
+        }
+        if let Some(x) = &opts.end {
+            write!(f, " end={x}")?;
+        }
+        if opts.skip_transcode {
+            write!(f, " skip-transcode")?;
+        }
+        for (name, val) in &opts.headers {
+            write!(f, " header={}", quote(&format!("{name}: {val}")))?;
+        }
+        if let Some(x) = &opts.format {
+            write!(f, " format={}", quote(x))?;
+        }
+        write!(f, "]: {}", self.url)
+    }
+}
+
+/// Seconds of an ffmpeg time duration like `01:02:03.5`, `02:03` or `123.5`
+pub fn parse_time(s: &str) -> Option<f64> {
+    s.split(':')
+        .try_fold(0.0, |acc, x| Some(acc * 60.0 + x.parse::<f64>().ok()?))
+}
+
+/// Bytes of a size like `500M`, `1.5GiB` or `1024`. Suffixes are powers of 1024.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|x: char| !x.is_ascii_digit() && x != '.')
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+
+    let num = num
+        .parse::<f64>()
+        .map_err(|_| format!("'{s}' is not a size, expected e.g. 500M"))?;
+    let exp = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 1,
+        "M" | "MB" | "MIB" => 2,
+        "G" | "GB" | "GIB" => 3,
+        "T" | "TB" | "TIB" => 4,
+        _ => return Err(format!("unknown size unit in '{s}'")),
+    };
+
+    Ok((num * 1024f64.powi(exp)) as u64)
+}
+
+/// Parses a `Name: Value` HTTP header
+pub fn parse_header(s: &str) -> Result<(String, String), String> {
+    let (name, val) = s
+        .split_once(':')
+        .ok_or_else(|| format!("'{s}' is not a header, expected 'Name: Value'"))?;
+    let (name, val) = (name.trim(), val.trim());
+
+    reqwest::header::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("'{name}' is not a valid header name"))?;
+    reqwest::header::HeaderValue::from_str(val)
+        .map_err(|_| format!("invalid value for header '{name}'"))?;
+
+    Ok((name.to_string(), val.to_string()))
+}
+
+type RawOption<'a> = (&'a str, Option<String>);
+
+fn nom_parse_option_value(input: &str) -> IResult<&str, String> {
+    alt((
+        delimited(
+            char('"'),
+            opt(escaped_transform(
+                is_not("\\\""),
+                '\\',
+                alt((value("\\", tag("\\")), value("\"", tag("\"")))),
+            )),
+            char('"'),
+        ),
+        opt(map(is_not(" \t]\""), str::to_string)),
+    ))(input)
+    .map(|(i, v)| (i, v.unwrap_or_default()))
+}
+
+fn nom_parse_option(input: &str) -> IResult<&str, RawOption<'_>> {
+    pair(
+        take_while1(|c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        opt(preceded(char('='), nom_parse_option_value)),
+    )(input)
+}
+
+fn nom_parse_line(line: &str) -> IResult<&str, ((&str, Vec<RawOption<'_>>), &str)> {
     // Parse lines like this:
-    // #[(TYPE)]: (url)
-    // where TYPE is a alphanumeric string
+    // #[(TYPE) (KEY)=(VALUE) ...]: (url)
+    // where TYPE is a alphanumeric string, and VALUE may be double quoted
     preceded(
         char('#'),
         pair(
-            delimited(char('['), is_not("]"), char(']')),
+            delimited(
+                char('['),
+                pair(
+                    preceded(space0, is_not(" \t]")),
+                    many0(preceded(space1, nom_parse_option)),
+                ),
+                preceded(space0, char(']')),
+            ),
             preceded(
                 multispace0,
-                preceded(opt(char(':')), preceded(multispace0, is_not("\n"))),
+                preceded(opt(char(':')), preceded(multispace0, rest)),
             ),
         ),
     )(line)
 }
 
-pub fn line_filter(line: &&str) -> bool {
-    !line.is_empty() && !line.starts_with(r#"//"#)
+pub fn line_filter(line: &(usize, &str)) -> bool {
+    !line.1.is_empty() && !line.1.starts_with(r#"//"#)
+}
+
+fn column_of(line: &str, part: &str) -> usize {
+    part.as_ptr() as usize - line.as_ptr() as usize + 1
 }
 
-pub fn parse_line(line: &str) -> Result<(DlTypes, &str), color_eyre::eyre::Report> {
+fn parse_options(line: &str, raw_opts: Vec<RawOption>) -> Result<LineOptions, String> {
+    let mut opts = LineOptions::default();
+
+    for (key, val) in raw_opts {
+        let col = column_of(line, key);
+        let required = |val: Option<String>| match val {
+            Some(v) if !v.is_empty() => Ok(v),
+            _ => Err(format!("column {col}: option '{key}' requires a value")),
+        };
+
+        match key {
+            "name" => {
+                let name = required(val)?;
+                opts.name =
+                    Some(crate::funcs::filename::sanitize(&name).ok_or_else(|| {
+                        format!("column {col}: '{name}' is not a valid file name")
+                    })?)
+            }
+            "profile" => opts.profile = Some(required(val)?),
+            "format" => opts.format = Some(required(val)?),
+            "dir" => {
+                let dir = PathBuf::from(required(val)?);
+                // The directory is joined onto the target directory, so it must stay inside it
+                if !dir
+                    .components()
+                    .all(|x| matches!(x, Component::Normal(_) | Component::CurDir))
+                {
+                    return Err(format!(
+                        "column {col}: '{}' must be a relative path without '..'",
+                        dir.display()
+                    ));
+                }
+                opts.dir = Some(dir)
+            }
+            "start" | "end" => {
+                let time = required(val)?;
+                if !time
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || c == ':' || c == '.')
+                {
+                    return Err(format!(
+                        "column {col}: '{time}' is not a valid time for '{key}'"
+                    ));
+                }
+
+                if key == "start" {
+                    opts.start = Some(time);
+                } else {
+                    opts.end = Some(time);
+                }
+            }
+            "skip-transcode" | "skip_transcode" => {
+                opts.skip_transcode = match val.as_deref() {
+                    None | Some("true") | Some("yes") | Some("1") => true,
+                    Some("false") | Some("no") | Some("0") => false,
+                    Some(other) => {
+                        return Err(format!(
+                            "column {col}: '{other}' is not a valid value for '{key}'"
+                        ))
+                    }
+                }
+            }
+            "header" => opts
+                .headers
+                .push(parse_header(&required(val)?).map_err(|e| format!("column {col}: {e}"))?),
+            other => return Err(format!("column {col}: unknown option '{other}'")),
+        }
+    }
+
+    Ok(opts)
+}
+
+pub fn parse_line((line_idx, line): (usize, &str)) -> Result<PlaylistEntry, Report> {
+    let line_num = line_idx + 1;
+
     if !line.starts_with("#[") {
-        return Ok((DlTypes::YtDlp, line));
+        return Ok(PlaylistEntry {
+            ty: DlTypes::YtDlp,
+            opts: LineOptions::default(),
+            url: line.trim().to_string(),
+        });
     }
 
-    let (ty, url) = nom_parse_line(line)
-        .map_err(|e| color_eyre::eyre::eyre!(e.to_owned()))?
-        .1;
+    let ((ty, raw_opts), url) = match nom_parse_line(line) {
+        Ok((_, parsed)) => parsed,
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => bail!(
+            "Failed to parse line {line_num}, column {}: unexpected input {:?}",
+            column_of(line, e.input),
+            e.input
+        ),
+        Err(e) => bail!("Failed to parse line {line_num}: {e}"),
+    };
 
-    Ok((DlTypes::from_str(ty)?, url))
+    let ty = DlTypes::from_str(ty).map_err(|_| {
+        eyre!(
+            "Failed to parse line {line_num}, column {}: unknown source type '{ty}'",
+            column_of(line, ty)
+        )
+    })?;
+
+    let opts =
+        parse_options(line, raw_opts).map_err(|e| eyre!("Failed to parse line {line_num}, {e}"))?;
+
+    let url = url.trim();
+    if url.is_empty() {
+        bail!(
+            "Failed to parse line {line_num}, column {}: missing url",
+            line.len() + 1
+        );
+    }
+
+    Ok(PlaylistEntry {
+        ty,
+        opts,
+        url: url.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<PlaylistEntry, String> {
+        parse_line((0, line)).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn parses_options() {
+        let cases: &[(&str, LineOptions)] = &[
+            ("#[yt-dlp]: url", LineOptions::default()),
+            (
+                "#[yt-dlp name=\"Lecture 3\" profile=av1]: url",
+                LineOptions {
+                    name: Some("Lecture 3".into()),
+                    profile: Some("av1".into()),
+                    ..Default::default()
+                },
+            ),
+            (
+                r#"#[dl name="say \"hi\" now" format="a\\b"]: url"#,
+                LineOptions {
+                    name: Some(r#"say "hi" now"#.into()),
+                    format: Some(r"a\b".into()),
+                    ..Default::default()
+                },
+            ),
+            (
+                "#[dl  start=00:01:00\tend=90.5 skip-transcode ]url",
+                LineOptions {
+                    start: Some("00:01:00".into()),
+                    end: Some("90.5".into()),
+                    skip_transcode: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                "#[dl skip_transcode=no dir=\"a b/c\"]: url",
+                LineOptions {
+                    dir: Some("a b/c".into()),
+                    ..Default::default()
+                },
+            ),
+            (
+                "#[dl header=\"Referer: https://a.b/c\" format=\"bv*+ba\"]: url",
+                LineOptions {
+                    headers: vec![("Referer".into(), "https://a.b/c".into())],
+                    format: Some("bv*+ba".into()),
+                    ..Default::default()
+                },
+            ),
+            (
+                "#[dl name=\"../../etc/passwd\"]: url",
+                LineOptions {
+                    name: Some("passwd".into()),
+                    ..Default::default()
+                },
+            ),
+        ];
+
+        for (line, opts) in cases {
+            let entry = parse(line).unwrap_or_else(|e| panic!("{line}: {e}"));
+            assert_eq!(&entry.opts, opts, "{line}");
+            assert_eq!(entry.url, "url", "{line}");
+        }
+    }
+
+    #[test]
+    fn reports_error_positions() {
+        let cases = [
+            (
+                "#[nope]: url",
+                "line 1, column 3: unknown source type 'nope'",
+            ),
+            ("#[dl foo=1]: url", "line 1, column 6: unknown option 'foo'"),
+            (
+                "#[dl name=\"\"]: url",
+                "line 1, column 6: option 'name' requires a value",
+            ),
+            (
+                "#[dl name]: url",
+                "line 1, column 6: option 'name' requires a value",
+            ),
+            (
+                "#[dl name=a start=1m]: url",
+                "line 1, column 13: '1m' is not a valid time",
+            ),
+            (
+                "#[dl skip-transcode=maybe]: url",
+                "line 1, column 6: 'maybe' is not a valid",
+            ),
+            (
+                "#[dl header=nocolon]: url",
+                "line 1, column 6: 'nocolon' is not a header",
+            ),
+            (
+                "#[dl dir=../up]: url",
+                "line 1, column 6: '../up' must be a relative path",
+            ),
+            (
+                "#[dl dir=/abs]: url",
+                "line 1, column 6: '/abs' must be a relative path",
+            ),
+            (
+                "#[dl name=..]: url",
+                "line 1, column 6: '..' is not a valid file name",
+            ),
+            ("#[dl]: ", "line 1, column 8: missing url"),
+            (
+                "#[dl name=\"open]: url",
+                "line 1, column 11: unexpected input",
+            ),
+            ("#[dl name=a", "line 1, column 12: unexpected input"),
+        ];
+
+        for (line, expected) in cases {
+            let err = parse(line).expect_err(line);
+            assert!(err.contains(expected), "{line}: got '{err}'");
+        }
+    }
+
+    #[test]
+    fn plain_lines_are_yt_dlp() {
+        let entry = parse("  https://example.com/watch?v=1 ").unwrap();
+        assert_eq!(entry.ty, DlTypes::YtDlp);
+        assert_eq!(entry.url, "https://example.com/watch?v=1");
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let entry = PlaylistEntry {
+            ty: DlTypes::DirectLink,
+            opts: LineOptions {
+                name: Some(r#"a "quoted" name"#.into()),
+                profile: Some(r"back\slash".into()),
+                dir: Some("sub dir/x".into()),
+                start: Some("01:00".into()),
+                end: Some("02:00".into()),
+                skip_transcode: true,
+                headers: vec![("Cookie".into(), "a=b; c=d".into())],
+                format: Some("bv*".into()),
+            },
+            url: "https://example.com/a.mp4".into(),
+        };
+
+        assert_eq!(parse(&entry.to_string()).unwrap(), entry);
+    }
 }
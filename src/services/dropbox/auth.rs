@@ -1,16 +1,7 @@
-use std::path::PathBuf;
-
 use color_eyre::eyre::{bail, ContextCompat, Error};
 use dropbox_sdk::{
-    async_routes::{
-        files::{list_folder, list_folder_continue},
-        sharing::get_shared_link_metadata,
-        users::get_current_account,
-    },
     default_async_client::{NoauthDefaultClient, UserAuthDefaultClient},
-    files::{ListFolderArg, ListFolderContinueArg, SharedLink},
     oauth2::{Authorization, AuthorizeUrlBuilder, Oauth2Type, PkceCode},
-    sharing::GetSharedLinkMetadataArg,
 };
 use serde::{Deserialize, Serialize};
 
@@ -63,13 +54,6 @@ pub fn load_token() -> Result<Option<String>, Error> {
     Ok(Some(token_str))
 }
 
-fn present_user_url(url: &str) {
-    println!();
-    println!("Dropbox requires permissions to use Dropbox API.");
-    println!("Open the url in your browser and follow the instructions:");
-    println!("{}", url);
-}
-
 fn present_user_prompt(url: &str) -> String {
     r###"
 Dropbox requires permissions to use Dropbox API.
@@ -1,25 +1,33 @@
-use std::path::PathBuf;
-
 use color_eyre::eyre::ContextCompat;
 use indicatif::ProgressIterator;
 
 use crate::{
     consts,
     funcs::{
-        ffmpeg::ffmpeg_transcode,
-        ffprobe::ffprobe_path,
-        opendal::{check_path_exists, copy_path_to_b2},
+        ffmpeg::{ffmpeg_embed_metadata, TranscodeSource},
+        ffprobe::{ffprobe_height, ffprobe_path},
+        http::RequestSettings,
+        opendal::Destinations,
         progressbar::{create_indefinite_spinner, get_progbar},
+        quality::transcode_checked,
+    },
+    init::{
+        db::jobs::{JobHistory, JobOutput, NewJob},
+        DownloadOpts,
     },
-    init::DownloadOpts,
+    parser::{DlTypes, LineOptions},
     statics::MPB,
+    structs::{EncodeProfile, MediaMetadata},
 };
 
 pub async fn handle_dropbox(
     args: &DownloadOpts,
     i: Option<usize>,
     shared_link: &str,
-    op: Option<opendal::Operator>,
+    opts: &LineOptions,
+    profile: &EncodeProfile,
+    history: &JobHistory,
+    dests: &Destinations,
 ) -> Result<(), color_eyre::eyre::Report> {
     let client = super::auth::get_async_client().await?;
 
@@ -41,7 +49,24 @@ pub async fn handle_dropbox(
 
     total_dropbox_pb.set_message("Dropbox Items");
 
-    for (url, path) in items.iter().progress_with(total_dropbox_pb) {
+    // Custom name only makes sense when the link resolves into a single file
+    let name_override = match (&opts.name, items.len()) {
+        (Some(name), 1) => Some(name.as_str()),
+        (Some(_), _) => {
+            tracing::warn!("Ignoring name option for Dropbox folder {shared_link}");
+            None
+        }
+        (None, _) => None,
+    };
+
+    for (url, path, modified) in items.iter().progress_with(total_dropbox_pb) {
+        let source_id = format!("{shared_link}{}", path.to_string_lossy());
+
+        if !args.force && history.is_done(&DlTypes::Dropbox, &source_id).await? {
+            tracing::info!("Skipping {source_id}, already processed");
+            continue;
+        }
+
         let url = if url.contains("dl=0") {
             url.replace("dl=0", "dl=1")
         } else if url.contains('?') {
@@ -50,15 +75,18 @@ pub async fn handle_dropbox(
             format!("{}?dl=1", url)
         };
 
-        let title = path
-            .file_stem()
-            .and_then(|x| x.to_str())
-            .wrap_err("File stem somehow ends with '..'")?;
+        let title = match name_override {
+            Some(name) => name,
+            None => path
+                .file_stem()
+                .and_then(|x| x.to_str())
+                .wrap_err("File stem somehow ends with '..'")?,
+        };
 
-        let _thumbnail = ""; // TODO: Find out how to implement thumbnail in ffmpeg
         let ext = path.extension().and_then(|x| x.to_str()).unwrap_or("mp4");
         let out_name = format!(
             "{idxstr}{title}.{ext}",
+            ext = profile.output_ext(ext),
             idxstr = if !args.no_index_filename {
                 match i {
                     Some(index) => format!("{:05}_", index),
@@ -78,77 +106,97 @@ pub async fn handle_dropbox(
             },
         );
 
-        let output_path = match args.target_dir {
-            Some(ref x) => x.join(out_name),
-            None => std::env::current_dir()?.join(out_name),
-        };
-
-        if let Some(op) = &op {
-            if check_path_exists(&output_path, op).await? {
-                tracing::warn!("File already exists on remote");
-                continue;
-            }
-        };
-
-        tracing::trace!("Getting {url}");
-
-        let source = if args.download_first {
-            let temp_encode_path = output_path.with_file_name(format!(
-                "{}_temp{}",
-                output_path.file_stem().unwrap().to_string_lossy(),
-                output_path
-                    .extension()
-                    .map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()))
-            ));
-
-            let path = tempfile::TempPath::from_path(temp_encode_path);
+        let output_path = args.get_output_dir(opts)?.join(out_name);
+
+        let job_id = history
+            .start(NewJob {
+                source_type: &DlTypes::Dropbox,
+                source_id: &source_id,
+                source_url: shared_link,
+                index: i,
+                title,
+                output_path: &output_path,
+                profile: args.get_profile_name(opts),
+                opts,
+            })
+            .await?;
+
+        let result = async {
+            tracing::trace!("Getting {url}");
+
+            let source = if args.download_first {
+                let temp_encode_path = output_path.with_file_name(format!(
+                    "{}_temp{}",
+                    output_path.file_stem().unwrap().to_string_lossy(),
+                    output_path
+                        .extension()
+                        .map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()))
+                ));
+
+                let path = tempfile::TempPath::from_path(temp_encode_path);
+
+                super::download_shared_file(&client, &url, &path).await?;
+
+                path
+            } else {
+                tempfile::TempPath::from_path(url)
+            };
 
-            super::download_shared_file(&client, &url, &path).await?;
+            let res: Option<i64> = {
+                let pb = create_indefinite_spinner(
+                    MPB.clone(),
+                    format!("Fetching {}", source.to_string_lossy()),
+                )?;
 
-            path
-        } else {
-            tempfile::TempPath::from_path(url)
-        };
+                let res = ffprobe_path(&source).ok().and_then(|x| ffprobe_height(&x));
 
-        let res: Option<i64> = {
-            let pb = create_indefinite_spinner(
-                MPB.clone(),
-                format!("Fetching {}", source.to_string_lossy()),
-            )?;
+                pb.finish_and_clear();
 
-            let res = if let Ok(x) = ffprobe_path(&source) {
-                let video_stream = x
-                    .streams
-                    .iter()
-                    .find(|x| x.codec_type == Some("video".to_string()))
-                    .unwrap();
-
-                Some(video_stream.height.unwrap())
-            } else {
-                None
+                res
             };
 
-            pb.finish_and_clear();
-
-            res
-        };
-
-        ffmpeg_transcode(
-            &source.to_string_lossy(),
-            &output_path,
-            format!(
-                "{title} ({})",
-                res.map(|x| x.to_string()).unwrap_or("".to_string())
+            let quality = transcode_checked(
+                args,
+                &TranscodeSource::single(source.to_string_lossy()),
+                &output_path,
+                profile,
+                opts,
+                // Headers are only configurable for direct and yt-dlp sources
+                &RequestSettings {
+                    proxy: args.proxy.clone(),
+                    ..Default::default()
+                },
+                &match res {
+                    Some(res) => format!("{title} ({res})"),
+                    None => title.to_string(),
+                },
             )
-            .as_str(),
-        )?;
+            .await?;
+
+            let modified = chrono::DateTime::parse_from_rfc3339(modified)
+                .ok()
+                .map(|x| x.to_utc());
+            let filename = path.file_name().unwrap_or_default().to_string_lossy();
+            let metadata = MediaMetadata::from_file(&filename, modified);
+            if let Err(e) = ffmpeg_embed_metadata(&output_path, &metadata, opts).await {
+                tracing::warn!("Failed to embed metadata into {title}: {e}");
+            }
+
+            let mut output = JobOutput::from_path(&output_path).await?;
+            output.quality = quality;
 
-        if let Some(op) = &op {
-            copy_path_to_b2(&output_path, op).await?;
-            if !args.skip_video_delete {
+            // Only counts as done once every destination has the file
+            output.remote_path = dests.upload(&output_path, output.size, &output.md5).await?;
+            if output.remote_path.is_some() && !args.skip_video_delete {
                 std::fs::remove_file(&output_path)?;
             }
-        };
+
+            Ok(output)
+        }
+        .await;
+
+        history.finish(job_id, &result).await?;
+        result?;
     }
 
     Ok(())
@@ -1,6 +1,4 @@
 pub mod auth;
-pub mod list_folder_iter;
-pub mod test;
 
 pub mod walker;
 pub use walker::walk_shared_link;
@@ -11,17 +11,18 @@ use dropbox_sdk::{
     sharing::GetSharedLinkMetadataArg,
 };
 
+/// Url, path and client modified time of every file behind the link
 #[async_recursion::async_recursion]
 pub async fn walk_shared_link(
     client: &UserAuthDefaultClient,
     shared_link: &str,
     path: Option<PathBuf>,
-) -> Result<Vec<(String, PathBuf)>, Error> {
+) -> Result<Vec<(String, PathBuf, String)>, Error> {
     use dropbox_sdk::files::Metadata;
     use dropbox_sdk::sharing::SharedLinkMetadata;
 
     let is_empty_path = path.is_none();
-    let root_path = path.unwrap_or(PathBuf::new());
+    let root_path = path.unwrap_or_default();
     let m_args = if !is_empty_path {
         GetSharedLinkMetadataArg::new(shared_link.to_string())
             .with_path(root_path.to_string_lossy().into_owned())
@@ -32,7 +33,7 @@ pub async fn walk_shared_link(
     let m = get_shared_link_metadata(client, &m_args).await?;
 
     let results = match m {
-        SharedLinkMetadata::File(f) => vec![(f.url, root_path)],
+        SharedLinkMetadata::File(f) => vec![(f.url, root_path, f.client_modified)],
         SharedLinkMetadata::Folder(f) => {
             let slink = SharedLink::new(f.url);
             let ls_arg = ListFolderArg::new("".to_string()).with_shared_link(slink);
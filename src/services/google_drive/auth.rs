@@ -7,14 +7,43 @@ use google_drive3::{
 };
 use serde::{Deserialize, Serialize};
 
-use color_eyre::Report;
+use color_eyre::{
+    eyre::{eyre, Context},
+    Report,
+};
+
+/// How the Google Drive token is obtained
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, strum::Display)]
+#[serde(rename_all = "kebab-case")]
+pub enum GdriveAuthFlow {
+    /// Browser sign-in redirecting to a local port
+    #[default]
+    #[strum(serialize = "installed app")]
+    Installed,
+    /// Browser sign-in on any device, pasting the redirect URL back, for headless machines
+    #[strum(serialize = "pasted code")]
+    #[serde(alias = "device")]
+    Headless,
+    /// JSON key of a service account
+    #[strum(serialize = "service account")]
+    ServiceAccount,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct GdriveCredentials {
+    #[serde(default)]
+    pub flow: GdriveAuthFlow,
+    #[serde(default)]
     pub id: String,
+    #[serde(default)]
     pub secret: String,
+    /// User the service account acts as, with domain-wide delegation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
 }
 
+const REDIRECT_URI: &str = "http://localhost:8420";
+
 struct AuthDelegate;
 
 impl yup_oauth2::authenticator_delegate::InstalledFlowDelegate for AuthDelegate {
@@ -37,6 +66,63 @@ async fn present_user_url(url: &str) -> Result<String, String> {
     Ok(String::new())
 }
 
+/// Redirects to the same local port as [`AuthDelegate`], which nothing listens on. The code
+/// gets read from the URL the browser ended up on, pasted into the terminal.
+struct HeadlessDelegate;
+
+impl yup_oauth2::authenticator_delegate::InstalledFlowDelegate for HeadlessDelegate {
+    fn redirect_uri(&self) -> Option<&str> {
+        Some(REDIRECT_URI)
+    }
+
+    fn present_user_url<'a>(
+        &'a self,
+        url: &'a str,
+        _need_code: bool,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<String, String>> + Send + 'a>>
+    {
+        Box::pin(async move {
+            use tokio::io::AsyncBufReadExt;
+
+            println!();
+            println!();
+            println!("Gdrive requires permissions to manage your files on Google Drive.");
+            println!("Open the url in a browser on any device and follow the instructions:");
+            println!("{}", url);
+            println!("The browser then fails to load a localhost page. Paste its URL here:");
+
+            let mut input = String::new();
+            tokio::io::BufReader::new(tokio::io::stdin())
+                .read_line(&mut input)
+                .await
+                .map_err(|e| format!("Failed to read the URL: {e}"))?;
+            parse_auth_code(&input)
+        })
+    }
+}
+
+/// Takes the code out of the URL the sign-in redirected to, or the code on its own
+fn parse_auth_code(input: &str) -> Result<String, String> {
+    let input = input.trim();
+    let Ok(url) = reqwest::Url::parse(input) else {
+        return match input.is_empty() {
+            true => Err("No URL was pasted".to_string()),
+            false => Ok(input.to_string()),
+        };
+    };
+
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.into_owned())
+    };
+    match (param("code"), param("error")) {
+        (Some(code), _) => Ok(code),
+        (None, Some(error)) => Err(format!("Sign-in failed: {error}")),
+        (None, None) => Err(format!("{input} has no code in it")),
+    }
+}
+
 fn get_token_path() -> std::path::PathBuf {
     crate::statics::PROJECT_DIR_PATH.join("gdrive-token.json")
 }
@@ -45,13 +131,12 @@ fn get_cred_path() -> std::path::PathBuf {
     crate::statics::PROJECT_DIR_PATH.join("gdrive-cred.json")
 }
 
-pub fn save_creds(id: &str, secret: &str) -> Result<(), Report> {
-    let creds = GdriveCredentials {
-        id: id.to_string(),
-        secret: secret.to_string(),
-    };
+fn get_service_account_path() -> std::path::PathBuf {
+    crate::statics::PROJECT_DIR_PATH.join("gdrive-service-account.json")
+}
 
-    std::fs::write(get_cred_path(), serde_json::to_string(&creds)?)?;
+pub fn save_creds(creds: &GdriveCredentials) -> Result<(), Report> {
+    crate::funcs::fs::write_private(&get_cred_path(), serde_json::to_string(creds)?.as_bytes())?;
 
     Ok(())
 }
@@ -60,26 +145,38 @@ pub fn load_creds() -> Result<GdriveCredentials, Report> {
     let creds_path = get_cred_path();
     if !creds_path.exists() {
         return Err(Report::msg(
-            "Gdrive credentials not found Please authenticate first with auth-gdrive subcommand",
+            "Gdrive credentials not found. Please authenticate first with authenticate google-drive subcommand",
         ));
     }
 
     let creds_str = std::fs::read_to_string(get_cred_path())?;
-    Ok(serde_json::from_str(&creds_str).expect("Failed to load Gdrive credentials"))
+    serde_json::from_str(&creds_str).wrap_err("Failed to load Gdrive credentials")
 }
 
 pub async fn get_auth(
     creds: Option<GdriveCredentials>,
 ) -> Result<Authenticator<HttpsConnector<HttpConnector>>, Report> {
-    let GdriveCredentials { id, secret } = creds.unwrap_or_else(|| load_creds().unwrap());
+    let creds = match creds {
+        Some(creds) => creds,
+        None => load_creds()?,
+    };
 
-    tracing::info!("Authenticating with Gdrive...");
+    build_auth(&creds, &get_service_account_path(), &get_token_path()).await
+}
+
+/// Signs in with the credentials, keeping the token in the file at `token_path`
+async fn build_auth(
+    creds: &GdriveCredentials,
+    service_account_path: &std::path::Path,
+    token_path: &std::path::Path,
+) -> Result<Authenticator<HttpsConnector<HttpConnector>>, Report> {
+    tracing::info!("Authenticating with Gdrive using {}...", creds.flow);
     let auth_secret = yup_oauth2::ApplicationSecret {
-        client_id: id,
-        client_secret: secret,
+        client_id: creds.id.clone(),
+        client_secret: creds.secret.clone(),
         token_uri: String::from("https://oauth2.googleapis.com/token"),
         auth_uri: String::from("https://accounts.google.com/o/oauth2/auth"),
-        redirect_uris: vec![String::from("urn:ietf:wg:oauth:2.0:oob")],
+        redirect_uris: vec![String::from(REDIRECT_URI)],
         project_id: None,
         client_email: None,
         auth_provider_x509_cert_url: Some(String::from(
@@ -88,15 +185,55 @@ pub async fn get_auth(
         client_x509_cert_url: None,
     };
 
-    yup_oauth2::InstalledFlowAuthenticator::builder(
-        auth_secret,
-        yup_oauth2::InstalledFlowReturnMethod::HTTPPortRedirect(8420),
-    )
-    .persist_tokens_to_disk(get_token_path())
-    .flow_delegate(Box::new(AuthDelegate))
-    .build()
-    .await
-    .map_err(|e| Report::msg(e.to_string()))
+    let auth = match creds.flow {
+        GdriveAuthFlow::Installed => {
+            yup_oauth2::InstalledFlowAuthenticator::builder(
+                auth_secret,
+                yup_oauth2::InstalledFlowReturnMethod::HTTPPortRedirect(8420),
+            )
+            .persist_tokens_to_disk(token_path)
+            .flow_delegate(Box::new(AuthDelegate))
+            .build()
+            .await
+        }
+        GdriveAuthFlow::Headless => {
+            yup_oauth2::InstalledFlowAuthenticator::builder(
+                auth_secret,
+                yup_oauth2::InstalledFlowReturnMethod::Interactive,
+            )
+            .persist_tokens_to_disk(token_path)
+            .flow_delegate(Box::new(HeadlessDelegate))
+            .build()
+            .await
+        }
+        GdriveAuthFlow::ServiceAccount => {
+            let key = yup_oauth2::read_service_account_key(service_account_path)
+                .await
+                .wrap_err("Failed to read Gdrive service account key")?;
+            let builder = yup_oauth2::ServiceAccountAuthenticator::builder(key)
+                .persist_tokens_to_disk(token_path);
+            match &creds.subject {
+                Some(subject) => builder.subject(subject.clone()).build().await,
+                None => builder.build().await,
+            }
+        }
+    }
+    .map_err(|e| Report::msg(e.to_string()))?;
+
+    // Fails here instead of on the first request when the refresh token got revoked or expired
+    auth.token(&[google_drive3::api::Scope::Full])
+        .await
+        .map_err(|e| {
+            eyre!(
+                "Failed to get a Gdrive token: {e}. Authenticate again with authenticate google-drive"
+            )
+        })?;
+    // Token files written by earlier versions may be readable by others
+    if token_path.exists() {
+        crate::funcs::fs::make_private(token_path)?;
+    }
+
+    Ok(auth)
 }
 
 pub async fn get_hub(
@@ -122,15 +259,82 @@ pub async fn get_hub(
     Ok(hub)
 }
 
-pub async fn authenticate(id: &str, secret: &str) -> Result<(), color_eyre::eyre::Report> {
-    save_creds(id, secret)?;
+/// Signs in with the credentials and, once that worked, saves them in place of an earlier
+/// sign-in. The service account key gets copied into the projects data folder.
+pub async fn authenticate(
+    creds: GdriveCredentials,
+    service_account_key: Option<&std::path::Path>,
+) -> Result<(), color_eyre::eyre::Report> {
+    // The earlier sign-in stays usable until the new one succeeded
+    let staged_token = get_token_path().with_extension("json.new");
+    let staged_key = get_service_account_path().with_extension("json.new");
+    let _ = std::fs::remove_file(&staged_token);
+
+    if let Some(key) = service_account_key {
+        yup_oauth2::read_service_account_key(key)
+            .await
+            .wrap_err_with(|| format!("{} is not a service account key", key.display()))?;
+        crate::funcs::fs::write_private(&staged_key, &std::fs::read(key)?)?;
+    }
 
-    let creds = load_creds()?;
-    let auth = get_auth(Some(creds)).await?;
-    let hub = get_hub(Some(auth)).await?;
+    let res = async {
+        let auth = build_auth(&creds, &staged_key, &staged_token).await?;
+        let hub = get_hub(Some(auth)).await?;
 
-    let about = hub.about().get().param("fields", "user").doit().await?;
-    tracing::info!("Drive Hub Functional: {:?}", about);
+        let about = hub.about().get().param("fields", "user").doit().await?;
+        tracing::info!("Drive Hub Functional: {:?}", about);
+
+        color_eyre::eyre::Ok(())
+    }
+    .await;
+    if let Err(e) = res {
+        let _ = std::fs::remove_file(&staged_token);
+        let _ = std::fs::remove_file(&staged_key);
+        return Err(e);
+    }
+
+    if service_account_key.is_some() {
+        std::fs::rename(&staged_key, get_service_account_path())?;
+    }
+    match staged_token.exists() {
+        true => std::fs::rename(&staged_token, get_token_path())?,
+        false if get_token_path().exists() => std::fs::remove_file(get_token_path())?,
+        false => (),
+    }
+    save_creds(&creds)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_codes() {
+        let cases = [
+            (
+                "http://localhost:8420/?code=4%2F0Ab_c&scope=https://www.googleapis.com/auth/drive\n",
+                Ok("4/0Ab_c"),
+            ),
+            ("  4/0Ab_c  ", Ok("4/0Ab_c")),
+            (
+                "http://localhost:8420/?error=access_denied",
+                Err("Sign-in failed: access_denied"),
+            ),
+            (
+                "http://localhost:8420/",
+                Err("http://localhost:8420/ has no code in it"),
+            ),
+            ("\n", Err("No URL was pasted")),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(
+                parse_auth_code(input),
+                expected.map(String::from).map_err(String::from),
+                "{input}"
+            );
+        }
+    }
+}
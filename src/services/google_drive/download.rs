@@ -1,38 +1,115 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::HashSet, path::PathBuf, sync::Arc};
 
-use color_eyre::eyre::{ContextCompat, Error};
+use color_eyre::eyre::{bail, eyre, ContextCompat, Error};
 use google_drive3::{
     hyper::body::Bytes, hyper_rustls::HttpsConnector,
     hyper_util::client::legacy::connect::HttpConnector, DriveHub,
 };
 
-use http_body_util::combinators::BoxBody;
-use indicatif::ProgressIterator;
+use futures_util::StreamExt;
+use http_body_util::{combinators::BoxBody, BodyExt};
 
 use crate::{
     consts,
     funcs::{
-        ffmpeg::ffmpeg_transcode,
-        opendal::{check_path_exists, copy_path_to_b2},
+        ffmpeg::{ffmpeg_embed_metadata, TranscodeSource},
+        http::RequestSettings,
+        opendal::Destinations,
         progressbar::get_progbar,
+        quality::transcode_checked,
     },
-    init::DownloadOpts,
+    init::{
+        db::jobs::{JobHistory, JobOutput, NewJob},
+        DownloadOpts,
+    },
+    parser::{DlTypes, LineOptions},
+    statics::RUN_SUMMARY,
+    structs::{EncodeProfile, MediaMetadata},
 };
 
 type Hub = DriveHub<HttpsConnector<HttpConnector>>;
 
+/// Redirects followed when downloading an export link
+const MAX_EXPORT_REDIRECTS: usize = 5;
+
+/// Downloads an export from its link, with the hub's client and token. The token is only sent
+/// to the link's own host, not to where it redirects.
+async fn get_body_from_export_link(
+    hub: &Hub,
+    link: &str,
+) -> Result<BoxBody<Bytes, google_drive3::hyper::Error>, Error> {
+    use google_drive3::hyper::{header, Request, Uri};
+
+    let token = hub
+        .auth
+        .get_token(&[google_drive3::api::Scope::Full.as_ref()])
+        .await
+        .map_err(|e| eyre!("Failed to get a Gdrive token: {e}"))?;
+    let first: Uri = link.parse()?;
+    let mut uri = first.clone();
+
+    for _ in 0..=MAX_EXPORT_REDIRECTS {
+        let mut req = Request::get(uri.clone());
+        if let Some(token) = token.as_ref().filter(|_| uri.host() == first.host()) {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+
+        let res = hub
+            .client
+            .request(req.body(google_drive3::common::to_body::<String>(None))?)
+            .await?;
+
+        if res.status().is_redirection() {
+            let location = res
+                .headers()
+                .get(header::LOCATION)
+                .wrap_err("Export redirected without a location")?
+                .to_str()?;
+            uri = location.parse()?;
+            continue;
+        }
+        if !res.status().is_success() {
+            bail!("Exporting failed with {}", res.status());
+        }
+
+        return Ok(res.into_body().boxed());
+    }
+
+    bail!("Export link redirected more than {MAX_EXPORT_REDIRECTS} times")
+}
+
+/// Content of the file, or its export into the MIME type for Google-native files
 pub async fn get_body_from_id(
     hub: &Hub,
-    file_id: &str,
+    file: &super::node::FileInfo,
 ) -> Result<BoxBody<Bytes, google_drive3::hyper::Error>, Error> {
-    let (response, _) = hub
-        .files()
-        .get(file_id)
-        .supports_all_drives(true)
-        .param("alt", "media")
-        .add_scope(google_drive3::api::Scope::Full)
-        .doit()
-        .await?;
+    let file_id = file.id.as_str();
+    if let Some(link) = &file.export_link {
+        return get_body_from_export_link(hub, link).await;
+    }
+
+    let response = match file.export {
+        // Only exports up to 10 MB, for files listed without export links
+        Some(mime) => {
+            hub.files()
+                .export(file_id, mime)
+                .add_scope(google_drive3::api::Scope::Full)
+                .delegate(&mut super::RateLimitBackoff::default())
+                .doit()
+                .await?
+        }
+        None => {
+            hub.files()
+                .get(file_id)
+                .supports_all_drives(true)
+                .param("alt", "media")
+                .add_scope(google_drive3::api::Scope::Full)
+                .delegate(&mut super::RateLimitBackoff::default())
+                .doit()
+                .await?
+                .0
+        }
+    };
 
     Ok(response.into_body())
 }
@@ -41,17 +118,24 @@ pub async fn handle_google_drive(
     args: &DownloadOpts,
     i: Option<usize>,
     file_id: &str,
-    op: Option<opendal::Operator>,
+    opts: &LineOptions,
+    profile: &EncodeProfile,
+    history: &JobHistory,
+    dests: &Destinations,
 ) -> Result<(), color_eyre::eyre::Report> {
     let hub = super::auth::get_hub(None).await?;
 
-    let nodes = super::node::fetch_nodes(&hub, file_id, Arc::new(None)).await?;
-    let items = nodes.get_tuples();
+    let filter = super::DriveFilter::from_opts(args)?;
+    let nodes = super::node::fetch_nodes(&hub, file_id, Arc::new(None), &filter).await?;
+    let items = nodes.as_ref().map(|x| x.get_tuples()).unwrap_or_default();
+    for (path, reason) in nodes.map(|x| x.get_unsupported()).unwrap_or_default() {
+        RUN_SUMMARY.skip(file_id, &path.to_string_lossy(), &reason);
+    }
+    if items.is_empty() {
+        tracing::warn!("No files left to process in Google Drive folder {file_id}");
+    }
 
-    let output_dir = match args.target_dir {
-        Some(ref x) => x,
-        None => &std::env::current_dir()?,
-    };
+    let output_dir = args.get_output_dir(opts)?;
 
     let total_gdrive_pb = if items.len() > 1 {
         crate::statics::MPB.add(get_progbar(
@@ -65,77 +149,197 @@ pub async fn handle_google_drive(
 
     total_gdrive_pb.set_message("Google Drive Items");
 
-    for super::node::FileInfo { id, path, md5 } in items.iter().progress_with(total_gdrive_pb) {
-        let file_path: PathBuf = output_dir.join(path);
-
-        let body = get_body_from_id(&hub, id).await?;
-
-        let output_path_stem = file_path
-            .file_stem()
-            .wrap_err("Cannot get file stem")?
-            .to_string_lossy();
-        let output_path_ext = file_path
-            .extension()
-            .unwrap_or(std::ffi::OsStr::new("mp4"))
-            .to_string_lossy();
-
-        let out_name = format!(
-            "{idxstr}{output_path_stem}.{output_path_ext}",
-            idxstr = if !args.no_index_filename {
-                match i {
-                    Some(index) => format!("{:05}_", index),
-                    None => String::new(),
-                }
-            } else {
-                String::new()
-            }
-        );
+    // Custom name only makes sense when the id resolves into a single file
+    let name_override = match (&opts.name, items.len()) {
+        (Some(name), 1) => Some(name.as_str()),
+        (Some(_), _) => {
+            tracing::warn!("Ignoring name option for Google Drive folder {file_id}");
+            None
+        }
+        (None, _) => None,
+    };
+
+    // Drive allows files of the same name side by side
+    let mut seen = HashSet::new();
+    let duplicates = items
+        .iter()
+        .filter(|x| !seen.insert(&x.path))
+        .map(|x| x.path.clone())
+        .collect();
 
-        let final_output_path = file_path.with_file_name(out_name);
+    let folder = DriveFolder {
+        file_id,
+        index: i,
+        opts,
+        output_dir,
+        name_override,
+        duplicates,
+    };
 
-        if let Some(op) = &op {
-            if check_path_exists(&final_output_path, op).await? {
-                tracing::warn!("File already exists on remote");
-                continue;
+    // Streaming indices rather than references keeps the futures Send
+    let (hub, folder, pb, items) = (&hub, &folder, &total_gdrive_pb, &items);
+    let results = futures_util::stream::iter(0..items.len())
+        .map(|idx| async move {
+            let result = handle_file(args, hub, folder, &items[idx], profile, history, dests).await;
+            pb.inc(1);
+            (idx, result)
+        })
+        .buffer_unordered(args.drive_jobs as usize)
+        .collect::<Vec<_>>()
+        .await;
+    total_gdrive_pb.finish();
+
+    // Every file gets its chance before the folder counts as failed
+    let mut errors = results
+        .into_iter()
+        .filter_map(|(idx, result)| Some((&items[idx], result.err()?)))
+        .collect::<Vec<_>>();
+    if items.len() > 1 {
+        for (item, e) in &errors {
+            tracing::warn!("Failed to process {}: {e}", item.path.display());
+        }
+    }
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0).1),
+        failed => bail!(
+            "{failed} of {} files of Google Drive folder {file_id} failed",
+            items.len()
+        ),
+    }
+}
+
+/// What the files of a walked folder share
+struct DriveFolder<'a> {
+    file_id: &'a str,
+    index: Option<usize>,
+    opts: &'a LineOptions,
+    output_dir: PathBuf,
+    name_override: Option<&'a str>,
+    /// Paths of more than one file, whose outputs get the file id appended
+    duplicates: HashSet<PathBuf>,
+}
+
+async fn handle_file(
+    args: &DownloadOpts,
+    hub: &Hub,
+    folder: &DriveFolder<'_>,
+    file: &super::node::FileInfo,
+    profile: &EncodeProfile,
+    history: &JobHistory,
+    dests: &Destinations,
+) -> Result<(), Error> {
+    let super::node::FileInfo {
+        id,
+        path,
+        md5,
+        modified,
+        ..
+    } = file;
+
+    if !args.force && history.is_done(&DlTypes::GoogleDrive, id).await? {
+        tracing::info!("Skipping {}, already processed", path.display());
+        return Ok(());
+    }
+
+    let file_path: PathBuf = folder.output_dir.join(path);
+
+    let source_stem = file_path
+        .file_stem()
+        .wrap_err("Cannot get file stem")?
+        .to_string_lossy();
+    let output_path_stem = match folder.name_override {
+        Some(name) => name.into(),
+        None if folder.duplicates.contains(path) => format!("{source_stem}_{id}").into(),
+        None => source_stem.clone(),
+    };
+    let source_ext = file_path
+        .extension()
+        .unwrap_or(std::ffi::OsStr::new("mp4"))
+        .to_string_lossy();
+    // Named by id, so files downloaded concurrently never share a temporary file
+    let download_path = file_path.with_file_name(format!("{source_stem}_{id}.{source_ext}"));
+    let output_path_ext = profile.output_ext(&source_ext);
+
+    let out_name = format!(
+        "{idxstr}{output_path_stem}.{output_path_ext}",
+        idxstr = if !args.no_index_filename {
+            match folder.index {
+                Some(index) => format!("{:05}_", index),
+                None => String::new(),
             }
-        };
+        } else {
+            String::new()
+        }
+    );
+
+    let final_output_path = file_path.with_file_name(out_name);
+
+    let job_id = history
+        .start(NewJob {
+            source_type: &DlTypes::GoogleDrive,
+            source_id: id,
+            source_url: folder.file_id,
+            index: folder.index,
+            title: &output_path_stem,
+            output_path: &final_output_path,
+            profile: args.get_profile_name(folder.opts),
+            opts: folder.opts,
+        })
+        .await?;
 
-        super::save_body_to_file(body, &file_path, md5.clone()).await?;
+    let result = async {
+        let body = get_body_from_id(hub, file).await?;
+        super::save_body_to_file(body, &download_path, md5.clone()).await?;
 
-        let encode_output_path = file_path
-            .with_file_name((output_path_stem.clone() + "_temp." + output_path_ext).as_ref());
+        let encode_output_path =
+            file_path.with_file_name(format!("{output_path_stem}_{id}_temp.{output_path_ext}"));
 
-        ffmpeg_transcode(
-            &file_path.to_string_lossy(),
+        let quality = transcode_checked(
+            args,
+            &TranscodeSource::single(download_path.to_string_lossy()),
             &encode_output_path,
+            profile,
+            folder.opts,
+            // Headers are only configurable for direct and yt-dlp sources
+            &RequestSettings {
+                proxy: args.proxy.clone(),
+                ..Default::default()
+            },
             format!("{output_path_stem}").as_str(),
-        )?;
+        )
+        .await?;
 
         tracing::trace!("Verifying {output_path_stem}...");
-        crate::funcs::ffmpeg::ffmpeg_check(&encode_output_path)?;
+        crate::funcs::ffmpeg::ffmpeg_check(&encode_output_path).await?;
         tracing::trace!("Verified {output_path_stem}...");
 
-        // OpenDAL doesn't support checksumming yet
-        tracing::info!(
-            "MD5: {}",
-            crate::funcs::md5::get_md5_from_path(&encode_output_path)?
-        );
-
-        std::fs::remove_file(&file_path)?;
+        std::fs::remove_file(&download_path)?;
         std::fs::rename(&encode_output_path, &final_output_path)?;
 
-        tracing::info!(
-            "MD5: {}",
-            crate::funcs::md5::get_md5_from_path(&final_output_path)?
-        );
+        let filename = path.file_name().unwrap_or_default().to_string_lossy();
+        let metadata = MediaMetadata::from_file(&filename, *modified);
+        if let Err(e) = ffmpeg_embed_metadata(&final_output_path, &metadata, folder.opts).await {
+            tracing::warn!("Failed to embed metadata into {output_path_stem}: {e}");
+        }
 
-        if let Some(op) = &op {
-            copy_path_to_b2(&final_output_path, op).await?;
-            if !args.skip_video_delete {
-                std::fs::remove_file(&final_output_path)?;
-            }
-        };
+        let mut output = JobOutput::from_path(&final_output_path).await?;
+        output.quality = quality;
+
+        // Only counts as done once every destination has the file
+        output.remote_path = dests
+            .upload(&final_output_path, output.size, &output.md5)
+            .await?;
+        if output.remote_path.is_some() && !args.skip_video_delete {
+            std::fs::remove_file(&final_output_path)?;
+        }
+
+        Ok(output)
     }
+    .await;
+
+    history.finish(job_id, &result).await?;
+    result?;
 
     Ok(())
 }
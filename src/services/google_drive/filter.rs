@@ -0,0 +1,312 @@
+use std::path::Path;
+
+use color_eyre::eyre::{Context, Error};
+use glob::{MatchOptions, Pattern};
+
+use crate::{consts::VIDEO_EXTENSIONS, init::DownloadOpts};
+
+use super::node::{
+    classify, ItemKind, EXPORT_FORMATS, MIME_TYPE_DRIVE_FOLDER, MIME_TYPE_DRIVE_SHORTCUT,
+};
+
+const GLOB_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Decides which files of a Google Drive folder get processed
+pub struct DriveFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    /// Empty when every type is taken
+    mime: Vec<String>,
+    /// Whether `video/*` also takes files with a video extension, as Drive types some of them
+    /// as `application/octet-stream`
+    video_extensions: bool,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+}
+
+fn parse_globs(globs: &[String]) -> Result<Vec<Pattern>, Error> {
+    globs
+        .iter()
+        .map(|x| Pattern::new(x.trim_matches('/')).wrap_err_with(|| format!("Invalid glob {x}")))
+        .collect()
+}
+
+/// Globs without a slash match the name anywhere in the tree, like in .gitignore
+fn glob_matches(pattern: &Pattern, path: &Path) -> bool {
+    match pattern.as_str().contains('/') {
+        true => pattern.matches_path_with(path, GLOB_OPTIONS),
+        false => path
+            .file_name()
+            .is_some_and(|x| pattern.matches_with(&x.to_string_lossy(), GLOB_OPTIONS)),
+    }
+}
+
+fn escape_query(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+impl DriveFilter {
+    pub fn from_opts(args: &DownloadOpts) -> Result<Self, Error> {
+        let mime = match args.drive_mime.iter().any(|x| x == "*" || x == "*/*") {
+            true => vec![],
+            false => args
+                .drive_mime
+                .iter()
+                .map(|x| x.trim().to_ascii_lowercase())
+                .collect(),
+        };
+
+        Ok(Self {
+            include: parse_globs(&args.drive_include)?,
+            exclude: parse_globs(&args.drive_exclude)?,
+            video_extensions: mime.iter().any(|x| x == "video/*"),
+            mime,
+            min_size: args.drive_min_size,
+            max_size: args.drive_max_size,
+        })
+    }
+
+    /// Drive query listing the children of the folder. Only the MIME types can be filtered by
+    /// Drive, paths and sizes are checked once listed. Video extensions can't be queried, so
+    /// with `video/*` every type gets listed.
+    pub fn query(&self, folder_id: &str) -> String {
+        let mut q = format!(
+            "'{}' in parents and trashed = false",
+            escape_query(folder_id)
+        );
+        if self.mime.is_empty() || self.video_extensions {
+            return q;
+        }
+
+        // Google-native files are judged by what they're exported to
+        let exportable = EXPORT_FORMATS
+            .iter()
+            .filter(|(_, export, _)| self.allows_mime(export))
+            .map(|(native, _, _)| *native);
+        let types = [MIME_TYPE_DRIVE_FOLDER, MIME_TYPE_DRIVE_SHORTCUT]
+            .into_iter()
+            .chain(exportable)
+            .map(|x| format!("mimeType = '{x}'"))
+            .chain(self.mime.iter().map(|x| match x.strip_suffix('*') {
+                Some(prefix) => format!("mimeType contains '{}'", escape_query(prefix)),
+                None => format!("mimeType = '{}'", escape_query(x)),
+            }))
+            .collect::<Vec<_>>();
+        q += &format!(" and ({})", types.join(" or "));
+
+        q
+    }
+
+    fn allows_mime(&self, mime: &str) -> bool {
+        self.mime.is_empty()
+            || self.mime.iter().any(|x| match x.strip_suffix('*') {
+                Some(prefix) => mime.starts_with(prefix),
+                None => mime == x,
+            })
+    }
+
+    /// Whether the folder is left out, along with everything in it
+    pub fn skips_folder(&self, path: &Path) -> bool {
+        self.exclude.iter().any(|x| glob_matches(x, path))
+    }
+
+    /// Why the file at the path inside the folder is left out, if it is
+    pub fn skip_reason(&self, path: &Path, file: &google_drive3::api::File) -> Option<String> {
+        if path.ancestors().any(|x| self.skips_folder(x)) {
+            return Some("excluded by --drive-exclude".to_string());
+        }
+        if !self.include.is_empty() && !self.include.iter().any(|x| glob_matches(x, path)) {
+            return Some("not matched by --drive-include".to_string());
+        }
+
+        let mime = match classify(file) {
+            ItemKind::Exportable { mime, .. } => mime,
+            _ => file.mime_type.as_deref().unwrap_or_default(),
+        };
+        let has_video_extension = self.video_extensions
+            && path
+                .extension()
+                .and_then(|x| x.to_str())
+                .is_some_and(|x| VIDEO_EXTENSIONS.contains(&x.to_ascii_lowercase().as_str()));
+        if !self.allows_mime(mime) && !has_video_extension {
+            return Some(format!("MIME type {mime} not allowed by --drive-mime"));
+        }
+
+        // Google-native files have no size until exported
+        if let Some(size) = file.size.map(|x| x.max(0) as u64) {
+            if self.min_size.is_some_and(|x| size < x) {
+                return Some(format!("{size} bytes is below --drive-min-size"));
+            }
+            if self.max_size.is_some_and(|x| size > x) {
+                return Some(format!("{size} bytes is above --drive-max-size"));
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use google_drive3::api::File;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        opts: DownloadOpts,
+    }
+
+    fn filter(args: &[&str]) -> DriveFilter {
+        let cli = Cli::try_parse_from(["yt-dlp-to-ffmpeg"].iter().chain(args)).unwrap();
+        DriveFilter::from_opts(&cli.opts).unwrap()
+    }
+
+    fn file(mime: &str, size: Option<i64>) -> File {
+        File {
+            mime_type: Some(mime.to_string()),
+            md5_checksum: size.map(|_| "d41d8cd98f00b204e9800998ecf8427e".to_string()),
+            size,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn globs() {
+        let cases = [
+            ("*.mkv", "a/b/Episode 1.MKV", true),
+            ("*.mkv", "a/b/Episode 1.mp4", false),
+            ("extras", "season 1/extras", true),
+            ("extras", "season 1/extras.mkv", false),
+            ("season 1/*", "season 1/a.mkv", true),
+            ("season 1/*", "season 1/extras/a.mkv", false),
+            ("season 1/*", "other/season 1/a.mkv", false),
+            ("*/extras/**", "season 1/extras/a/b.mkv", true),
+        ];
+
+        for (pattern, path, expected) in cases {
+            let pattern = Pattern::new(pattern).unwrap();
+            assert_eq!(
+                glob_matches(&pattern, Path::new(path)),
+                expected,
+                "{pattern} on {path}"
+            );
+        }
+    }
+
+    #[test]
+    fn queries() {
+        let cases: [(&[&str], &str, &str); 4] = [
+            (&[], "folder", "'folder' in parents and trashed = false"),
+            (
+                &["--drive-mime", "*"],
+                "it's\\",
+                "'it\\'s\\\\' in parents and trashed = false",
+            ),
+            (
+                &["--drive-mime", "audio/*,video/mp4"],
+                "folder",
+                "'folder' in parents and trashed = false and (\
+                mimeType = 'application/vnd.google-apps.folder' or \
+                mimeType = 'application/vnd.google-apps.shortcut' or \
+                mimeType = 'application/vnd.google-apps.vid' or \
+                mimeType contains 'audio/' or mimeType = 'video/mp4')",
+            ),
+            (
+                &["--drive-mime", "audio/x-'quoted'"],
+                "folder",
+                "'folder' in parents and trashed = false and (\
+                mimeType = 'application/vnd.google-apps.folder' or \
+                mimeType = 'application/vnd.google-apps.shortcut' or \
+                mimeType = 'audio/x-\\'quoted\\'')",
+            ),
+        ];
+
+        for (args, folder_id, expected) in cases {
+            assert_eq!(filter(args).query(folder_id), expected, "{args:?}");
+        }
+    }
+
+    #[test]
+    fn skip_reasons() {
+        let octet_stream = "application/octet-stream";
+        let cases: [(&[&str], &str, File, Option<&str>); 12] = [
+            (&[], "a.mp4", file("video/mp4", Some(10)), None),
+            (&[], "a.mkv", file(octet_stream, Some(10)), None),
+            (&[], "a.TS", file(octet_stream, Some(10)), None),
+            (
+                &[],
+                "a.pdf",
+                file("application/pdf", Some(10)),
+                Some("MIME type application/pdf not allowed by --drive-mime"),
+            ),
+            (
+                &[],
+                "a.bin",
+                file(octet_stream, Some(10)),
+                Some("MIME type application/octet-stream not allowed by --drive-mime"),
+            ),
+            (
+                &["--drive-mime", "video/mp4"],
+                "a.mkv",
+                file(octet_stream, Some(10)),
+                Some("MIME type application/octet-stream not allowed by --drive-mime"),
+            ),
+            (
+                &[],
+                "a",
+                file("application/vnd.google-apps.vid", None),
+                None,
+            ),
+            (
+                &["--drive-mime", "*"],
+                "a.pdf",
+                file("application/pdf", Some(10)),
+                None,
+            ),
+            (
+                &["--drive-include", "season 1/**"],
+                "season 2/a.mp4",
+                file("video/mp4", Some(10)),
+                Some("not matched by --drive-include"),
+            ),
+            (
+                &[
+                    "--drive-include",
+                    "season 1/**",
+                    "--drive-exclude",
+                    "extras",
+                ],
+                "season 1/extras/a.mp4",
+                file("video/mp4", Some(10)),
+                Some("excluded by --drive-exclude"),
+            ),
+            (
+                &["--drive-min-size", "1K"],
+                "a.mp4",
+                file("video/mp4", Some(10)),
+                Some("10 bytes is below --drive-min-size"),
+            ),
+            (
+                &["--drive-max-size", "5"],
+                "a.mp4",
+                file("video/mp4", Some(10)),
+                Some("10 bytes is above --drive-max-size"),
+            ),
+        ];
+
+        for (args, path, file, expected) in cases {
+            assert_eq!(
+                filter(args).skip_reason(Path::new(path), &file).as_deref(),
+                expected,
+                "{args:?} on {path}"
+            );
+        }
+    }
+}
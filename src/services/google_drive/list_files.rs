@@ -5,7 +5,13 @@ use google_drive3::{
 
 type Hub = DriveHub<HttpsConnector<HttpConnector>>;
 
-pub async fn list_files(hub: &Hub, q: &str) -> Result<Vec<google_drive3::api::File>, Error> {
+/// Lists the files matching the query. Shared drive folders are only searched completely when
+/// their drive is given.
+pub async fn list_files(
+    hub: &Hub,
+    q: &str,
+    drive_id: Option<&str>,
+) -> Result<Vec<google_drive3::api::File>, Error> {
     let mut collected_files: Vec<google_drive3::api::File> = vec![];
     let mut next_page_token: Option<String> = None;
 
@@ -15,7 +21,12 @@ pub async fn list_files(hub: &Hub, q: &str) -> Result<Vec<google_drive3::api::Fi
         if let Some(token) = next_page_token {
             req = req.page_token(&token);
         }
+        req = match drive_id {
+            Some(drive_id) => req.corpora("drive").drive_id(drive_id),
+            None => req.corpora("user"),
+        };
 
+        let _permit = super::request_permit().await?;
         let (_, file_list) = req
             .page_size(1000i32)
             .q(q)
@@ -24,8 +35,9 @@ pub async fn list_files(hub: &Hub, q: &str) -> Result<Vec<google_drive3::api::Fi
             .include_items_from_all_drives(true)
             .param(
                 "fields",
-                "files(id,name,md5Checksum,mimeType,size,createdTime,parents),nextPageToken",
+                "files(id,name,md5Checksum,mimeType,size,createdTime,modifiedTime,trashed,parents,shortcutDetails,driveId,exportLinks),nextPageToken",
             )
+            .delegate(&mut super::RateLimitBackoff::default())
             .doit()
             .await?;
 
@@ -1,5 +1,8 @@
 pub mod auth;
 
+mod filter;
+pub use filter::DriveFilter;
+
 mod node;
 
 mod download;
@@ -8,5 +11,8 @@ pub use download::handle_google_drive;
 mod save_body_to_file;
 pub use save_body_to_file::save_body_to_file;
 
+mod throttle;
+pub use throttle::{request_permit, RateLimitBackoff};
+
 mod list_files;
 pub use list_files::list_files;
@@ -1,4 +1,7 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use color_eyre::eyre::{ContextCompat, Error};
 use futures_util::future::try_join_all;
@@ -6,22 +9,76 @@ use google_drive3::{
     hyper_rustls::HttpsConnector, hyper_util::client::legacy::connect::HttpConnector, DriveHub,
 };
 
+use super::filter::DriveFilter;
+
 pub const MIME_TYPE_DRIVE_FOLDER: &str = "application/vnd.google-apps.folder";
 pub const MIME_TYPE_DRIVE_SHORTCUT: &str = "application/vnd.google-apps.shortcut";
+pub const MIME_TYPE_GOOGLE_APPS_PREFIX: &str = "application/vnd.google-apps.";
+/// Google-native types exported into something ffmpeg reads, with the export's MIME type and
+/// extension
+pub const EXPORT_FORMATS: &[(&str, &str, &str)] =
+    &[("application/vnd.google-apps.vid", "video/mp4", "mp4")];
+
+const FILE_FIELDS: &str = "id, name, mimeType, modifiedTime, size, md5Checksum, trashed, \
+    shortcutDetails, driveId, exportLinks";
 
 pub fn is_directory(file: &google_drive3::api::File) -> bool {
     file.mime_type == Some(String::from(MIME_TYPE_DRIVE_FOLDER))
 }
 
-#[allow(dead_code)]
 pub fn is_binary(file: &google_drive3::api::File) -> bool {
-    file.md5_checksum != None
+    file.md5_checksum.is_some()
 }
 
 pub fn is_shortcut(file: &google_drive3::api::File) -> bool {
     file.mime_type == Some(String::from(MIME_TYPE_DRIVE_SHORTCUT))
 }
 
+/// What a Drive item is, and how the content of a file can be fetched
+#[derive(Debug, PartialEq)]
+pub enum ItemKind {
+    Folder,
+    /// Walked as the item it points to, if Drive gave it
+    Shortcut {
+        target_id: Option<String>,
+    },
+    /// Downloaded as it is
+    Binary,
+    /// Google-native file converted by Drive
+    Exportable {
+        mime: &'static str,
+        ext: &'static str,
+    },
+    Unsupported(String),
+}
+
+pub fn classify(file: &google_drive3::api::File) -> ItemKind {
+    if is_directory(file) {
+        return ItemKind::Folder;
+    }
+    if is_shortcut(file) {
+        let target_id = file
+            .shortcut_details
+            .as_ref()
+            .and_then(|x| x.target_id.clone());
+        return ItemKind::Shortcut { target_id };
+    }
+    if is_binary(file) {
+        return ItemKind::Binary;
+    }
+
+    let mime = file.mime_type.as_deref().unwrap_or_default();
+    if let Some((_, export, ext)) = EXPORT_FORMATS.iter().find(|x| x.0 == mime) {
+        return ItemKind::Exportable { mime: export, ext };
+    }
+
+    match mime.starts_with(MIME_TYPE_GOOGLE_APPS_PREFIX) {
+        true => ItemKind::Unsupported(format!("{mime} can't be downloaded or exported as video")),
+        // Drive only leaves out the checksum of some binary files
+        false => ItemKind::Binary,
+    }
+}
+
 type Hub = DriveHub<HttpsConnector<HttpConnector>>;
 
 #[derive(Clone)]
@@ -35,7 +92,14 @@ pub enum DriveNode {
         id: String,
         name: String,
         parent: Arc<Option<DriveNode>>,
-        file_info: google_drive3::api::File,
+        file_info: Box<google_drive3::api::File>,
+    },
+    /// Item that was found but can't be processed, kept for the skip report
+    Unsupported {
+        name: String,
+        parent: Arc<Option<DriveNode>>,
+        path: PathBuf,
+        reason: String,
     },
 }
 
@@ -43,6 +107,11 @@ pub struct FileInfo {
     pub id: String,
     pub path: PathBuf,
     pub md5: Option<String>,
+    pub modified: Option<chrono::DateTime<chrono::Utc>>,
+    /// MIME type to export a Google-native file to
+    pub export: Option<&'static str>,
+    /// Link downloading that export, which unlike files.export isn't limited to 10 MB
+    pub export_link: Option<String>,
 }
 
 impl DriveNode {
@@ -66,6 +135,7 @@ impl DriveNode {
         match self {
             DriveNode::Folder { parent, .. } => parent.as_ref().clone(),
             DriveNode::File { parent, .. } => parent.as_ref().clone(),
+            DriveNode::Unsupported { parent, .. } => parent.as_ref().clone(),
         }
     }
 
@@ -73,6 +143,7 @@ impl DriveNode {
         match self {
             DriveNode::Folder { name, .. } => name.clone(),
             DriveNode::File { name, .. } => name.clone(),
+            DriveNode::Unsupported { name, .. } => name.clone(),
         }
     }
 
@@ -90,64 +161,237 @@ impl DriveNode {
                 .iter()
                 .flat_map(|x| x.get_tuples())
                 .collect::<Vec<_>>(),
-            DriveNode::File { id, file_info, .. } => vec![FileInfo {
-                id: id.to_string(),
-                path: self.build_path(),
-                md5: file_info.md5_checksum.clone(),
-            }],
+            DriveNode::File { id, file_info, .. } => {
+                let mut path = self.build_path();
+                let export = match classify(file_info) {
+                    ItemKind::Exportable { mime, ext } => {
+                        path.set_file_name(format!("{}.{ext}", self.get_name()));
+                        Some(mime)
+                    }
+                    _ => None,
+                };
+                let export_link = export.and_then(|mime| {
+                    file_info
+                        .export_links
+                        .as_ref()
+                        .and_then(|x| x.get(mime).cloned())
+                });
+
+                vec![FileInfo {
+                    id: id.to_string(),
+                    path,
+                    md5: file_info.md5_checksum.clone(),
+                    modified: file_info.modified_time,
+                    export,
+                    export_link,
+                }]
+            }
+            DriveNode::Unsupported { .. } => vec![],
+        }
+    }
+
+    /// Items left out of the walk with the reason, by their path inside the walked folder
+    pub fn get_unsupported(&self) -> Vec<(PathBuf, String)> {
+        match &self {
+            DriveNode::Folder { child, .. } => {
+                child.iter().flat_map(|x| x.get_unsupported()).collect()
+            }
+            DriveNode::File { .. } => vec![],
+            DriveNode::Unsupported { path, reason, .. } => vec![(path.clone(), reason.clone())],
         }
     }
 }
 
-#[async_recursion::async_recursion]
-pub async fn fetch_nodes<S: AsRef<str> + std::marker::Send>(
-    hub: &Hub,
-    id: S,
-    parent_node: Arc<Option<DriveNode>>,
-) -> Result<DriveNode, Error> {
+async fn get_metadata(hub: &Hub, id: &str) -> Result<google_drive3::api::File, Error> {
+    let _permit = super::request_permit().await?;
     let (_, metadata) = hub
         .files()
-        .get(id.as_ref())
+        .get(id)
         .supports_all_drives(true)
-        .param("fields", "id, name, mimeType")
+        .param("fields", FILE_FIELDS)
         .add_scope(google_drive3::api::Scope::Full)
+        .delegate(&mut super::RateLimitBackoff::default())
         .doit()
         .await?;
 
-    if is_shortcut(&metadata) {
-        let target_file_id = metadata
-            .shortcut_details
-            .and_then(|details| details.target_id);
-
-        fetch_nodes(hub, target_file_id.unwrap_or_default(), parent_node).await
-    } else if is_directory(&metadata) {
-        let child_nodes = super::list_files(
-            hub,
-            format!("'{}' in parents and trashed = false", id.as_ref()).as_str(),
-        )
-        .await?;
+    Ok(metadata)
+}
 
-        let asdf = child_nodes
-            .iter()
-            .filter_map(|x| x.id.clone())
-            .map(|x| (x.clone(), parent_node.clone()))
-            .map(|x| fetch_nodes(hub, x.0, x.1))
+/// Walks the tree below the id. Files the filter leaves out give None.
+pub async fn fetch_nodes(
+    hub: &Hub,
+    id: &str,
+    parent_node: Arc<Option<DriveNode>>,
+    filter: &DriveFilter,
+) -> Result<Option<DriveNode>, Error> {
+    let metadata = get_metadata(hub, id).await?;
+    walk(hub, metadata, parent_node, filter, None, &[]).await
+}
+
+/// `path` is the path inside the walked folder, None for the walked item itself, which is taken
+/// regardless of the filter. `visited` holds the folders and shortcuts leading to the item.
+#[async_recursion::async_recursion]
+async fn walk(
+    hub: &Hub,
+    metadata: google_drive3::api::File,
+    parent_node: Arc<Option<DriveNode>>,
+    filter: &DriveFilter,
+    path: Option<&'async_recursion Path>,
+    visited: &[String],
+) -> Result<Option<DriveNode>, Error> {
+    let id = metadata.id.clone().wrap_err("Can't get file id")?;
+    let name = metadata.name.clone().wrap_err("Can't get file name")?;
+    let unsupported = |reason: String| {
+        let path = path.map_or_else(|| PathBuf::from(&name), Path::to_path_buf);
+        tracing::info!("Skipping {}: {reason}", path.display());
+        Ok(Some(DriveNode::Unsupported {
+            name: name.clone(),
+            parent: parent_node.clone(),
+            path,
+            reason,
+        }))
+    };
+
+    if visited.contains(&id) {
+        return unsupported("shortcut loops back to a folder containing it".to_string());
+    }
+    let visited = [visited, std::slice::from_ref(&id)].concat();
+
+    let kind = classify(&metadata);
+    if let ItemKind::Shortcut { target_id } = kind {
+        let Some(target_id) = target_id else {
+            return unsupported("shortcut without target".to_string());
+        };
+        let target = match get_metadata(hub, &target_id).await {
+            Ok(x) => x,
+            Err(e) => return unsupported(format!("shortcut target can't be read: {e}")),
+        };
+        if target.trashed == Some(true) {
+            return unsupported("shortcut target is in the trash".to_string());
+        }
+
+        walk(hub, target, parent_node, filter, path, &visited).await
+    } else if kind == ItemKind::Folder {
+        if let Some(drive_id) = &metadata.drive_id {
+            tracing::debug!("Listing {id} in shared drive {drive_id}");
+        }
+        let child_nodes =
+            super::list_files(hub, &filter.query(&id), metadata.drive_id.as_deref()).await?;
+
+        let children = child_nodes
+            .into_iter()
+            .filter_map(|x| {
+                let child_path = path
+                    .unwrap_or(Path::new(""))
+                    .join(x.name.as_deref().unwrap_or_default());
+                let skip_reason = if is_directory(&x) {
+                    filter
+                        .skips_folder(&child_path)
+                        .then(|| "excluded by --drive-exclude".to_string())
+                } else if is_shortcut(&x) {
+                    // Checked once the target is known
+                    None
+                } else {
+                    filter.skip_reason(&child_path, &x)
+                };
+
+                if let Some(reason) = skip_reason {
+                    tracing::info!("Skipping {}: {reason}", child_path.display());
+                    return None;
+                }
+                Some((x, child_path))
+            })
             .collect::<Vec<_>>();
 
-        let childs = try_join_all(asdf).await?;
+        let childs = try_join_all(children.into_iter().map(|(x, child_path)| {
+            let (parent_node, visited) = (parent_node.clone(), &visited);
+            async move { walk(hub, x, parent_node, filter, Some(&child_path), visited).await }
+        }))
+        .await?;
 
-        Ok(DriveNode::Folder {
-            name: metadata.name.wrap_err("Can't get folder name")?,
+        Ok(Some(DriveNode::Folder {
+            name,
             parent: parent_node.clone(),
-            child: childs,
-        })
-        // let newnode = Self::Folder()
+            child: childs.into_iter().flatten().collect(),
+        }))
     } else {
-        Ok(DriveNode::File {
-            id: id.as_ref().to_string(),
-            name: metadata.name.clone().wrap_err("Can't get file name")?,
+        if let Some(path) = path {
+            if let Some(reason) = filter.skip_reason(path, &metadata) {
+                tracing::info!("Skipping {}: {reason}", path.display());
+                return Ok(None);
+            }
+        }
+        if let ItemKind::Unsupported(reason) = kind {
+            return unsupported(reason);
+        }
+
+        Ok(Some(DriveNode::File {
+            id,
+            name,
             parent: parent_node.clone(),
-            file_info: metadata,
-        })
+            file_info: Box::new(metadata),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use google_drive3::api::{File, FileShortcutDetails};
+
+    use super::*;
+
+    fn file(mime: &str, md5: Option<&str>) -> File {
+        File {
+            mime_type: Some(mime.to_string()),
+            md5_checksum: md5.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn classifies() {
+        let shortcut = File {
+            shortcut_details: Some(FileShortcutDetails {
+                target_id: Some("target".to_string()),
+                ..Default::default()
+            }),
+            ..file(MIME_TYPE_DRIVE_SHORTCUT, None)
+        };
+        let cases = [
+            (file(MIME_TYPE_DRIVE_FOLDER, None), ItemKind::Folder),
+            (
+                shortcut,
+                ItemKind::Shortcut {
+                    target_id: Some("target".to_string()),
+                },
+            ),
+            (
+                file(MIME_TYPE_DRIVE_SHORTCUT, None),
+                ItemKind::Shortcut { target_id: None },
+            ),
+            (
+                file("application/vnd.google-apps.vid", None),
+                ItemKind::Exportable {
+                    mime: "video/mp4",
+                    ext: "mp4",
+                },
+            ),
+            (
+                file("application/vnd.google-apps.document", None),
+                ItemKind::Unsupported(
+                    "application/vnd.google-apps.document can't be downloaded or exported as video"
+                        .to_string(),
+                ),
+            ),
+            (
+                file("video/mp4", Some("d41d8cd98f00b204e9800998ecf8427e")),
+                ItemKind::Binary,
+            ),
+            (file("video/x-matroska", None), ItemKind::Binary),
+        ];
+
+        for (file, expected) in cases {
+            assert_eq!(classify(&file), expected, "{:?}", file.mime_type);
+        }
     }
 }
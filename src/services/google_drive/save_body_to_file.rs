@@ -16,7 +16,7 @@ pub async fn save_body_to_file(
     if file_path.exists() {
         tracing::info!("Existing file detected. Checking MD5...");
 
-        let existing_file = File::open(&file_path)?;
+        let existing_file = File::open(file_path)?;
 
         if let Some(expected_md5) = &expected_md5 {
             let pb = MPB.add(get_progbar(
@@ -90,5 +90,5 @@ pub async fn save_body_to_file(
     pb.finish_and_clear();
 
     // Rename temporary file to final file
-    std::fs::rename(&tmp_file_path, &file_path).wrap_err("Cannot rename temporary file to final")
+    std::fs::rename(&tmp_file_path, file_path).wrap_err("Cannot rename temporary file to final")
 }
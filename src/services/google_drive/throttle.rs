@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use color_eyre::eyre::Error;
+use google_drive3::common::{Delegate, Response, Retry};
+
+use crate::{
+    consts::{DRIVE_BACKOFF_MS, DRIVE_RATE_LIMIT_ATTEMPTS, DRIVE_REQUESTS},
+    statics::DRIVE_REQUEST_SEMAPHORE,
+};
+
+/// Reasons Drive gives with a 403 when the request was only refused for going too fast
+const RATE_LIMIT_REASONS: &[&str] = &["rateLimitExceeded", "userRateLimitExceeded"];
+
+/// Retries Drive requests refused by the rate limits, waiting twice as long every time
+#[derive(Default)]
+pub struct RateLimitBackoff {
+    attempt: u32,
+}
+
+fn is_rate_limited(res: &Response, err: Option<&serde_json::Value>) -> bool {
+    match res.status().as_u16() {
+        429 => true,
+        403 => err
+            .and_then(|x| x["error"]["errors"].as_array())
+            .into_iter()
+            .flatten()
+            .any(|x| RATE_LIMIT_REASONS.contains(&x["reason"].as_str().unwrap_or_default())),
+        _ => false,
+    }
+}
+
+impl Delegate for RateLimitBackoff {
+    fn http_failure(&mut self, res: &Response, err: Option<&serde_json::Value>) -> Retry {
+        if !is_rate_limited(res, err) || self.attempt >= DRIVE_RATE_LIMIT_ATTEMPTS {
+            return Retry::Abort;
+        }
+
+        // Jitter keeps concurrent requests from retrying all at once
+        let jitter = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_millis() as u64;
+        let wait = Duration::from_millis((DRIVE_BACKOFF_MS << self.attempt) + jitter);
+        self.attempt += 1;
+
+        tracing::info!(
+            "Google Drive rate limit hit, retrying in {:.1}s",
+            wait.as_secs_f64()
+        );
+        Retry::After(wait)
+    }
+}
+
+/// Waits until fewer than --drive-requests API requests are running
+pub async fn request_permit() -> Result<tokio::sync::SemaphorePermit<'static>, Error> {
+    Ok(DRIVE_REQUEST_SEMAPHORE
+        .get_or_init(|| tokio::sync::Semaphore::new(DRIVE_REQUESTS as usize))
+        .acquire()
+        .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use google_drive3::common::to_body;
+
+    use super::*;
+
+    fn response(status: u16) -> Response {
+        google_drive3::hyper::Response::builder()
+            .status(status)
+            .body(to_body::<String>(None))
+            .unwrap()
+    }
+
+    fn error(reason: &str) -> serde_json::Value {
+        serde_json::json!({
+            "error": {
+                "code": 403,
+                "errors": [{ "domain": "usageLimits", "reason": reason }],
+            }
+        })
+    }
+
+    #[test]
+    fn rate_limits() {
+        let cases = [
+            (429, None, true),
+            (403, Some(error("rateLimitExceeded")), true),
+            (403, Some(error("userRateLimitExceeded")), true),
+            (403, Some(error("insufficientPermissions")), false),
+            (403, None, false),
+            (404, Some(error("rateLimitExceeded")), false),
+            (500, None, false),
+        ];
+
+        for (status, err, expected) in cases {
+            assert_eq!(
+                is_rate_limited(&response(status), err.as_ref()),
+                expected,
+                "{status} {err:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn backs_off_until_out_of_attempts() {
+        let mut backoff = RateLimitBackoff::default();
+        let waits = std::iter::from_fn(|| match backoff.http_failure(&response(429), None) {
+            Retry::After(x) => Some(x),
+            Retry::Abort => None,
+        })
+        .collect::<Vec<_>>();
+
+        assert_eq!(waits.len(), DRIVE_RATE_LIMIT_ATTEMPTS as usize);
+        for (attempt, wait) in waits.iter().enumerate() {
+            let min = Duration::from_millis(DRIVE_BACKOFF_MS << attempt);
+            assert!(*wait >= min && *wait < min + Duration::from_secs(1));
+        }
+
+        let mut backoff = RateLimitBackoff::default();
+        assert!(matches!(
+            backoff.http_failure(&response(403), Some(&error("insufficientPermissions"))),
+            Retry::Abort
+        ));
+    }
+}
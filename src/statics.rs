@@ -1,6 +1,14 @@
-use std::sync::LazyLock;
+use std::sync::{LazyLock, OnceLock};
 
 pub static MPB: LazyLock<indicatif::MultiProgress> = LazyLock::new(indicatif::MultiProgress::new);
+/// Limits concurrently running ffmpeg processes. Set from `--encode-jobs` on startup.
+pub static ENCODE_SEMAPHORE: OnceLock<tokio::sync::Semaphore> = OnceLock::new();
+/// Limits concurrent Google Drive API requests. Set from `--drive-requests` on startup.
+pub static DRIVE_REQUEST_SEMAPHORE: OnceLock<tokio::sync::Semaphore> = OnceLock::new();
+/// Per-host HTTP headers from the header config. Set on startup.
+pub static HEADER_RULES: OnceLock<crate::structs::HeaderRules> = OnceLock::new();
+/// Outcome of every source, reported at the end of the run
+pub static RUN_SUMMARY: LazyLock<crate::structs::RunSummary> = LazyLock::new(Default::default);
 pub static PROJECT_DIR_PATH: LazyLock<std::path::PathBuf> = LazyLock::new(|| {
     let dirpath = match directories::ProjectDirs::from(
         crate::consts::APP_ID[0],
@@ -0,0 +1,443 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{bail, Context, ContextCompat, Error};
+
+/// Seconds between the Windows epoch Chromium counts from and the unix epoch
+const CHROMIUM_EPOCH_OFFSET: i64 = 11_644_473_600;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    /// Leading dot when subdomains get it too, as in the Netscape format
+    pub domain: String,
+    pub path: String,
+    pub secure: bool,
+    pub http_only: bool,
+    /// Unix seconds, 0 for session cookies
+    pub expires: i64,
+    pub name: String,
+    pub value: String,
+}
+
+impl Cookie {
+    /// Whether the cookie is sent to the domain or any of its subdomains
+    pub fn matches(&self, domain: &str) -> bool {
+        let host = self.domain.trim_start_matches('.');
+        host.eq_ignore_ascii_case(domain)
+            || host
+                .to_ascii_lowercase()
+                .ends_with(&format!(".{}", domain.to_ascii_lowercase()))
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires != 0 && self.expires < chrono::Utc::now().timestamp()
+    }
+}
+
+/// Where cookies get imported from
+#[derive(Debug, Clone, Copy, PartialEq, strum::Display)]
+pub enum CookieSource {
+    Firefox,
+    Chromium,
+    Netscape,
+}
+
+/// Cookies read from a browser profile or a cookie file
+#[derive(Debug, Clone, Default)]
+pub struct CookieJar(pub Vec<Cookie>);
+
+/// Finds the cookie database of a profile directory, or takes the file as it is
+fn locate(path: &Path) -> Result<(CookieSource, PathBuf), Error> {
+    if path.is_dir() {
+        let candidates = [
+            (CookieSource::Firefox, path.join("cookies.sqlite")),
+            (CookieSource::Chromium, path.join("Network").join("Cookies")),
+            (CookieSource::Chromium, path.join("Cookies")),
+        ];
+        return candidates
+            .into_iter()
+            .find(|(_, x)| x.is_file())
+            .wrap_err_with(|| format!("No cookie database found in {}", path.display()));
+    }
+
+    let mut header = [0; 16];
+    let is_sqlite = std::io::Read::read_exact(&mut std::fs::File::open(path)?, &mut header).is_ok()
+        && header == *b"SQLite format 3\0";
+    if !is_sqlite {
+        return Ok((CookieSource::Netscape, path.to_path_buf()));
+    }
+
+    match path.file_name().and_then(|x| x.to_str()) {
+        Some("cookies.sqlite") => Ok((CookieSource::Firefox, path.to_path_buf())),
+        _ => Ok((CookieSource::Chromium, path.to_path_buf())),
+    }
+}
+
+/// Key Linux Chromium encrypts `v10` values with when no keyring is available
+fn chromium_key() -> [u8; 16] {
+    let mut key = [0; 16];
+    pbkdf2::pbkdf2_hmac::<sha1::Sha1>(b"peanuts", b"saltysalt", 1, &mut key);
+    key
+}
+
+/// Decrypts a Linux Chromium `v10` value, encrypted with the fixed key used when no keyring
+/// is available. Newer versions put a hash of the host before the value.
+fn decrypt_chromium(encrypted: &[u8], strip_hash: bool) -> Result<String, Error> {
+    use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+
+    let Some(data) = encrypted.strip_prefix(b"v10") else {
+        bail!("only v10 values can be decrypted, export the cookies to a file instead");
+    };
+
+    let mut buf = data.to_vec();
+    let decrypted = cbc::Decryptor::<aes::Aes128>::new(&chromium_key().into(), &[b' '; 16].into())
+        .decrypt_padded_mut::<Pkcs7>(&mut buf)
+        .map_err(|_| color_eyre::eyre::eyre!("wrong key or corrupted value"))?;
+    let decrypted = match strip_hash {
+        true => decrypted.get(32..).unwrap_or_default(),
+        false => decrypted,
+    };
+
+    Ok(String::from_utf8_lossy(decrypted).into_owned())
+}
+
+impl CookieJar {
+    /// Reads the cookies of a Firefox or Chromium profile, their cookie database, or a
+    /// Netscape cookie file
+    pub async fn load(path: &Path) -> Result<(CookieSource, Self), Error> {
+        let (source, path) = locate(path)?;
+        tracing::debug!("Reading {source} cookies from {}", path.display());
+
+        let jar = match source {
+            CookieSource::Netscape => Self::parse_netscape(
+                &std::fs::read_to_string(&path).wrap_err("Failed to read cookie file")?,
+            )?,
+            CookieSource::Firefox | CookieSource::Chromium => {
+                Self::read_sqlite(source, &path).await?
+            }
+        };
+
+        Ok((source, jar))
+    }
+
+    async fn read_sqlite(source: CookieSource, path: &Path) -> Result<Self, Error> {
+        // Browsers keep the database locked while running, so a copy gets read instead
+        let dir = tempfile::tempdir()?;
+        let copy = dir.path().join("cookies.db");
+        std::fs::copy(path, &copy).wrap_err("Failed to copy cookie database")?;
+        let wal = PathBuf::from(format!("{}-wal", path.display()));
+        if wal.exists() {
+            std::fs::copy(&wal, dir.path().join("cookies.db-wal"))?;
+        }
+
+        let db = libsql::Builder::new_local(&copy).build().await?;
+        let conn = db.connect()?;
+
+        let mut cookies = vec![];
+        match source {
+            CookieSource::Firefox => {
+                let mut rows = conn
+                    .query(
+                        "SELECT host, path, isSecure, isHttpOnly, expiry, name, value FROM moz_cookies",
+                        (),
+                    )
+                    .await?;
+                while let Some(row) = rows.next().await? {
+                    // Firefox has moved to milliseconds at some point
+                    let expiry = row.get::<i64>(4)?;
+                    cookies.push(Cookie {
+                        domain: row.get(0)?,
+                        path: row.get(1)?,
+                        secure: row.get::<i64>(2)? != 0,
+                        http_only: row.get::<i64>(3)? != 0,
+                        expires: if expiry > 100_000_000_000 {
+                            expiry / 1000
+                        } else {
+                            expiry
+                        },
+                        name: row.get(5)?,
+                        value: row.get(6)?,
+                    });
+                }
+            }
+            _ => {
+                let mut rows = conn
+                    .query("SELECT value FROM meta WHERE key = 'version'", ())
+                    .await?;
+                let version = match rows.next().await? {
+                    Some(row) => row.get::<String>(0)?.parse::<i64>().unwrap_or_default(),
+                    None => 0,
+                };
+
+                let mut rows = conn
+                    .query(
+                        "SELECT host_key, path, is_secure, is_httponly, expires_utc, name, value,
+                        encrypted_value FROM cookies",
+                        (),
+                    )
+                    .await?;
+                let mut undecryptable = 0;
+                while let Some(row) = rows.next().await? {
+                    let mut value = row.get::<String>(6)?;
+                    let encrypted = row.get::<Vec<u8>>(7).unwrap_or_default();
+                    if value.is_empty() && !encrypted.is_empty() {
+                        match decrypt_chromium(&encrypted, version >= 24) {
+                            Ok(x) => value = x,
+                            Err(e) => {
+                                tracing::debug!("Skipping cookie {}: {e}", row.get::<String>(5)?);
+                                undecryptable += 1;
+                                continue;
+                            }
+                        }
+                    }
+
+                    let expires_utc = row.get::<i64>(4)?;
+                    cookies.push(Cookie {
+                        domain: row.get(0)?,
+                        path: row.get(1)?,
+                        secure: row.get::<i64>(2)? != 0,
+                        http_only: row.get::<i64>(3)? != 0,
+                        expires: match expires_utc {
+                            0 => 0,
+                            x => x / 1_000_000 - CHROMIUM_EPOCH_OFFSET,
+                        },
+                        name: row.get(5)?,
+                        value,
+                    });
+                }
+
+                if undecryptable > 0 {
+                    tracing::warn!(
+                        "Skipped {undecryptable} cookies encrypted with a key from the system keyring. \
+                        Export them to a Netscape cookie file with a browser extension instead."
+                    );
+                }
+            }
+        }
+
+        Ok(Self(cookies))
+    }
+
+    pub fn parse_netscape(content: &str) -> Result<Self, Error> {
+        let mut cookies = vec![];
+
+        for (idx, line) in content.lines().enumerate() {
+            let (http_only, line) = match line.strip_prefix("#HttpOnly_") {
+                Some(x) => (true, x),
+                None => (false, line),
+            };
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let [domain, _, path, secure, expires, name, value] = fields[..] else {
+                bail!("Line {} is not a Netscape cookie", idx + 1);
+            };
+
+            cookies.push(Cookie {
+                domain: domain.to_string(),
+                path: path.to_string(),
+                secure: secure.eq_ignore_ascii_case("TRUE"),
+                http_only,
+                expires: expires.parse().unwrap_or_default(),
+                name: name.to_string(),
+                value: value.to_string(),
+            });
+        }
+
+        Ok(Self(cookies))
+    }
+
+    /// Unexpired cookies sent to the domain or its subdomains
+    pub fn for_domain(&self, domain: &str) -> Self {
+        Self(
+            self.0
+                .iter()
+                .filter(|x| x.matches(domain) && !x.is_expired())
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// The cookies in the Netscape format yt-dlp reads
+    pub fn to_netscape(&self) -> String {
+        let mut out = "# Netscape HTTP Cookie File\n".to_string();
+        let flag = |x: bool| if x { "TRUE" } else { "FALSE" };
+
+        for cookie in &self.0 {
+            out += &format!(
+                "{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                if cookie.http_only { "#HttpOnly_" } else { "" },
+                cookie.domain,
+                flag(cookie.domain.starts_with('.')),
+                cookie.path,
+                flag(cookie.secure),
+                cookie.expires,
+                cookie.name,
+                cookie.value
+            );
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt_chromium(plain: &[u8]) -> Vec<u8> {
+        use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+
+        let mut buf = vec![0; plain.len() + 16];
+        buf[..plain.len()].copy_from_slice(plain);
+        let encrypted =
+            cbc::Encryptor::<aes::Aes128>::new(&chromium_key().into(), &[b' '; 16].into())
+                .encrypt_padded_mut::<Pkcs7>(&mut buf, plain.len())
+                .unwrap();
+
+        [b"v10".as_slice(), encrypted].concat()
+    }
+
+    #[test]
+    fn decrypts_chromium() {
+        let encrypted = encrypt_chromium(b"session");
+        assert_eq!(decrypt_chromium(&encrypted, false).unwrap(), "session");
+
+        // Version 24 databases put the SHA256 of the host before the value
+        let hashed = encrypt_chromium(&[[7; 32].as_slice(), b"session"].concat());
+        assert_eq!(decrypt_chromium(&hashed, true).unwrap(), "session");
+
+        // v11 values need the key from the keyring
+        let mut v11 = encrypted.clone();
+        v11[..3].copy_from_slice(b"v11");
+        assert!(decrypt_chromium(&v11, false)
+            .unwrap_err()
+            .to_string()
+            .contains("only v10"));
+
+        let mut corrupted = encrypted;
+        corrupted.truncate(10);
+        assert!(decrypt_chromium(&corrupted, false).is_err());
+    }
+
+    #[test]
+    fn parses_netscape() {
+        let jar = CookieJar::parse_netscape(
+            "# Netscape HTTP Cookie File\n\
+            \n\
+            .youtube.com\tTRUE\t/\tTRUE\t1893456000\tSID\tabc=def\n\
+            #HttpOnly_www.example.com\tFALSE\t/path\tFALSE\t0\tsession\txyz\n\
+            # a comment\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            jar.0,
+            [
+                Cookie {
+                    domain: ".youtube.com".to_string(),
+                    path: "/".to_string(),
+                    secure: true,
+                    http_only: false,
+                    expires: 1893456000,
+                    name: "SID".to_string(),
+                    value: "abc=def".to_string(),
+                },
+                Cookie {
+                    domain: "www.example.com".to_string(),
+                    path: "/path".to_string(),
+                    secure: false,
+                    http_only: true,
+                    expires: 0,
+                    name: "session".to_string(),
+                    value: "xyz".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            CookieJar::parse_netscape(&jar.to_netscape()).unwrap().0,
+            jar.0
+        );
+
+        assert!(CookieJar::parse_netscape("youtube.com\tTRUE\t/\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_firefox_expiry_in_seconds_and_milliseconds() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cookies.sqlite");
+        let db = libsql::Builder::new_local(&path).build().await.unwrap();
+        db.connect()
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE moz_cookies (host TEXT, path TEXT, isSecure INTEGER,
+                    isHttpOnly INTEGER, expiry INTEGER, name TEXT, value TEXT);
+                INSERT INTO moz_cookies VALUES
+                    ('.youtube.com', '/', 1, 1, 1893456000, 'SID', 'a'),
+                    ('.youtube.com', '/', 0, 0, 1893456000000, 'PREF', 'b');",
+            )
+            .await
+            .unwrap();
+        drop(db);
+
+        let (source, jar) = CookieJar::load(&path).await.unwrap();
+        assert_eq!(source, CookieSource::Firefox);
+        assert_eq!(
+            jar.0.iter().map(|x| x.expires).collect::<Vec<_>>(),
+            [1893456000, 1893456000]
+        );
+        assert!(jar.0[0].secure && jar.0[0].http_only);
+    }
+
+    #[tokio::test]
+    async fn reads_chromium() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Cookies");
+        let db = libsql::Builder::new_local(&path).build().await.unwrap();
+        let conn = db.connect().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE meta (key TEXT, value TEXT);
+            INSERT INTO meta VALUES ('version', '24');
+            CREATE TABLE cookies (host_key TEXT, path TEXT, is_secure INTEGER,
+                is_httponly INTEGER, expires_utc INTEGER, name TEXT, value TEXT,
+                encrypted_value BLOB);",
+        )
+        .await
+        .unwrap();
+        let rows = [
+            (
+                "SID",
+                "",
+                encrypt_chromium(&[[0; 32].as_slice(), b"secret"].concat()),
+            ),
+            ("PREF", "plain", vec![]),
+            ("KEYRING", "", b"v11whatever".to_vec()),
+        ];
+        for (name, value, encrypted) in rows {
+            conn.execute(
+                "INSERT INTO cookies VALUES ('.youtube.com', '/', 1, 0, ?1, ?2, ?3, ?4)",
+                libsql::params![
+                    (1893456000 + CHROMIUM_EPOCH_OFFSET) * 1_000_000,
+                    name,
+                    value,
+                    encrypted
+                ],
+            )
+            .await
+            .unwrap();
+        }
+        drop(conn);
+        drop(db);
+
+        let (source, jar) = CookieJar::load(&path).await.unwrap();
+        assert_eq!(source, CookieSource::Chromium);
+        assert_eq!(
+            jar.0
+                .iter()
+                .map(|x| (x.name.as_str(), x.value.as_str(), x.expires))
+                .collect::<Vec<_>>(),
+            [("SID", "secret", 1893456000), ("PREF", "plain", 1893456000)]
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+
+use color_eyre::eyre::{Context, ContextCompat, Error};
+use ffmpeg_sidecar::command::FfmpegCommand;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    consts::{DEFAULT_MIN_SSIM, DEFAULT_MIN_VMAF, FFMPEG_SCALE},
+    funcs::quality::QualityMetric,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncodeProfile {
+    /// Video encoder (`-c:v`). Drops the video stream when unset.
+    pub video_codec: Option<String>,
+    pub crf: Option<u32>,
+    /// Target video bitrate (`-b:v`), e.g. `4M`
+    pub video_bitrate: Option<String>,
+    pub preset: Option<String>,
+    pub pix_fmt: Option<String>,
+    /// Video filter passed to `-vf`
+    pub scale: Option<String>,
+    /// Short side in pixels a source can have to be copied by `--reencode=auto`, e.g. 1080.
+    /// Should match what `scale` limits to.
+    pub max_resolution: Option<u32>,
+    /// Audio encoder (`-c:a`). Drops the audio stream when unset.
+    pub audio_codec: Option<String>,
+    /// Target audio bitrate (`-b:a`), e.g. `192k`
+    pub audio_bitrate: Option<String>,
+    /// Output file extension. Keeps the source extension when unset.
+    pub container: Option<String>,
+    /// Lowest VMAF score `--quality-check` accepts
+    pub min_vmaf: Option<f64>,
+    /// Lowest SSIM score `--quality-check` accepts when ffmpeg lacks libvmaf
+    pub min_ssim: Option<f64>,
+}
+
+impl Default for EncodeProfile {
+    fn default() -> Self {
+        Self {
+            video_codec: None,
+            crf: None,
+            video_bitrate: None,
+            preset: None,
+            pix_fmt: None,
+            scale: None,
+            max_resolution: None,
+            audio_codec: Some("copy".to_string()),
+            audio_bitrate: None,
+            container: None,
+            min_vmaf: None,
+            min_ssim: None,
+        }
+    }
+}
+
+impl EncodeProfile {
+    pub fn hevc_1080p() -> Self {
+        Self {
+            video_codec: Some("libx265".to_string()),
+            pix_fmt: Some("yuva420p10le".to_string()),
+            scale: Some(FFMPEG_SCALE.to_string()),
+            max_resolution: Some(1080),
+            ..Default::default()
+        }
+    }
+
+    pub fn av1() -> Self {
+        Self {
+            video_codec: Some("libsvtav1".to_string()),
+            crf: Some(32),
+            preset: Some("8".to_string()),
+            pix_fmt: Some("yuv420p10le".to_string()),
+            scale: Some(FFMPEG_SCALE.to_string()),
+            max_resolution: Some(1080),
+            container: Some("mkv".to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn h264() -> Self {
+        Self {
+            video_codec: Some("libx264".to_string()),
+            crf: Some(23),
+            preset: Some("medium".to_string()),
+            pix_fmt: Some("yuv420p".to_string()),
+            scale: Some(FFMPEG_SCALE.to_string()),
+            max_resolution: Some(1080),
+            audio_codec: Some("aac".to_string()),
+            audio_bitrate: Some("192k".to_string()),
+            container: Some("mp4".to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn audio_only() -> Self {
+        Self {
+            audio_codec: Some("aac".to_string()),
+            audio_bitrate: Some("192k".to_string()),
+            container: Some("m4a".to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn min_quality(&self, metric: QualityMetric) -> f64 {
+        match metric {
+            QualityMetric::Vmaf => self.min_vmaf.unwrap_or(DEFAULT_MIN_VMAF),
+            QualityMetric::Ssim => self.min_ssim.unwrap_or(DEFAULT_MIN_SSIM),
+        }
+    }
+
+    /// Picks the output extension, falling back to the source one
+    pub fn output_ext<'a>(&'a self, source_ext: &'a str) -> &'a str {
+        self.container.as_deref().unwrap_or(source_ext)
+    }
+
+    pub fn apply<'a>(&self, cmd: &'a mut FfmpegCommand) -> &'a mut FfmpegCommand {
+        match &self.video_codec {
+            Some(codec) => {
+                cmd.codec_video(codec);
+
+                if let Some(crf) = self.crf {
+                    cmd.crf(crf);
+                }
+                if let Some(bitrate) = &self.video_bitrate {
+                    cmd.args(["-b:v", bitrate]);
+                }
+                if let Some(preset) = &self.preset {
+                    cmd.preset(preset);
+                }
+                if let Some(pix_fmt) = &self.pix_fmt {
+                    cmd.pix_fmt(pix_fmt);
+                }
+                if let Some(scale) = &self.scale {
+                    cmd.args(["-vf", scale]);
+                }
+            }
+            None => {
+                cmd.no_video();
+            }
+        };
+
+        match &self.audio_codec {
+            Some(codec) => {
+                cmd.codec_audio(codec);
+
+                if let Some(bitrate) = &self.audio_bitrate {
+                    cmd.args(["-b:a", bitrate]);
+                }
+            }
+            None => {
+                cmd.no_audio();
+            }
+        };
+
+        cmd
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EncodeProfiles(HashMap<String, EncodeProfile>);
+
+impl EncodeProfiles {
+    pub fn builtin() -> Self {
+        Self(HashMap::from([
+            ("hevc-1080p".to_string(), EncodeProfile::hevc_1080p()),
+            ("av1".to_string(), EncodeProfile::av1()),
+            ("h264".to_string(), EncodeProfile::h264()),
+            ("audio-only".to_string(), EncodeProfile::audio_only()),
+        ]))
+    }
+
+    /// Loads built-in profiles, overridden by the ones in the config file if it exists
+    pub fn load(path: &std::path::Path) -> Result<Self, Error> {
+        let mut profiles = Self::builtin();
+
+        if !path.exists() {
+            tracing::debug!("No profile config found at {}", path.display());
+            return Ok(profiles);
+        }
+
+        let config_str = std::fs::read_to_string(path).wrap_err("Failed to read profile config")?;
+        let config: HashMap<String, EncodeProfile> =
+            serde_json::from_str(&config_str).wrap_err("Failed to parse profile config")?;
+
+        profiles.0.extend(config);
+
+        Ok(profiles)
+    }
+
+    pub fn get(&self, name: &str) -> Result<&EncodeProfile, Error> {
+        self.0.get(name).wrap_err_with(|| {
+            let mut names = self.0.keys().map(String::as_str).collect::<Vec<_>>();
+            names.sort();
+
+            format!(
+                "Unknown encoding profile '{name}'. Available: {}",
+                names.join(", ")
+            )
+        })
+    }
+}
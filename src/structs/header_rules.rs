@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+
+use color_eyre::eyre::{bail, Context, Error};
+
+/// HTTP headers sent to matching hosts, keyed by host pattern in the header config file.
+/// Patterns are either an exact host like `cdn.example.com` or a wildcard like `*.example.com`,
+/// which also matches `example.com` itself.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRules(HashMap<String, HashMap<String, String>>);
+
+fn matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => {
+            host == domain || host.strip_suffix(domain).is_some_and(|x| x.ends_with('.'))
+        }
+        None => host == pattern,
+    }
+}
+
+impl HeaderRules {
+    pub fn load(path: &std::path::Path) -> Result<Self, Error> {
+        if !path.exists() {
+            tracing::debug!("No header config found at {}", path.display());
+            return Ok(Self::default());
+        }
+
+        let config_str = std::fs::read_to_string(path).wrap_err("Failed to read header config")?;
+        let rules: HashMap<String, HashMap<String, String>> =
+            serde_json::from_str(&config_str).wrap_err("Failed to parse header config")?;
+
+        for (pattern, headers) in &rules {
+            for (name, val) in headers {
+                if let Err(e) = crate::parser::parse_header(&format!("{name}: {val}")) {
+                    bail!("Invalid header for '{pattern}' in header config: {e}");
+                }
+            }
+        }
+
+        Ok(Self(
+            rules
+                .into_iter()
+                .map(|(k, v)| (k.to_ascii_lowercase(), v))
+                .collect(),
+        ))
+    }
+
+    /// Headers of every rule matching the host. Exact rules come last, so they win over wildcards.
+    pub fn for_host(&self, host: &str) -> Vec<(String, String)> {
+        let host = host.to_ascii_lowercase();
+
+        let mut rules = self
+            .0
+            .iter()
+            .filter(|(pattern, _)| matches(pattern, &host))
+            .collect::<Vec<_>>();
+        // More specific wildcards are longer
+        rules.sort_by_key(|(pattern, _)| (!pattern.starts_with("*."), pattern.len()));
+
+        rules
+            .into_iter()
+            .flat_map(|(_, headers)| headers.iter().map(|(k, v)| (k.clone(), v.clone())))
+            .collect()
+    }
+}
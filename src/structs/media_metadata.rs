@@ -0,0 +1,296 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+
+use crate::parser::{parse_time, LineOptions};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chapter {
+    /// Seconds from the start of the source
+    pub start: f64,
+    pub end: f64,
+    pub title: String,
+}
+
+/// Subtitle file muxed into the output as a stream of its own
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubtitleStream {
+    pub path: PathBuf,
+    /// ISO 639 code for the language tag
+    pub language: Option<&'static str>,
+    pub title: String,
+    /// Part of the file to keep, in seconds, when the output is trimmed
+    pub start: f64,
+    pub duration: Option<f64>,
+}
+
+/// Tags, chapters, subtitles and cover art written into the output once it's encoded
+#[derive(Debug, Clone, Default)]
+pub struct MediaMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    /// `YYYY-MM-DD`
+    pub date: Option<String>,
+    pub description: Option<String>,
+    /// Where the file came from, e.g. the video page or the original file name
+    pub comment: Option<String>,
+    pub creation_time: Option<DateTime<Utc>>,
+    pub chapters: Vec<Chapter>,
+    pub subtitles: Vec<SubtitleStream>,
+    /// Image to attach as cover art
+    pub cover: Option<PathBuf>,
+}
+
+/// Escapes `=`, `;`, `#`, `\` and newlines as the ffmetadata format expects
+fn escape_ffmetadata(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl MediaMetadata {
+    pub fn from_ytdlp(video: &youtube_dl::SingleVideo) -> Self {
+        // yt-dlp gives YYYYMMDD
+        let date = video
+            .upload_date
+            .as_ref()
+            .or(video.release_date.as_ref())
+            .and_then(|x| chrono::NaiveDate::parse_from_str(x, "%Y%m%d").ok());
+
+        let chapters = video
+            .chapters
+            .iter()
+            .flatten()
+            .filter_map(|x| {
+                Some(Chapter {
+                    start: x.start_time?,
+                    end: x.end_time?,
+                    title: x.title.clone().unwrap_or_default(),
+                })
+            })
+            .collect();
+
+        Self {
+            title: video.title.clone(),
+            artist: video
+                .artist
+                .clone()
+                .or_else(|| video.uploader.clone())
+                .or_else(|| video.channel.clone()),
+            date: date.map(|x| x.format("%Y-%m-%d").to_string()),
+            description: video.description.clone(),
+            comment: video.webpage_url.clone(),
+            creation_time: video
+                .timestamp
+                .and_then(|x| DateTime::from_timestamp(x as i64, 0)),
+            chapters,
+            subtitles: vec![],
+            cover: None,
+        }
+    }
+
+    /// What file sources know about themselves: their name and when they were modified
+    pub fn from_file(filename: &str, modified: Option<DateTime<Utc>>) -> Self {
+        let title = std::path::Path::new(filename)
+            .file_stem()
+            .map(|x| x.to_string_lossy().into_owned());
+
+        Self {
+            title,
+            comment: Some(filename.to_string()),
+            creation_time: modified,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags().is_empty()
+            && self.chapters.is_empty()
+            && self.subtitles.is_empty()
+            && self.cover.is_none()
+    }
+
+    pub fn tags(&self) -> Vec<(&'static str, String)> {
+        [
+            ("title", self.title.clone()),
+            ("artist", self.artist.clone()),
+            ("date", self.date.clone()),
+            ("description", self.description.clone()),
+            ("comment", self.comment.clone()),
+            (
+                "creation_time",
+                self.creation_time
+                    .map(|x| x.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(k, v)| Some((k, v.filter(|x| !x.is_empty())?)))
+        .collect()
+    }
+
+    /// Moves the chapters and subtitles to where they are in the output after trimming with start
+    /// and end, dropping the chapters cut off entirely
+    pub fn trim(&mut self, opts: &LineOptions) {
+        let start = opts.start.as_deref().and_then(parse_time).unwrap_or(0.0);
+        let end = opts
+            .end
+            .as_deref()
+            .and_then(parse_time)
+            .unwrap_or(f64::INFINITY);
+
+        self.chapters = self
+            .chapters
+            .iter()
+            .filter(|x| x.end > start && x.start < end)
+            .map(|x| Chapter {
+                start: x.start.max(start) - start,
+                end: x.end.min(end) - start,
+                title: x.title.clone(),
+            })
+            .collect();
+
+        for subtitle in &mut self.subtitles {
+            subtitle.start = start;
+            subtitle.duration = end.is_finite().then_some(end - start);
+        }
+    }
+
+    /// Chapters in ffmpeg's metadata file format, for `-map_chapters`
+    pub fn ffmetadata(&self) -> String {
+        let mut out = ";FFMETADATA1\n".to_string();
+        for chapter in &self.chapters {
+            out += &format!(
+                "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+                (chapter.start * 1000.0) as i64,
+                (chapter.end * 1000.0) as i64,
+                escape_ffmetadata(&chapter.title)
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chapter(start: f64, end: f64, title: &str) -> Chapter {
+        Chapter {
+            start,
+            end,
+            title: title.to_string(),
+        }
+    }
+
+    fn trim_opts(start: Option<&str>, end: Option<&str>) -> LineOptions {
+        LineOptions {
+            start: start.map(str::to_string),
+            end: end.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn escapes_ffmetadata() {
+        let cases = [
+            ("Intro", "Intro"),
+            ("a=b", "a\\=b"),
+            ("Part 1; Part 2", "Part 1\\; Part 2"),
+            ("#1", "\\#1"),
+            ("C:\\path", "C:\\\\path"),
+            ("two\nlines", "two\\\nlines"),
+            ("", ""),
+        ];
+
+        for (title, expected) in cases {
+            assert_eq!(escape_ffmetadata(title), expected, "{title}");
+        }
+    }
+
+    #[test]
+    fn writes_ffmetadata() {
+        let metadata = MediaMetadata {
+            chapters: vec![chapter(0.0, 61.5, "Intro"), chapter(61.5, 120.0, "Q&A; #2")],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            metadata.ffmetadata(),
+            ";FFMETADATA1\n\
+            [CHAPTER]\nTIMEBASE=1/1000\nSTART=0\nEND=61500\ntitle=Intro\n\
+            [CHAPTER]\nTIMEBASE=1/1000\nSTART=61500\nEND=120000\ntitle=Q&A\\; \\#2\n"
+        );
+        assert_eq!(MediaMetadata::default().ffmetadata(), ";FFMETADATA1\n");
+    }
+
+    #[test]
+    fn trims_chapters() {
+        let chapters = vec![
+            chapter(0.0, 60.0, "a"),
+            chapter(60.0, 120.0, "b"),
+            chapter(120.0, 180.0, "c"),
+            chapter(180.0, 240.0, "d"),
+        ];
+
+        let cases = [
+            (None, None, chapters.clone()),
+            (
+                Some("90"),
+                None,
+                vec![
+                    chapter(0.0, 30.0, "b"),
+                    chapter(30.0, 90.0, "c"),
+                    chapter(90.0, 150.0, "d"),
+                ],
+            ),
+            (
+                None,
+                Some("00:02:30"),
+                vec![
+                    chapter(0.0, 60.0, "a"),
+                    chapter(60.0, 120.0, "b"),
+                    chapter(120.0, 150.0, "c"),
+                ],
+            ),
+            (Some("1:00"), Some("2:00"), vec![chapter(0.0, 60.0, "b")]),
+            (Some("240"), None, vec![]),
+        ];
+
+        for (start, end, expected) in cases {
+            let mut metadata = MediaMetadata {
+                chapters: chapters.clone(),
+                ..Default::default()
+            };
+            metadata.trim(&trim_opts(start, end));
+            assert_eq!(metadata.chapters, expected, "{start:?}-{end:?}");
+        }
+    }
+
+    #[test]
+    fn trims_subtitles() {
+        let subtitle = SubtitleStream {
+            path: PathBuf::from("video.en.srt"),
+            language: Some("eng"),
+            title: "en".to_string(),
+            start: 0.0,
+            duration: None,
+        };
+        let mut metadata = MediaMetadata {
+            subtitles: vec![subtitle.clone()],
+            ..Default::default()
+        };
+
+        metadata.trim(&trim_opts(Some("30"), Some("90")));
+        assert_eq!(metadata.subtitles[0].start, 30.0);
+        assert_eq!(metadata.subtitles[0].duration, Some(60.0));
+
+        metadata.subtitles = vec![subtitle];
+        metadata.trim(&trim_opts(Some("30"), None));
+        assert_eq!(metadata.subtitles[0].duration, None);
+    }
+}
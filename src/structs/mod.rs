@@ -1,3 +1,15 @@
+pub mod cookie_jar;
+pub mod encode_profile;
+pub mod header_rules;
 pub mod md5writer;
+pub mod media_metadata;
+pub mod remote_config;
+pub mod run_summary;
 
+pub use cookie_jar::CookieJar;
+pub use encode_profile::{EncodeProfile, EncodeProfiles};
+pub use header_rules::HeaderRules;
 pub use md5writer::Md5Writer;
+pub use media_metadata::{MediaMetadata, SubtitleStream};
+pub use remote_config::{RemoteConfig, RemoteConfigs};
+pub use run_summary::RunSummary;
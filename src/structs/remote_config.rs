@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+
+use color_eyre::eyre::{Context, ContextCompat, Error};
+use serde::{Deserialize, Serialize};
+
+/// Upload destination, tagged by `type` in the remote config file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RemoteConfig {
+    B2 {
+        application_key_id: String,
+        application_key: String,
+        bucket: String,
+        bucket_id: String,
+        #[serde(default)]
+        root: Option<String>,
+    },
+    /// Any S3-compatible store, e.g. AWS, MinIO or R2
+    S3 {
+        bucket: String,
+        /// Custom endpoint for non-AWS stores, e.g. `http://127.0.0.1:9000`
+        #[serde(default)]
+        endpoint: Option<String>,
+        #[serde(default)]
+        region: Option<String>,
+        #[serde(default)]
+        access_key_id: Option<String>,
+        #[serde(default)]
+        secret_access_key: Option<String>,
+        /// Use `bucket.endpoint` addressing instead of `endpoint/bucket`
+        #[serde(default)]
+        virtual_host_style: bool,
+        #[serde(default)]
+        root: Option<String>,
+    },
+    /// Directory on the local filesystem, e.g. a mounted NAS share
+    Fs { root: String },
+    Webdav {
+        endpoint: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+        /// Bearer token, used instead of username and password
+        #[serde(default)]
+        token: Option<String>,
+        #[serde(default)]
+        root: Option<String>,
+    },
+    /// Uses the system ssh client, so hosts and keys from ~/.ssh work as usual
+    Sftp {
+        /// e.g. `ssh://user@host:22`
+        endpoint: String,
+        #[serde(default)]
+        user: Option<String>,
+        /// Path to the private key
+        #[serde(default)]
+        key: Option<String>,
+        /// `strict`, `accept` or `add`
+        #[serde(default)]
+        known_hosts_strategy: Option<String>,
+        #[serde(default)]
+        root: Option<String>,
+    },
+}
+
+impl RemoteConfig {
+    /// Parses the legacy `B2;Key ID;App Key;Bucket;BucketID;Root path` string of `--b2args`
+    pub fn from_b2args(service_string: &str) -> Result<Self, Error> {
+        let parts = service_string.split(';').collect::<Vec<_>>();
+        let Some(&[service, id, key, bucket, bucket_id, rootpath]) = parts.get(0..6) else {
+            color_eyre::eyre::bail!("Error parsing service string")
+        };
+
+        match service {
+            "B2" | "b2" => Ok(Self::B2 {
+                application_key_id: id.to_string(),
+                application_key: key.to_string(),
+                bucket: bucket.to_string(),
+                bucket_id: bucket_id.to_string(),
+                root: Some(rootpath.to_string()),
+            }),
+            others => color_eyre::eyre::bail!(
+                "{others} service is not supported by --b2args, use --upload with a remote config instead"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RemoteConfigs(HashMap<String, RemoteConfig>);
+
+impl RemoteConfigs {
+    pub fn load(path: &std::path::Path) -> Result<Self, Error> {
+        if !path.exists() {
+            tracing::debug!("No remote config found at {}", path.display());
+            return Ok(Self::default());
+        }
+
+        let config_str = std::fs::read_to_string(path).wrap_err("Failed to read remote config")?;
+        let config = serde_json::from_str(&config_str).wrap_err("Failed to parse remote config")?;
+
+        Ok(Self(config))
+    }
+
+    pub fn get(&self, name: &str) -> Result<&RemoteConfig, Error> {
+        self.0.get(name).wrap_err_with(|| {
+            let mut names = self.0.keys().map(String::as_str).collect::<Vec<_>>();
+            names.sort();
+
+            format!(
+                "Unknown remote '{name}'. Available: {}",
+                if names.is_empty() {
+                    "none".to_string()
+                } else {
+                    names.join(", ")
+                }
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_type() {
+        let config = r#"{
+            "b2": {
+                "type": "b2",
+                "application_key_id": "id",
+                "application_key": "key",
+                "bucket": "videos",
+                "bucket_id": "bid"
+            },
+            "minio": {
+                "type": "s3",
+                "bucket": "videos",
+                "endpoint": "http://127.0.0.1:9000",
+                "virtual_host_style": true
+            },
+            "nas": { "type": "fs", "root": "/mnt/nas" },
+            "dav": { "type": "webdav", "endpoint": "https://dav.example.com", "token": "t" },
+            "box": { "type": "sftp", "endpoint": "ssh://me@host:22", "root": "/srv" }
+        }"#;
+        let configs: HashMap<String, RemoteConfig> = serde_json::from_str(config).unwrap();
+        let configs = RemoteConfigs(configs);
+
+        assert_eq!(
+            configs.get("b2").unwrap(),
+            &RemoteConfig::B2 {
+                application_key_id: "id".into(),
+                application_key: "key".into(),
+                bucket: "videos".into(),
+                bucket_id: "bid".into(),
+                root: None,
+            }
+        );
+        assert_eq!(
+            configs.get("minio").unwrap(),
+            &RemoteConfig::S3 {
+                bucket: "videos".into(),
+                endpoint: Some("http://127.0.0.1:9000".into()),
+                region: None,
+                access_key_id: None,
+                secret_access_key: None,
+                virtual_host_style: true,
+                root: None,
+            }
+        );
+        assert_eq!(
+            configs.get("nas").unwrap(),
+            &RemoteConfig::Fs {
+                root: "/mnt/nas".into()
+            }
+        );
+        assert!(matches!(
+            configs.get("dav").unwrap(),
+            RemoteConfig::Webdav { token: Some(t), username: None, .. } if t == "t"
+        ));
+        assert!(matches!(
+            configs.get("box").unwrap(),
+            RemoteConfig::Sftp { root: Some(r), key: None, .. } if r == "/srv"
+        ));
+
+        let err = configs.get("nope").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Unknown remote 'nope'. Available: b2, box, dav, minio, nas"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        let cases = [
+            r#"{ "x": { "type": "ftp", "root": "/" } }"#,
+            r#"{ "x": { "type": "fs" } }"#,
+            r#"{ "x": { "root": "/" } }"#,
+            r#"{ "x": { "type": "s3", "endpoint": "http://a" } }"#,
+        ];
+
+        for config in cases {
+            let parsed = serde_json::from_str::<HashMap<String, RemoteConfig>>(config);
+            assert!(parsed.is_err(), "{config}");
+        }
+    }
+
+    #[test]
+    fn loads_missing_file_as_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let configs = RemoteConfigs::load(&dir.path().join("remotes.json")).unwrap();
+
+        let err = configs.get("nas").unwrap_err().to_string();
+        assert_eq!(err, "Unknown remote 'nas'. Available: none");
+    }
+
+    #[test]
+    fn parses_b2args() {
+        assert_eq!(
+            RemoteConfig::from_b2args("B2;id;key;videos;bid;/backup").unwrap(),
+            RemoteConfig::B2 {
+                application_key_id: "id".into(),
+                application_key: "key".into(),
+                bucket: "videos".into(),
+                bucket_id: "bid".into(),
+                root: Some("/backup".into()),
+            }
+        );
+        assert!(RemoteConfig::from_b2args("B2;id;key").is_err());
+        assert!(RemoteConfig::from_b2args("S3;id;key;videos;bid;/backup").is_err());
+    }
+}
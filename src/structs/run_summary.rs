@@ -0,0 +1,109 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Mutex,
+};
+
+use color_eyre::eyre::Error;
+
+use crate::funcs::ytdlp::YtDlpError;
+
+#[derive(Debug, Default)]
+struct Outcomes {
+    succeeded: usize,
+    /// Failed sources with their error, by kind of failure
+    failed: BTreeMap<String, Vec<(String, String)>>,
+    /// Items of sources that couldn't be processed, with the reason, by source
+    skipped: BTreeMap<String, Vec<(String, String)>>,
+    /// Sources counted by the items they expanded into, like playlists
+    expanded: HashSet<String>,
+}
+
+/// How the sources of the run went, reported once it's over
+#[derive(Debug, Default)]
+pub struct RunSummary(Mutex<Outcomes>);
+
+impl RunSummary {
+    pub fn record(&self, source: &str, result: &Result<(), Error>) {
+        let mut outcomes = self.0.lock().unwrap();
+
+        match result {
+            Ok(()) if outcomes.expanded.contains(source) => (),
+            Ok(()) => outcomes.succeeded += 1,
+            Err(e) => {
+                let (kind, message) = match e.downcast_ref::<YtDlpError>() {
+                    Some(x) => (x.kind.to_string(), x.message.clone()),
+                    None => ("other".to_string(), e.to_string()),
+                };
+                outcomes
+                    .failed
+                    .entry(kind)
+                    .or_default()
+                    .push((source.to_string(), message));
+            }
+        }
+    }
+
+    /// Counts the source by the items it expanded into, which are recorded on their own. It still
+    /// counts as failed if it fails as a whole.
+    pub fn expand(&self, source: &str) {
+        self.0.lock().unwrap().expanded.insert(source.to_string());
+    }
+
+    pub fn skip(&self, source: &str, item: &str, reason: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .skipped
+            .entry(source.to_string())
+            .or_default()
+            .push((item.to_string(), reason.to_string()));
+    }
+
+    /// Logs the skipped items and the failed sources by kind, returning how many there were
+    pub fn report(&self) -> usize {
+        let outcomes = self.0.lock().unwrap();
+
+        for (source, items) in &outcomes.skipped {
+            tracing::warn!("Skipped {} items of {source}:", items.len());
+            for (item, reason) in items {
+                tracing::warn!("  {item}: {reason}");
+            }
+        }
+
+        let failed = outcomes.failed.values().map(Vec::len).sum::<usize>();
+        if failed == 0 {
+            tracing::info!("{} sources processed", outcomes.succeeded);
+            return 0;
+        }
+
+        tracing::warn!("{} sources processed, {failed} failed:", outcomes.succeeded);
+        for (kind, sources) in &outcomes.failed {
+            tracing::warn!("  {kind} ({}):", sources.len());
+            for (source, message) in sources {
+                tracing::warn!("    {source}: {message}");
+            }
+        }
+
+        failed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn playlists_count_by_their_items() {
+        let summary = RunSummary::default();
+        summary.expand("https://example.com/playlist");
+        summary.record("https://example.com/a", &Ok(()));
+        summary.record(
+            "https://example.com/b",
+            &Err(color_eyre::eyre::eyre!("failed")),
+        );
+        summary.record("https://example.com/playlist", &Ok(()));
+
+        assert_eq!(summary.0.lock().unwrap().succeeded, 1);
+        assert_eq!(summary.report(), 1);
+    }
+}